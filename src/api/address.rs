@@ -0,0 +1,202 @@
+//! JSON views of the coins and transactions of an address, either as of the tip of the longest
+//! chain or also counting the pending transactions of the mempool.
+
+use crate::blockchain::Blockchain;
+use crate::crypto::address::H160;
+use crate::crypto::hash::Hashable;
+use crate::memory_pool::TransactionMempool;
+use crate::transaction::{UtxoInput, UtxoOutput};
+use serde::Serialize;
+use std::collections::HashSet;
+
+#[derive(Serialize)]
+pub struct UtxoView {
+    /// Hash of the unsigned transaction that created the output
+    pub txid: String,
+    pub index: u8,
+    pub value: u32,
+    /// Whether the output is created by a pending transaction
+    pub pending: bool,
+}
+
+#[derive(Serialize)]
+pub struct BalanceView {
+    pub address: String,
+    pub mempool: bool,
+    pub balance: u64,
+    pub utxo_count: usize,
+}
+
+#[derive(Serialize)]
+pub struct UtxoList {
+    pub address: String,
+    pub mempool: bool,
+    pub utxos: Vec<UtxoView>,
+}
+
+#[derive(Serialize)]
+pub struct HistoryEntry {
+    /// Hash of the signed transaction
+    pub hash: String,
+    /// The block of the longest chain that includes it, none if it is pending
+    pub block: Option<String>,
+    pub height: Option<u64>,
+    pub confirmations: u64,
+}
+
+#[derive(Serialize)]
+pub struct History {
+    pub address: String,
+    pub mempool: bool,
+    pub transactions: Vec<HistoryEntry>,
+}
+
+/// The unspent outputs of an address on the tip, and once the pending transactions of `mempool`
+/// are applied if it is given.
+fn unspent(
+    blockchain: &Blockchain,
+    mempool: Option<&TransactionMempool>,
+    address: &H160,
+) -> Vec<(UtxoInput, UtxoOutput, bool)> {
+    let mut utxos: Vec<_> = blockchain
+        .tip_state()
+        .utxos_of(address)
+        .into_iter()
+        .map(|(input, output)| (input, output, false))
+        .collect();
+    if let Some(mempool) = mempool {
+        let mut spent: HashSet<&UtxoInput> = HashSet::new();
+        for (_, signed_tx) in mempool.pending() {
+            spent.extend(signed_tx.transaction.tx_input.iter());
+            let txid = signed_tx.transaction.hash();
+            for (i, output) in signed_tx.transaction.tx_output.iter().enumerate() {
+                if output.recipient_address == *address {
+                    let input = UtxoInput { prev_hash: txid, index: i as u8 };
+                    utxos.push((input, *output, true));
+                }
+            }
+        }
+        utxos.retain(|(input, _, _)| !spent.contains(input));
+    }
+    utxos.sort_by_key(|(input, _, pending)| (*pending, input.prev_hash, input.index));
+    utxos
+}
+
+pub fn balance(
+    blockchain: &Blockchain,
+    mempool: Option<&TransactionMempool>,
+    address: &H160,
+) -> BalanceView {
+    let utxos = unspent(blockchain, mempool, address);
+    BalanceView {
+        address: address.to_string(),
+        mempool: mempool.is_some(),
+        balance: utxos.iter().map(|(_, output, _)| output.value as u64).sum(),
+        utxo_count: utxos.len(),
+    }
+}
+
+pub fn utxos(
+    blockchain: &Blockchain,
+    mempool: Option<&TransactionMempool>,
+    address: &H160,
+) -> UtxoList {
+    UtxoList {
+        address: address.to_string(),
+        mempool: mempool.is_some(),
+        utxos: unspent(blockchain, mempool, address)
+            .into_iter()
+            .map(|(input, output, pending)| UtxoView {
+                txid: input.prev_hash.to_string(),
+                index: input.index,
+                value: output.value,
+                pending,
+            })
+            .collect(),
+    }
+}
+
+/// The transactions of the longest chain involving an address, oldest first, followed by the
+/// pending ones of `mempool` if it is given.
+pub fn history(
+    blockchain: &Blockchain,
+    mempool: Option<&TransactionMempool>,
+    address: &H160,
+) -> History {
+    let mut confirmed: Vec<(u64, HistoryEntry)> = blockchain
+        .transactions_of(address)
+        .iter()
+        .filter_map(|tx_hash| {
+            let block = blockchain
+                .blocks_containing(tx_hash)
+                .iter()
+                .find(|block| blockchain.is_in_main_chain(block))?;
+            let height = blockchain.height(block)?;
+            let entry = HistoryEntry {
+                hash: tx_hash.to_string(),
+                block: Some(block.to_string()),
+                height: Some(height),
+                confirmations: blockchain.confirmations(block),
+            };
+            Some((height, entry))
+        })
+        .collect();
+    confirmed.sort_by_key(|(height, _)| *height);
+    let mut transactions: Vec<HistoryEntry> =
+        confirmed.into_iter().map(|(_, entry)| entry).collect();
+    if let Some(mempool) = mempool {
+        transactions.extend(
+            mempool
+                .pending()
+                .filter(|(_, signed_tx)| signed_tx.addresses().contains(address))
+                .map(|(tx_hash, _)| HistoryEntry {
+                    hash: tx_hash.to_string(),
+                    block: None,
+                    height: None,
+                    confirmations: 0,
+                }),
+        );
+    }
+    History {
+        address: address.to_string(),
+        mempool: mempool.is_some(),
+        transactions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::address;
+    use crate::fixtures;
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn confirmed_and_pending_views() {
+        let key = fixtures::key(5);
+        let owner = address::address_from_public_key_ref(key.public_key());
+        let other = address::generate_random_address();
+        let (blockchain, coin, reward) = fixtures::chain_with_coinbase_to(owner);
+        let mut mempool = TransactionMempool::new();
+
+        let view = balance(&blockchain, None, &owner);
+        assert_eq!((view.balance, view.utxo_count), (reward as u64, 1));
+        let signed_tx = fixtures::sign_spend(&key, vec![coin], vec![
+            UtxoOutput { recipient_address: other, value: 10 },
+            UtxoOutput { recipient_address: owner, value: reward - 10 },
+        ]);
+        mempool.admit(signed_tx, blockchain.tip_state()).unwrap();
+
+        assert_eq!(balance(&blockchain, Some(&mempool), &owner).balance, reward as u64 - 10);
+        assert_eq!(balance(&blockchain, Some(&mempool), &other).balance, 10);
+        assert_eq!(balance(&blockchain, None, &other).balance, 0);
+        let pending = utxos(&blockchain, Some(&mempool), &owner).utxos;
+        assert_eq!(pending.len(), 1);
+        assert!(pending[0].pending);
+        assert_eq!(history(&blockchain, None, &owner).transactions.len(), 1);
+        let entries = history(&blockchain, Some(&mempool), &owner).transactions;
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].height, Some(1));
+        assert_eq!(entries[1].block, None);
+    }
+}
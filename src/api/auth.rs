@@ -0,0 +1,258 @@
+//! Access control of the API server. Clients may be restricted to some networks, and when
+//! tokens are configured every request must carry one as `Authorization: Bearer <token>`. A
+//! token grants either read-only access or admin access, which is required to control the miner,
+//! the network, the validity of blocks and the wallet, and to submit transactions.
+//!
+//! Without any token, every allowed client is an admin, as before authentication existed.
+
+use rand::Rng;
+use std::net::IpAddr;
+use std::path::Path;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Role {
+    ReadOnly,
+    Admin,
+}
+
+/// Why a request was refused, with the HTTP status to answer it with.
+#[derive(Debug, PartialEq)]
+pub enum Denied {
+    /// The client address is not in an allowed network
+    Address,
+    /// The request has no token, or an unknown one
+    Unauthenticated,
+    /// The token does not grant the role the endpoint requires
+    Forbidden,
+}
+
+impl Denied {
+    pub fn status(&self) -> u16 {
+        match self {
+            Denied::Unauthenticated => 401,
+            Denied::Address | Denied::Forbidden => 403,
+        }
+    }
+
+    pub fn message(&self) -> &'static str {
+        match self {
+            Denied::Address => "client address not allowed",
+            Denied::Unauthenticated => "missing or invalid bearer token",
+            Denied::Forbidden => "admin token required",
+        }
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct AccessControl {
+    tokens: Vec<(String, Role)>,
+    /// Networks clients may connect from, as an address and a prefix length. Empty to allow any.
+    allowed: Vec<(IpAddr, u8)>,
+}
+
+impl AccessControl {
+    pub fn add_token(&mut self, token: &str, role: Role) {
+        self.tokens.push((token.to_string(), role));
+    }
+
+    /// Allow clients from a network given as an address, such as `10.0.0.1`, or an address and
+    /// a prefix length, such as `10.0.0.0/8`.
+    pub fn allow(&mut self, network: &str) -> Result<(), String> {
+        let (addr, prefix) = match network.find('/') {
+            Some(i) => (&network[..i], Some(&network[i + 1..])),
+            None => (network, None),
+        };
+        let addr: IpAddr = addr
+            .parse()
+            .map_err(|e| format!("error parsing network {}: {}", network, e))?;
+        let max_prefix = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix
+                .parse::<u8>()
+                .ok()
+                .filter(|prefix| *prefix <= max_prefix)
+                .ok_or_else(|| format!("invalid prefix length in network {}", network))?,
+            None => max_prefix,
+        };
+        self.allowed.push((addr, prefix));
+        Ok(())
+    }
+
+    /// Whether requests must carry a token
+    pub fn requires_token(&self) -> bool {
+        !self.tokens.is_empty()
+    }
+
+    /// Whether clients are restricted to some networks
+    pub fn restricts_clients(&self) -> bool {
+        !self.allowed.is_empty()
+    }
+
+    /// Check that a client may send a request requiring `required`, given the value of its
+    /// `Authorization` header if any.
+    pub fn authorize(
+        &self,
+        client: IpAddr,
+        authorization: Option<&str>,
+        required: Role,
+    ) -> Result<Role, Denied> {
+        if self.restricts_clients()
+            && !self.allowed.iter().any(|(network, prefix)| contains(network, *prefix, &client))
+        {
+            return Err(Denied::Address);
+        }
+        if !self.requires_token() {
+            return Ok(Role::Admin);
+        }
+        let token = authorization
+            .and_then(|value| value.strip_prefix("Bearer "))
+            .map(str::trim)
+            .ok_or(Denied::Unauthenticated)?;
+        let role = self
+            .tokens
+            .iter()
+            .filter(|(known, _)| {
+                ring::constant_time::verify_slices_are_equal(known.as_bytes(), token.as_bytes())
+                    .is_ok()
+            })
+            .map(|(_, role)| *role)
+            .max()
+            .ok_or(Denied::Unauthenticated)?;
+        if role < required {
+            return Err(Denied::Forbidden);
+        }
+        Ok(role)
+    }
+}
+
+/// The role an endpoint requires, given the HTTP method of the request. Controlling the miner,
+/// the network and the validity of blocks, submitting transactions, and spending from or adding
+/// keys to the wallet, requires admin access, listing the peers and bans or reading the wallet
+/// balance does not.
+pub fn required_role(method: &str, path: &str) -> Role {
+    let network_control = path.starts_with("/network/")
+        && path != "/network/peers"
+        && path != "/network/banned";
+    if path.starts_with("/miner/")
+        || network_control
+        || path == "/chain/invalidate"
+        || path == "/chain/reconsider"
+        || path == "/wallet/send"
+        || path == "/wallet/new-address"
+        || (method == "POST" && path == "/tx")
+    {
+        Role::Admin
+    } else {
+        Role::ReadOnly
+    }
+}
+
+/// The role a JSON-RPC method requires. Submitting transactions and spending from or adding keys
+/// to the wallet requires admin access.
+pub fn rpc_required_role(method: &str) -> Role {
+    if matches!(method, "getnewaddress" | "sendtoaddress" | "sendrawtransaction") {
+        Role::Admin
+    } else {
+        Role::ReadOnly
+    }
+}
+
+/// Generate a random token and write it to `path`, readable only by the current user, so that
+/// local tools can authenticate without a token on the command line.
+pub fn write_cookie(path: &Path) -> std::io::Result<String> {
+    let bytes: [u8; 32] = rand::thread_rng().gen();
+    let token = hex::encode(bytes);
+    // the permissions of an existing file would be kept, so the cookie is always created anew
+    match std::fs::remove_file(path) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    std::io::Write::write_all(&mut options.open(path)?, token.as_bytes())?;
+    Ok(token)
+}
+
+/// Whether `addr` is in the network of `network` with a prefix of `prefix` bits.
+fn contains(network: &IpAddr, prefix: u8, addr: &IpAddr) -> bool {
+    let (network, addr): (Vec<u8>, Vec<u8>) = match (network, addr) {
+        (IpAddr::V4(network), IpAddr::V4(addr)) => (network.octets().to_vec(), addr.octets().to_vec()),
+        (IpAddr::V6(network), IpAddr::V6(addr)) => (network.octets().to_vec(), addr.octets().to_vec()),
+        _ => return false,
+    };
+    let full_bytes = prefix as usize / 8;
+    if network[..full_bytes] != addr[..full_bytes] {
+        return false;
+    }
+    let rest = prefix % 8;
+    if rest == 0 {
+        return true;
+    }
+    let mask = 0xffu8 << (8 - rest);
+    network[full_bytes] & mask == addr[full_bytes] & mask
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokens_roles_and_networks() {
+        let local: IpAddr = "127.0.0.1".parse().unwrap();
+        let open = AccessControl::default();
+        assert_eq!(open.authorize(local, None, Role::Admin), Ok(Role::Admin));
+
+        let mut access = AccessControl::default();
+        access.add_token("admin-secret", Role::Admin);
+        access.add_token("reader", Role::ReadOnly);
+        access.allow("10.1.0.0/20").unwrap();
+        access.allow("127.0.0.1").unwrap();
+        assert!(access.allow("10.0.0.0/33").is_err());
+        assert!(access.allow("localhost").is_err());
+
+        let admin = Some("Bearer admin-secret");
+        let reader = Some("Bearer reader");
+        assert_eq!(access.authorize(local, admin, Role::Admin), Ok(Role::Admin));
+        assert_eq!(access.authorize(local, reader, Role::ReadOnly), Ok(Role::ReadOnly));
+        assert_eq!(access.authorize(local, reader, Role::Admin), Err(Denied::Forbidden));
+        assert_eq!(access.authorize(local, None, Role::ReadOnly), Err(Denied::Unauthenticated));
+        assert_eq!(
+            access.authorize(local, Some("Bearer admin"), Role::ReadOnly),
+            Err(Denied::Unauthenticated)
+        );
+        let inside = "10.1.15.255".parse().unwrap();
+        let outside = "10.1.16.0".parse().unwrap();
+        assert_eq!(access.authorize(inside, admin, Role::Admin), Ok(Role::Admin));
+        assert_eq!(access.authorize(outside, admin, Role::Admin), Err(Denied::Address));
+        assert_eq!(access.authorize("::1".parse().unwrap(), admin, Role::Admin), Err(Denied::Address));
+
+        assert_eq!(required_role("GET", "/miner/start"), Role::Admin);
+        assert_eq!(required_role("GET", "/chain/invalidate"), Role::Admin);
+        assert_eq!(required_role("GET", "/chain/tip"), Role::ReadOnly);
+        assert_eq!(required_role("GET", "/network/ban"), Role::Admin);
+        assert_eq!(required_role("GET", "/network/peers"), Role::ReadOnly);
+        assert_eq!(required_role("GET", "/wallet/send"), Role::Admin);
+        assert_eq!(required_role("GET", "/wallet/balance"), Role::ReadOnly);
+        assert_eq!(required_role("POST", "/tx"), Role::Admin);
+        assert_eq!(required_role("GET", "/tx"), Role::ReadOnly);
+        assert_eq!(rpc_required_role("sendrawtransaction"), Role::Admin);
+        assert_eq!(rpc_required_role("getrawtransaction"), Role::ReadOnly);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn cookie_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("cookie-test-{}", std::process::id()));
+        std::fs::write(&path, "old").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        let token = write_cookie(&path).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), token);
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+    }
+}
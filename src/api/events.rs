@@ -0,0 +1,199 @@
+//! A feed of node events for the server-sent events stream at `/events`: changes of the longest
+//! chain, transactions entering the mempool, and peers connecting, disconnecting or being banned.
+//!
+//! Each event is sent as an SSE frame whose `event` field is the type of the event and whose
+//! `data` field is the event as JSON, for example:
+//!
+//! ```text
+//! event: tip
+//! data: {"type":"tip","tip":"…","height":12,"connected":["…"]}
+//! ```
+
+use crate::blockchain::{Blockchain, ChainEvent, InsertOutcome};
+use crate::crypto::hash::H256;
+use crate::memory_pool::TransactionMempool;
+use crate::network::peer::Direction;
+use crate::network::server::{Handle as NetworkServerHandle, PeerEvent};
+use crate::network::transport::PeerSink;
+use crossbeam::channel::{self, Receiver};
+use serde::Serialize;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+/// Comment frame sent when nothing happened for `KEEPALIVE_INTERVAL`, so that proxies keep the
+/// connection open and a client that went away is noticed
+pub const KEEPALIVE: &str = ": keepalive\n\n";
+pub const KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+#[derive(Serialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Event {
+    /// The longest chain grew
+    Tip {
+        tip: String,
+        height: u64,
+        connected: Vec<String>,
+    },
+    /// A fork became the longest chain
+    Reorg {
+        tip: String,
+        height: u64,
+        /// From the old tip down to the fork point
+        disconnected: Vec<String>,
+        /// From the fork point up to the new tip
+        connected: Vec<String>,
+    },
+    Transaction {
+        hash: String,
+    },
+    PeerConnected {
+        addr: String,
+        inbound: bool,
+    },
+    PeerDisconnected {
+        addr: String,
+    },
+    PeerBanned {
+        ip: String,
+    },
+}
+
+impl Event {
+    fn name(&self) -> &'static str {
+        match self {
+            Event::Tip { .. } => "tip",
+            Event::Reorg { .. } => "reorg",
+            Event::Transaction { .. } => "transaction",
+            Event::PeerConnected { .. } => "peer_connected",
+            Event::PeerDisconnected { .. } => "peer_disconnected",
+            Event::PeerBanned { .. } => "peer_banned",
+        }
+    }
+
+    /// The event as a server-sent events frame.
+    pub fn frame(&self) -> String {
+        format!("event: {}\ndata: {}\n\n", self.name(), serde_json::to_string(self).unwrap())
+    }
+}
+
+/// Subscriptions to the blockchain, the mempool and the P2P server, merged into one stream.
+pub struct Feed {
+    blockchain: Arc<RwLock<Blockchain>>,
+    chain_events: Receiver<ChainEvent>,
+    transactions: Receiver<H256>,
+    peer_events: Receiver<PeerEvent>,
+}
+
+impl Feed {
+    pub fn new(
+        blockchain: &Arc<RwLock<Blockchain>>,
+        tx_mempool: &Arc<RwLock<TransactionMempool>>,
+        network: &NetworkServerHandle,
+    ) -> Self {
+        Feed {
+            blockchain: Arc::clone(blockchain),
+            chain_events: blockchain.write().unwrap().subscribe(),
+            transactions: tx_mempool.write().unwrap().subscribe(),
+            peer_events: network.subscribe(),
+        }
+    }
+
+    /// Wait for the next event for at most `timeout`. Insertions that leave the longest chain
+    /// unchanged are skipped.
+    pub fn next(&mut self, timeout: Duration) -> Option<Event> {
+        loop {
+            channel::select! {
+                recv(self.chain_events) -> event => {
+                    match event.ok().and_then(|event| self.chain_event(event)) {
+                        Some(event) => return Some(event),
+                        None => continue,
+                    }
+                }
+                recv(self.transactions) -> hash => {
+                    if let Ok(hash) = hash {
+                        return Some(Event::Transaction { hash: hash.to_string() });
+                    }
+                    self.transactions = channel::never();
+                }
+                recv(self.peer_events) -> event => {
+                    match event {
+                        Ok(event) => return Some(peer_event(event)),
+                        // the P2P server stopped
+                        Err(_) => self.peer_events = channel::never(),
+                    }
+                }
+                default(timeout) => return None,
+            }
+        }
+    }
+
+    fn chain_event(&self, event: ChainEvent) -> Option<Event> {
+        let strings = |hashes: &[H256]| hashes.iter().map(|h| h.to_string()).collect();
+        let (tip, disconnected, connected) = match &event.outcome {
+            InsertOutcome::ExtendedTip { connected } => (connected.last()?, None, connected),
+            InsertOutcome::Reorg { disconnected, connected } => {
+                (connected.last()?, Some(disconnected), connected)
+            }
+            _ => return None,
+        };
+        let height = self.blockchain.read().unwrap().height(tip)?;
+        Some(match disconnected {
+            None => Event::Tip {
+                tip: tip.to_string(),
+                height,
+                connected: strings(connected),
+            },
+            Some(disconnected) => Event::Reorg {
+                tip: tip.to_string(),
+                height,
+                disconnected: strings(disconnected),
+                connected: strings(connected),
+            },
+        })
+    }
+}
+
+fn peer_event(event: PeerEvent) -> Event {
+    match event {
+        PeerEvent::Connected(peer, direction) => Event::PeerConnected {
+            addr: peer.addr().to_string(),
+            inbound: direction == Direction::Incoming,
+        },
+        PeerEvent::Disconnected(addr) => Event::PeerDisconnected {
+            addr: addr.to_string(),
+        },
+        PeerEvent::Banned(ip) => Event::PeerBanned { ip: ip.to_string() },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_spec::ChainSpec;
+    use crate::network::server;
+
+    #[test]
+    fn merges_chain_and_mempool_events() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::from_spec(ChainSpec::regtest())));
+        let tx_mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let (msg_sender, _msg_receiver) = crossbeam::channel::unbounded();
+        // the server is never started, so its event channel is closed at once
+        let (ctx, network) = server::new("127.0.0.1:0".parse().unwrap(), msg_sender).unwrap();
+        drop(ctx);
+        let mut feed = Feed::new(&blockchain, &tx_mempool, &network);
+        assert_eq!(feed.next(Duration::from_millis(10)), None);
+
+        let block = {
+            let blockchain = blockchain.read().unwrap();
+            let content = crate::miner::block_template(&blockchain, &tx_mempool.read().unwrap(), None);
+            crate::miner::assemble_block(blockchain.tip(), blockchain.spec.initial_target, 0, 0, content)
+        };
+        blockchain.write().unwrap().insert(&block);
+        blockchain.write().unwrap().insert(&block);
+        let hash = crate::crypto::hash::Hashable::hash(&block).to_string();
+        let event = feed.next(Duration::from_secs(1)).unwrap();
+        assert_eq!(event, Event::Tip { tip: hash.clone(), height: 1, connected: vec![hash] });
+        assert!(event.frame().starts_with("event: tip\ndata: {\"type\":\"tip\""));
+        assert_eq!(feed.next(Duration::from_millis(10)), None);
+    }
+}
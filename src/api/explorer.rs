@@ -0,0 +1,220 @@
+//! JSON views of the blockchain for the read-only explorer endpoints. Hashes and addresses are
+//! written in hex.
+
+use crate::block::Block;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::memory_pool::TransactionMempool;
+use crate::transaction::SignTransaction;
+use serde::Serialize;
+
+/// Maximum number of blocks listed at once
+pub const MAX_PAGE_SIZE: u64 = 100;
+
+#[derive(Serialize)]
+pub struct HeaderView {
+    pub hash: String,
+    pub height: u64,
+    pub parent: String,
+    pub nonce: u32,
+    pub difficulty: String,
+    /// Milliseconds since the Unix epoch, narrowed from the header so that the view converts
+    /// to a `serde_json::Value`, which has no 128 bit integers
+    pub timestamp: u64,
+    pub merkle_root: String,
+}
+
+#[derive(Serialize)]
+pub struct InputView {
+    pub prev_hash: String,
+    pub index: u8,
+}
+
+#[derive(Serialize)]
+pub struct OutputView {
+    pub address: String,
+    pub value: u32,
+}
+
+#[derive(Serialize)]
+pub struct TxView {
+    /// Hash of the signed transaction, by which blocks and the mempool refer to it
+    pub hash: String,
+    /// Hash of the unsigned transaction, by which inputs refer to its outputs
+    pub txid: String,
+    pub coinbase: bool,
+    pub inputs: Vec<InputView>,
+    pub outputs: Vec<OutputView>,
+}
+
+#[derive(Serialize)]
+pub struct BlockView {
+    #[serde(flatten)]
+    pub header: HeaderView,
+    pub status: String,
+    pub confirmations: u64,
+    pub transactions: Vec<TxView>,
+}
+
+#[derive(Serialize)]
+pub struct BlockPage {
+    /// Number of blocks in the longest chain, genesis included
+    pub total: u64,
+    pub start: u64,
+    pub blocks: Vec<HeaderView>,
+}
+
+#[derive(Serialize)]
+pub struct TxLookup {
+    #[serde(flatten)]
+    pub transaction: TxView,
+    /// The block of the longest chain that includes the transaction, if any
+    pub block: Option<String>,
+    pub confirmations: u64,
+    /// Side chain blocks that include the transaction
+    pub side_blocks: Vec<String>,
+    pub in_mempool: bool,
+}
+
+#[derive(Serialize)]
+pub struct ForkStats {
+    pub height: u64,
+    pub blocks: usize,
+    /// Connected blocks that are not in the longest chain
+    pub side_chain_blocks: usize,
+    /// Blocks without children, the tip included
+    pub chain_tips: usize,
+    pub orphans: usize,
+}
+
+pub fn header_view(blockchain: &Blockchain, block: &Block) -> HeaderView {
+    let hash = block.hash();
+    HeaderView {
+        hash: hash.to_string(),
+        height: blockchain.height(&hash).unwrap_or_default(),
+        parent: block.Header.parent.to_string(),
+        nonce: block.Header.nonce,
+        difficulty: block.Header.difficulty.to_string(),
+        timestamp: block.Header.timestamp as u64,
+        merkle_root: block.Header.merkleRoot.to_string(),
+    }
+}
+
+pub fn tx_view(signed_tx: &SignTransaction) -> TxView {
+    let tx = &signed_tx.transaction;
+    TxView {
+        hash: signed_tx.hash().to_string(),
+        txid: tx.hash().to_string(),
+        coinbase: signed_tx.is_coinbase(),
+        inputs: tx
+            .tx_input
+            .iter()
+            .map(|input| InputView {
+                prev_hash: input.prev_hash.to_string(),
+                index: input.index,
+            })
+            .collect(),
+        outputs: tx
+            .tx_output
+            .iter()
+            .map(|output| OutputView {
+                address: output.recipient_address.to_string(),
+                value: output.value,
+            })
+            .collect(),
+    }
+}
+
+pub fn block_view(blockchain: &Blockchain, hash: &H256) -> Option<BlockView> {
+    let block = blockchain.get_block(hash)?;
+    Some(BlockView {
+        header: header_view(blockchain, block),
+        status: format!("{:?}", blockchain.status(hash)),
+        confirmations: blockchain.confirmations(hash),
+        transactions: block.Content.content.iter().map(tx_view).collect(),
+    })
+}
+
+/// Headers of the longest chain from height `start`, at most `limit` of them.
+pub fn block_page(blockchain: &Blockchain, start: u64, limit: u64) -> BlockPage {
+    let total = blockchain.height(&blockchain.tip()).unwrap() + 1;
+    let end = total.min(start.saturating_add(limit.min(MAX_PAGE_SIZE)));
+    BlockPage {
+        total,
+        start,
+        blocks: (start..end)
+            .filter_map(|height| blockchain.block_at_height(height))
+            .map(|hash| header_view(blockchain, &blockchain.chain[&hash]))
+            .collect(),
+    }
+}
+
+/// Find a transaction by the hash of the signed transaction, in connected blocks or the mempool.
+pub fn tx_lookup(
+    blockchain: &Blockchain,
+    mempool: &TransactionMempool,
+    hash: &H256,
+) -> Option<TxLookup> {
+    let blocks = blockchain.blocks_containing(hash);
+    let signed_tx = match blocks.first() {
+        Some(block) => blockchain.chain[block]
+            .Content
+            .content
+            .iter()
+            .find(|tx| tx.hash() == *hash)?,
+        None => mempool.tx_map.get(hash)?,
+    };
+    let main_block = blocks.iter().find(|block| blockchain.is_in_main_chain(block));
+    Some(TxLookup {
+        transaction: tx_view(signed_tx),
+        block: main_block.map(|block| block.to_string()),
+        confirmations: main_block.map_or(0, |block| blockchain.confirmations(block)),
+        side_blocks: blocks
+            .iter()
+            .filter(|block| Some(*block) != main_block)
+            .map(|block| block.to_string())
+            .collect(),
+        in_mempool: mempool.tx_to_process.get(hash) == Some(&true),
+    })
+}
+
+pub fn fork_stats(blockchain: &Blockchain) -> ForkStats {
+    let height = blockchain.height(&blockchain.tip()).unwrap();
+    ForkStats {
+        height,
+        blocks: blockchain.chain.len(),
+        side_chain_blocks: blockchain.chain.len() - (height as usize + 1),
+        chain_tips: blockchain.chain_tips().len(),
+        orphans: blockchain.orphans.len(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pages_and_lookups() {
+        let blockchain = Blockchain::new();
+        let mempool = TransactionMempool::new();
+        let page = block_page(&blockchain, 0, 1000);
+        assert_eq!(page.total, 1);
+        assert_eq!(page.blocks.len(), 1);
+        assert!(block_page(&blockchain, 5, 10).blocks.is_empty());
+
+        let genesis = blockchain.tip();
+        let view = block_view(&blockchain, &genesis).unwrap();
+        assert_eq!(view.status, "MainChain");
+        assert_eq!(view.confirmations, 1);
+        assert!(view.transactions[0].coinbase);
+
+        let genesis_tx = blockchain.chain[&genesis].Content.content[0].hash();
+        let lookup = tx_lookup(&blockchain, &mempool, &genesis_tx).unwrap();
+        assert_eq!(lookup.block, Some(genesis.to_string()));
+        assert!(!lookup.in_mempool);
+        assert!(tx_lookup(&blockchain, &mempool, &H256::default()).is_none());
+
+        let stats = fork_stats(&blockchain);
+        assert_eq!((stats.height, stats.side_chain_blocks, stats.chain_tips), (0, 0, 1));
+    }
+}
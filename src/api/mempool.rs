@@ -0,0 +1,290 @@
+//! JSON views of the pending transactions of the mempool and of the block the miner would build
+//! from them.
+//!
+//! Fee rates are in coins per 1000 bytes of serialized transaction. The fee of a transaction
+//! spending the outputs of another pending transaction counts them at their value.
+
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::memory_pool::TransactionMempool;
+use crate::transaction::{SignTransaction, UtxoInput, UtxoOutput};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+/// Lower bounds of the fee rate buckets of the histogram
+const FEE_RATE_BUCKETS: &[f64] =
+    &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0, 1000.0];
+
+#[derive(Serialize)]
+pub struct MempoolEntry {
+    /// Hash of the signed transaction
+    pub hash: String,
+    /// Hash of the unsigned transaction, by which inputs refer to its outputs
+    pub txid: String,
+    pub size: u64,
+    pub fee: u64,
+    pub fee_rate: f64,
+    /// Milliseconds since the transaction became pending
+    pub age_ms: u64,
+}
+
+#[derive(Serialize)]
+pub struct FeeBucket {
+    pub min_fee_rate: f64,
+    /// None for the last bucket
+    pub max_fee_rate: Option<f64>,
+    pub count: usize,
+    pub bytes: u64,
+}
+
+#[derive(Serialize)]
+pub struct MempoolInfo {
+    /// Number of pending transactions
+    pub size: usize,
+    /// Total serialized size of the pending transactions
+    pub bytes: u64,
+    pub total_fee: u64,
+    pub fee_histogram: Vec<FeeBucket>,
+}
+
+#[derive(Serialize)]
+pub struct Relatives {
+    #[serde(flatten)]
+    pub entry: MempoolEntry,
+    /// Pending transactions whose outputs it spends, directly or not
+    pub ancestors: Vec<String>,
+    /// Pending transactions spending its outputs, directly or not
+    pub descendants: Vec<String>,
+}
+
+#[derive(Serialize)]
+pub struct TemplateView {
+    pub parent: String,
+    pub height: u64,
+    /// Value the coinbase may create on top of the fees
+    pub reward: u32,
+    pub total_fee: u64,
+    pub size: u64,
+    pub transactions: Vec<MempoolEntry>,
+}
+
+/// The pending transactions with what is needed to value their inputs.
+struct Pool<'a> {
+    blockchain: &'a Blockchain,
+    mempool: &'a TransactionMempool,
+    pending: Vec<(H256, &'a SignTransaction)>,
+    /// Outputs of the pending transactions
+    outputs: HashMap<UtxoInput, UtxoOutput>,
+    /// Hash of the signed pending transaction of each txid
+    by_txid: HashMap<H256, H256>,
+}
+
+impl<'a> Pool<'a> {
+    fn new(blockchain: &'a Blockchain, mempool: &'a TransactionMempool) -> Self {
+        let pending: Vec<(H256, &SignTransaction)> =
+            mempool.pending().map(|(hash, signed_tx)| (*hash, signed_tx)).collect();
+        let mut outputs = HashMap::new();
+        let mut by_txid = HashMap::new();
+        for (hash, signed_tx) in &pending {
+            let txid = signed_tx.transaction.hash();
+            by_txid.insert(txid, *hash);
+            for (i, output) in signed_tx.transaction.tx_output.iter().enumerate() {
+                outputs.insert(UtxoInput { prev_hash: txid, index: i as u8 }, *output);
+            }
+        }
+        Pool { blockchain, mempool, pending, outputs, by_txid }
+    }
+
+    fn fee(&self, signed_tx: &SignTransaction) -> u64 {
+        let state = self.blockchain.tip_state();
+        let input_value: u64 = signed_tx
+            .transaction
+            .tx_input
+            .iter()
+            .filter_map(|input| state.get(input).or_else(|| self.outputs.get(input)))
+            .map(|output| output.value as u64)
+            .sum();
+        let output_value: u64 =
+            signed_tx.transaction.tx_output.iter().map(|output| output.value as u64).sum();
+        input_value.saturating_sub(output_value)
+    }
+
+    fn entry(&self, hash: &H256, signed_tx: &SignTransaction, now: SystemTime) -> MempoolEntry {
+        let size = bincode::serialized_size(signed_tx).unwrap();
+        let fee = self.fee(signed_tx);
+        let age = self
+            .mempool
+            .entered(hash)
+            .and_then(|entered| now.duration_since(entered).ok())
+            .unwrap_or_default();
+        MempoolEntry {
+            hash: hash.to_string(),
+            txid: signed_tx.transaction.hash().to_string(),
+            size,
+            fee,
+            fee_rate: fee_rate(fee, size),
+            age_ms: age.as_millis() as u64,
+        }
+    }
+
+    /// Pending transactions whose outputs `signed_tx` spends
+    fn parents(&self, signed_tx: &SignTransaction) -> Vec<H256> {
+        signed_tx
+            .transaction
+            .tx_input
+            .iter()
+            .filter_map(|input| self.by_txid.get(&input.prev_hash).copied())
+            .collect()
+    }
+
+    /// Follow `next` from `hash`, returning every transaction reached in the order found
+    fn walk(&self, hash: H256, next: impl Fn(&H256) -> Vec<H256>) -> Vec<String> {
+        let mut seen = HashSet::new();
+        let mut queue = vec![hash];
+        let mut found = vec![];
+        while let Some(hash) = queue.pop() {
+            for relative in next(&hash) {
+                if seen.insert(relative) {
+                    found.push(relative.to_string());
+                    queue.push(relative);
+                }
+            }
+        }
+        found
+    }
+}
+
+fn fee_rate(fee: u64, size: u64) -> f64 {
+    if size == 0 {
+        return 0.0;
+    }
+    fee as f64 * 1000.0 / size as f64
+}
+
+pub fn info(blockchain: &Blockchain, mempool: &TransactionMempool) -> MempoolInfo {
+    let pool = Pool::new(blockchain, mempool);
+    let now = SystemTime::now();
+    let entries: Vec<MempoolEntry> =
+        pool.pending.iter().map(|(hash, signed_tx)| pool.entry(hash, signed_tx, now)).collect();
+    let fee_histogram = FEE_RATE_BUCKETS
+        .iter()
+        .enumerate()
+        .map(|(i, min)| {
+            let max = FEE_RATE_BUCKETS.get(i + 1).copied();
+            let in_bucket: Vec<&MempoolEntry> = entries
+                .iter()
+                .filter(|entry| {
+                    entry.fee_rate >= *min && entry.fee_rate < max.unwrap_or(f64::INFINITY)
+                })
+                .collect();
+            FeeBucket {
+                min_fee_rate: *min,
+                max_fee_rate: max,
+                count: in_bucket.len(),
+                bytes: in_bucket.iter().map(|entry| entry.size).sum(),
+            }
+        })
+        .collect();
+    MempoolInfo {
+        size: entries.len(),
+        bytes: entries.iter().map(|entry| entry.size).sum(),
+        total_fee: entries.iter().map(|entry| entry.fee).sum(),
+        fee_histogram,
+    }
+}
+
+/// The pending transactions in arrival order.
+pub fn entries(blockchain: &Blockchain, mempool: &TransactionMempool) -> Vec<MempoolEntry> {
+    let pool = Pool::new(blockchain, mempool);
+    let now = SystemTime::now();
+    pool.pending.iter().map(|(hash, signed_tx)| pool.entry(hash, signed_tx, now)).collect()
+}
+
+/// A pending transaction with its pending ancestors and descendants, none if it is not pending.
+pub fn relatives(
+    blockchain: &Blockchain,
+    mempool: &TransactionMempool,
+    hash: &H256,
+) -> Option<Relatives> {
+    let pool = Pool::new(blockchain, mempool);
+    let signed_tx = pool.pending.iter().find(|(pending, _)| pending == hash)?.1;
+    let mut children: HashMap<H256, Vec<H256>> = HashMap::new();
+    for (child, child_tx) in &pool.pending {
+        for parent in pool.parents(child_tx) {
+            children.entry(parent).or_default().push(*child);
+        }
+    }
+    let ancestors = pool.walk(*hash, |hash| {
+        pool.pending
+            .iter()
+            .find(|(pending, _)| pending == hash)
+            .map_or(vec![], |(_, signed_tx)| pool.parents(signed_tx))
+    });
+    let descendants = pool.walk(*hash, |hash| children.get(hash).cloned().unwrap_or_default());
+    Some(Relatives {
+        entry: pool.entry(hash, signed_tx, SystemTime::now()),
+        ancestors,
+        descendants,
+    })
+}
+
+/// The transactions the miner would put in a block on the tip now, without its coinbase.
+pub fn template(blockchain: &Blockchain, mempool: &TransactionMempool) -> TemplateView {
+    let pool = Pool::new(blockchain, mempool);
+    let now = SystemTime::now();
+    let transactions: Vec<MempoolEntry> = crate::miner::block_template(blockchain, mempool, None)
+        .iter()
+        .map(|signed_tx| pool.entry(&signed_tx.hash(), signed_tx, now))
+        .collect();
+    let tip = blockchain.tip();
+    let height = blockchain.height(&tip).unwrap() + 1;
+    TemplateView {
+        parent: tip.to_string(),
+        height,
+        reward: blockchain.spec.reward(height),
+        total_fee: transactions.iter().map(|entry| entry.fee).sum(),
+        size: transactions.iter().map(|entry| entry.size).sum(),
+        transactions,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::address;
+    use crate::fixtures;
+    use ring::signature::KeyPair;
+
+    #[test]
+    fn fees_relatives_and_template() {
+        let key = fixtures::key(9);
+        let owner = address::address_from_public_key_ref(key.public_key());
+        let (blockchain, coin, reward) = fixtures::chain_with_coinbase_to(owner);
+        let mut mempool = TransactionMempool::new();
+
+        let parent = fixtures::sign_spend(&key, vec![coin], vec![UtxoOutput { recipient_address: owner, value: reward - 5 }]);
+        let parent_hash = mempool.admit(parent.clone(), blockchain.tip_state()).unwrap();
+        let change = UtxoInput { prev_hash: parent.transaction.hash(), index: 0 };
+        let child = fixtures::sign_spend(&key, vec![change], vec![UtxoOutput { recipient_address: owner, value: reward - 7 }]);
+        let child_hash = mempool.admit(child, blockchain.tip_state()).unwrap();
+
+        let info = info(&blockchain, &mempool);
+        assert_eq!((info.size, info.total_fee), (2, 7));
+        assert_eq!(info.fee_histogram.iter().map(|bucket| bucket.count).sum::<usize>(), 2);
+        let listed = entries(&blockchain, &mempool);
+        assert_eq!(listed[1].fee, 2);
+        assert!(listed[0].fee_rate > listed[1].fee_rate);
+
+        let of_parent = relatives(&blockchain, &mempool, &parent_hash).unwrap();
+        assert!(of_parent.ancestors.is_empty());
+        assert_eq!(of_parent.descendants, vec![child_hash.to_string()]);
+        let of_child = relatives(&blockchain, &mempool, &child_hash).unwrap();
+        assert_eq!(of_child.ancestors, vec![parent_hash.to_string()]);
+        assert!(relatives(&blockchain, &mempool, &H256::default()).is_none());
+
+        let projected = template(&blockchain, &mempool);
+        assert_eq!((projected.height, projected.total_fee), (2, 7));
+        assert_eq!(projected.transactions.len(), 2);
+    }
+}
@@ -1,12 +1,31 @@
+mod address;
+pub mod auth;
+mod events;
+mod explorer;
+mod mempool;
+mod peers;
+mod rpc;
+
 use serde::Serialize;
+use crate::blockchain::{Blockchain, InsertOutcome};
+use crate::crypto::address::H160;
+use crate::crypto::hash::H256;
+use crate::memory_pool::TransactionMempool;
+use crate::metrics;
+use crate::transaction::SignTransaction;
 use crate::miner::Handle as MinerHandle;
 use crate::network::server::Handle as NetworkServerHandle;
-use crate::network::message::Message;
+use crate::network::transport::Transport;
+use crate::network::peer::LinkConditions;
+use crate::wallet::{self, Wallet};
 
 use log::info;
 use std::collections::HashMap;
+use std::io::Write;
+use std::sync::{Arc, RwLock};
 use std::thread;
 use tiny_http::Header;
+use tiny_http::Method;
 use tiny_http::Response;
 use tiny_http::Server as HTTPServer;
 use url::Url;
@@ -15,6 +34,10 @@ pub struct Server {
     handle: HTTPServer,
     miner: MinerHandle,
     network: NetworkServerHandle,
+    blockchain: Arc<RwLock<Blockchain>>,
+    tx_mempool: Arc<RwLock<TransactionMempool>>,
+    wallet: Arc<RwLock<Wallet>>,
+    access: Arc<auth::AccessControl>,
 }
 
 #[derive(Serialize)]
@@ -36,22 +59,95 @@ macro_rules! respond_result {
     }};
 }
 
+macro_rules! respond_json {
+    ( $req:expr, $data:expr ) => {{
+        let content_type = "Content-Type: application/json".parse::<Header>().unwrap();
+        let resp = Response::from_string(serde_json::to_string_pretty(&$data).unwrap())
+            .with_header(content_type);
+        $req.respond(resp).unwrap();
+    }};
+}
+
+#[derive(Serialize)]
+struct TxStatus {
+    hash: String,
+    confirmations: Option<u64>,
+    k: u64,
+    is_final: bool,
+}
+
+/// The result of submitting a transaction. `reason` is `malformed` if it could not be decoded,
+/// or else a mempool `Rejection`.
+#[derive(Serialize)]
+struct TxSubmission {
+    accepted: bool,
+    hash: Option<String>,
+    reason: Option<String>,
+    message: String,
+}
+
+impl TxSubmission {
+    fn rejected(reason: &str, message: impl std::fmt::Display) -> Self {
+        TxSubmission {
+            accepted: false,
+            hash: None,
+            reason: Some(reason.to_string()),
+            message: message.to_string(),
+        }
+    }
+}
+
+/// How the longest chain changed, with hashes in hex.
+#[derive(Serialize)]
+struct ChainUpdate {
+    tip: String,
+    disconnected: Vec<String>,
+    connected: Vec<String>,
+}
+
+impl ChainUpdate {
+    fn new(outcome: &InsertOutcome, tip: H256) -> Self {
+        let (disconnected, connected) = match outcome {
+            InsertOutcome::ExtendedTip { connected } => (&[][..], &connected[..]),
+            InsertOutcome::Reorg { disconnected, connected } => (&disconnected[..], &connected[..]),
+            _ => (&[][..], &[][..]),
+        };
+        ChainUpdate {
+            tip: tip.to_string(),
+            disconnected: disconnected.iter().map(|h| h.to_string()).collect(),
+            connected: connected.iter().map(|h| h.to_string()).collect(),
+        }
+    }
+}
+
 impl Server {
     pub fn start(
         addr: std::net::SocketAddr,
         miner: &MinerHandle,
         network: &NetworkServerHandle,
+        blockchain: &Arc<RwLock<Blockchain>>,
+        tx_mempool: &Arc<RwLock<TransactionMempool>>,
+        wallet: &Arc<RwLock<Wallet>>,
+        access: auth::AccessControl,
     ) {
         let handle = HTTPServer::http(&addr).unwrap();
         let server = Self {
             handle,
             miner: miner.clone(),
             network: network.clone(),
+            blockchain: Arc::clone(blockchain),
+            tx_mempool: Arc::clone(tx_mempool),
+            wallet: Arc::clone(wallet),
+            access: Arc::new(access),
         };
         thread::spawn(move || {
-            for req in server.handle.incoming_requests() {
+            for mut req in server.handle.incoming_requests() {
                 let miner = server.miner.clone();
                 let network = server.network.clone();
+                let blockchain = Arc::clone(&server.blockchain);
+                let tx_mempool = Arc::clone(&server.tx_mempool);
+                let wallet = Arc::clone(&server.wallet);
+                let access = Arc::clone(&server.access);
                 thread::spawn(move || {
                     // a valid url requires a base
                     let base_url = Url::parse(&format!("http://{}/", &addr)).unwrap();
@@ -62,6 +158,34 @@ impl Server {
                             return;
                         }
                     };
+                    let authorization = req
+                        .headers()
+                        .iter()
+                        .find(|header| header.field.equiv("Authorization"))
+                        .map(|header| header.value.as_str().to_string());
+                    let client = req.remote_addr().ip();
+                    let required = auth::required_role(req.method().as_str(), url.path());
+                    let role = match access.authorize(client, authorization.as_deref(), required) {
+                        Ok(role) => role,
+                        Err(denied) => {
+                            let content_type =
+                                "Content-Type: application/json".parse::<Header>().unwrap();
+                            let payload = ApiResponse {
+                                success: false,
+                                message: denied.message().to_string(),
+                            };
+                            let mut resp = Response::from_string(
+                                serde_json::to_string_pretty(&payload).unwrap(),
+                            )
+                            .with_header(content_type)
+                            .with_status_code(denied.status());
+                            if denied == auth::Denied::Unauthenticated {
+                                resp.add_header("WWW-Authenticate: Bearer".parse::<Header>().unwrap());
+                            }
+                            req.respond(resp).unwrap();
+                            return;
+                        }
+                    };
                     match url.path() {
                         "/miner/start" => {
                             let params = url.query_pairs();
@@ -84,13 +208,529 @@ impl Server {
                                     return;
                                 }
                             };
-                            miner.start(lambda);
-                            respond_result!(req, true, "ok");
+                            match miner.start(lambda) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/events" => {
+                            let mut feed = events::Feed::new(&blockchain, &tx_mempool, &network);
+                            let headers = vec![
+                                "Content-Type: text/event-stream".parse::<Header>().unwrap(),
+                                "Cache-Control: no-cache".parse::<Header>().unwrap(),
+                            ];
+                            let response = Response::empty(200);
+                            let response = headers
+                                .into_iter()
+                                .fold(response, |response, header| response.with_header(header));
+                            // tiny_http buffers chunked bodies, so the frames are written on the
+                            // raw connection, which stays open until the client goes away
+                            let mut stream = req.upgrade("event-stream", response);
+                            loop {
+                                let frame = match feed.next(events::KEEPALIVE_INTERVAL) {
+                                    Some(event) => event.frame(),
+                                    None => events::KEEPALIVE.to_string(),
+                                };
+                                if stream
+                                    .write_all(frame.as_bytes())
+                                    .and_then(|_| stream.flush())
+                                    .is_err()
+                                {
+                                    break;
+                                }
+                            }
+                        }
+                        "/metrics" => {
+                            let (height, orphans) = {
+                                let blockchain = blockchain.read().unwrap();
+                                (blockchain.height(&blockchain.tip()).unwrap(), blockchain.orphans.len())
+                            };
+                            let (mempool_transactions, mempool_bytes) = {
+                                let tx_mempool = tx_mempool.read().unwrap();
+                                let sizes: Vec<u64> = tx_mempool
+                                    .pending()
+                                    .map(|(_, signed_tx)| bincode::serialized_size(signed_tx).unwrap())
+                                    .collect();
+                                (sizes.len() as u64, sizes.iter().sum())
+                            };
+                            let gauges = [
+                                ("bitcoin_chain_height", "Height of the tip of the longest chain", height),
+                                ("bitcoin_orphan_blocks", "Blocks in the orphan pool", orphans as u64),
+                                ("bitcoin_mempool_transactions", "Pending transactions", mempool_transactions),
+                                (
+                                    "bitcoin_mempool_bytes",
+                                    "Serialized size of the pending transactions",
+                                    mempool_bytes,
+                                ),
+                                ("bitcoin_peers", "Connected peers", network.peers().len() as u64),
+                            ];
+                            let content_type = "Content-Type: text/plain; version=0.0.4"
+                                .parse::<Header>()
+                                .unwrap();
+                            let resp = Response::from_string(metrics::global().render(&gauges))
+                                .with_header(content_type);
+                            req.respond(resp).unwrap();
+                        }
+                        "/mempool/info" | "/mempool/transactions" | "/mempool/template" => {
+                            let blockchain = blockchain.read().unwrap();
+                            let tx_mempool = tx_mempool.read().unwrap();
+                            match url.path() {
+                                "/mempool/info" => {
+                                    respond_json!(req, mempool::info(&blockchain, &tx_mempool))
+                                }
+                                "/mempool/transactions" => {
+                                    respond_json!(req, mempool::entries(&blockchain, &tx_mempool))
+                                }
+                                _ => respond_json!(req, mempool::template(&blockchain, &tx_mempool)),
+                            }
+                        }
+                        "/mempool/entry" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match required_param::<H256>(&params, "hash") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.read().unwrap();
+                            let tx_mempool = tx_mempool.read().unwrap();
+                            match mempool::relatives(&blockchain, &tx_mempool, &hash) {
+                                Some(relatives) => respond_json!(req, relatives),
+                                None => respond_result!(req, false, "transaction not pending"),
+                            }
+                        }
+                        "/chain/tip" => {
+                            let blockchain = blockchain.read().unwrap();
+                            let tip = blockchain.tip();
+                            respond_json!(
+                                req,
+                                explorer::header_view(&blockchain, &blockchain.chain[&tip])
+                            );
+                        }
+                        "/chain/block" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let blockchain = blockchain.read().unwrap();
+                            let hash = if params.contains_key("height") {
+                                match required_param::<u64>(&params, "height") {
+                                    Ok(height) => blockchain.block_at_height(height),
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                }
+                            } else {
+                                match required_param::<H256>(&params, "hash") {
+                                    Ok(hash) => Some(hash),
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                }
+                            };
+                            match hash.and_then(|hash| explorer::block_view(&blockchain, &hash)) {
+                                Some(view) => respond_json!(req, view),
+                                None => respond_result!(req, false, "block not found"),
+                            }
+                        }
+                        "/chain/blocks" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let start = match optional_param(&params, "start", 0) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let limit = match optional_param(&params, "limit", 20) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.read().unwrap();
+                            respond_json!(req, explorer::block_page(&blockchain, start, limit));
+                        }
+                        "/chain/forks" => {
+                            let blockchain = blockchain.read().unwrap();
+                            respond_json!(req, explorer::fork_stats(&blockchain));
+                        }
+                        "/rpc" if *req.method() == Method::Post => {
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let node = rpc::Node {
+                                blockchain: &blockchain,
+                                tx_mempool: &tx_mempool,
+                                network: &network,
+                                wallet: &wallet,
+                                role,
+                            };
+                            match node.handle(&body) {
+                                Some(response) => respond_json!(req, response),
+                                // only notifications were sent
+                                None => req.respond(Response::empty(204)).unwrap(),
+                            }
+                        }
+                        "/tx" if *req.method() == Method::Post => {
+                            let mut body = String::new();
+                            if let Err(e) = req.as_reader().read_to_string(&mut body) {
+                                respond_result!(req, false, format!("error reading body: {}", e));
+                                return;
+                            }
+                            let signed_tx = match decode_transaction(&body) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_json!(req, TxSubmission::rejected("malformed", e));
+                                    return;
+                                }
+                            };
+                            let admitted = {
+                                let blockchain = blockchain.read().unwrap();
+                                let mut tx_mempool = tx_mempool.write().unwrap();
+                                tx_mempool.admit(signed_tx, blockchain.tip_state())
+                            };
+                            match admitted {
+                                Ok(hash) => {
+                                    network.relay_transactions(vec![hash], None);
+                                    respond_json!(req, TxSubmission {
+                                        accepted: true,
+                                        hash: Some(hash.to_string()),
+                                        reason: None,
+                                        message: "ok".to_string(),
+                                    });
+                                }
+                                Err(rejection) => {
+                                    respond_json!(
+                                        req,
+                                        TxSubmission::rejected(rejection.code(), rejection)
+                                    );
+                                }
+                            }
+                        }
+                        "/tx" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match required_param::<H256>(&params, "hash") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.read().unwrap();
+                            let tx_mempool = tx_mempool.read().unwrap();
+                            match explorer::tx_lookup(&blockchain, &tx_mempool, &hash) {
+                                Some(lookup) => respond_json!(req, lookup),
+                                None => respond_result!(req, false, "transaction not found"),
+                            }
+                        }
+                        "/address/balance" | "/address/utxos" | "/address/history" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match required_param::<H160>(&params, "address") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let include_mempool = match optional_param(&params, "mempool", false) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.read().unwrap();
+                            let tx_mempool = tx_mempool.read().unwrap();
+                            let mempool = if include_mempool { Some(&*tx_mempool) } else { None };
+                            match url.path() {
+                                "/address/balance" => {
+                                    respond_json!(req, address::balance(&blockchain, mempool, &addr))
+                                }
+                                "/address/utxos" => {
+                                    respond_json!(req, address::utxos(&blockchain, mempool, &addr))
+                                }
+                                _ => respond_json!(req, address::history(&blockchain, mempool, &addr)),
+                            }
+                        }
+                        "/wallet/balance" => {
+                            let wallet = wallet.read().unwrap();
+                            let tx_mempool = tx_mempool.read().unwrap();
+                            respond_json!(req, wallet.balance(&tx_mempool));
+                        }
+                        "/wallet/addresses" => {
+                            let addresses: Vec<String> = wallet
+                                .read()
+                                .unwrap()
+                                .addresses()
+                                .iter()
+                                .map(|address| address.to_string())
+                                .collect();
+                            respond_json!(req, addresses);
+                        }
+                        "/wallet/new-address" => match wallet.write().unwrap().new_address() {
+                            Ok(address) => respond_result!(req, true, address),
+                            Err(e) => respond_result!(req, false, e),
+                        },
+                        "/wallet/send" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let payment = match parse_payment(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let sent = {
+                                let blockchain = blockchain.read().unwrap();
+                                let wallet = wallet.read().unwrap();
+                                let mut tx_mempool = tx_mempool.write().unwrap();
+                                wallet.send(&blockchain, &mut tx_mempool, &payment)
+                            };
+                            match sent {
+                                Ok(hash) => {
+                                    network.relay_transactions(vec![hash], None);
+                                    respond_json!(req, TxSubmission {
+                                        accepted: true,
+                                        hash: Some(hash.to_string()),
+                                        reason: None,
+                                        message: "ok".to_string(),
+                                    });
+                                }
+                                Err(e) => respond_json!(req, TxSubmission::rejected(e.code(), e)),
+                            }
+                        }
+                        "/miner/pause" => {
+                            match miner.pause() {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/resume" => {
+                            match miner.resume() {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/stop" => {
+                            match miner.stop() {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/status" => {
+                            match miner.status() {
+                                Ok(status) => respond_json!(req, status),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/payout" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let payout = if params.contains_key("address") {
+                                match required_param::<H160>(&params, "address") {
+                                    Ok(v) => Some(v),
+                                    Err(e) => {
+                                        respond_result!(req, false, e);
+                                        return;
+                                    }
+                                }
+                            } else {
+                                None
+                            };
+                            match miner.set_payout(payout) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/threads" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let threads = match required_param::<usize>(&params, "n") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            if threads == 0 {
+                                respond_result!(req, false, "n must be at least 1");
+                                return;
+                            }
+                            match miner.set_threads(threads) {
+                                Ok(()) => respond_result!(req, true, "ok"),
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/miner/generate" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let n = match required_param::<u64>(&params, "n") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let address = match required_param::<H160>(&params, "address") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            match miner.generate(n, address) {
+                                Ok(hashes) => {
+                                    let hashes: Vec<String> = hashes.iter().map(|h| h.to_string()).collect();
+                                    respond_json!(req, hashes);
+                                }
+                                Err(e) => respond_result!(req, false, e),
+                            }
+                        }
+                        "/chain/invalidate" | "/chain/reconsider" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match required_param::<H256>(&params, "hash") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let mut blockchain = blockchain.write().unwrap();
+                            let outcome = if url.path() == "/chain/invalidate" {
+                                blockchain.invalidate_block(&hash)
+                            } else {
+                                blockchain.reconsider_block(&hash)
+                            };
+                            match outcome {
+                                Ok(outcome) => {
+                                    tx_mempool.write().unwrap().update(&outcome, &blockchain);
+                                    respond_json!(req, ChainUpdate::new(&outcome, blockchain.tip()));
+                                }
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                }
+                            }
                         }
                         "/network/ping" => {
-                            network.broadcast(Message::Ping(String::from("Test ping")));
+                            network.ping_peers();
                             respond_result!(req, true, "ok");
                         }
+                        "/network/peers" => {
+                            let peers: Vec<_> = network.peers().iter().map(peers::peer_view).collect();
+                            respond_json!(req, peers);
+                        }
+                        "/network/banned" => {
+                            let banned = network.banned().iter().map(|ip| ip.to_string()).collect();
+                            respond_json!(req, peers::BanList { banned });
+                        }
+                        "/network/connect" | "/network/disconnect" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let addr = match required_param::<std::net::SocketAddr>(&params, "addr") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            if url.path() == "/network/disconnect" {
+                                if network.disconnect(addr) {
+                                    respond_result!(req, true, "ok");
+                                } else {
+                                    respond_result!(req, false, "peer not connected");
+                                }
+                                return;
+                            }
+                            match network.connect(addr) {
+                                Ok(_) => respond_result!(req, true, "ok"),
+                                Err(e) => {
+                                    respond_result!(req, false, format!("error connecting: {}", e))
+                                }
+                            }
+                        }
+                        "/network/ban" | "/network/unban" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let ip = match required_param::<std::net::IpAddr>(&params, "ip") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            if url.path() == "/network/ban" {
+                                network.ban(ip);
+                                respond_result!(req, true, "ok");
+                            } else if network.unban(ip) {
+                                respond_result!(req, true, "ok");
+                            } else {
+                                respond_result!(req, false, "address not banned");
+                            }
+                        }
+                        "/network/link" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let conditions = match parse_link_conditions(&params) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let peer = match params.get("peer") {
+                                Some(v) => match v.parse::<std::net::SocketAddr>() {
+                                    Ok(v) => Some(v),
+                                    Err(e) => {
+                                        respond_result!(
+                                            req,
+                                            false,
+                                            format!("error parsing peer: {}", e)
+                                        );
+                                        return;
+                                    }
+                                },
+                                None => None,
+                            };
+                            network.set_link_conditions(conditions, peer);
+                            respond_result!(req, true, "ok");
+                        }
+                        "/tx/status" => {
+                            let params = url.query_pairs();
+                            let params: HashMap<_, _> = params.into_owned().collect();
+                            let hash = match required_param::<H256>(&params, "hash") {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let blockchain = blockchain.read().unwrap();
+                            let k = match optional_param(&params, "k", blockchain.confirmation_depth) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    respond_result!(req, false, e);
+                                    return;
+                                }
+                            };
+                            let status = TxStatus {
+                                hash: hash.to_string(),
+                                confirmations: blockchain.tx_confirmations(&hash),
+                                k,
+                                is_final: blockchain.is_tx_final(&hash, k),
+                            };
+                            respond_json!(req, status);
+                        }
                         _ => {
                             let content_type =
                                 "Content-Type: application/json".parse::<Header>().unwrap();
@@ -112,3 +752,83 @@ impl Server {
         info!("API server listening at {}", &addr);
     }
 }
+
+/// Decode a transaction given either as JSON or as the hex of its bincode encoding, which is how
+/// it is sent to peers.
+fn decode_transaction(body: &str) -> Result<SignTransaction, String> {
+    let body = body.trim();
+    if body.starts_with('{') {
+        serde_json::from_str(body).map_err(|e| format!("error parsing JSON transaction: {}", e))
+    } else {
+        let bytes = hex::decode(body).map_err(|e| format!("error parsing hex transaction: {}", e))?;
+        bincode::deserialize(&bytes).map_err(|e| format!("error decoding transaction: {}", e))
+    }
+}
+
+/// Parse a query parameter that must be present.
+fn required_param<T>(params: &HashMap<String, String>, name: &str) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match params.get(name) {
+        Some(v) => v
+            .parse::<T>()
+            .map_err(|e| format!("error parsing {}: {}", name, e)),
+        None => Err(format!("missing {}", name)),
+    }
+}
+
+/// Parse a query parameter, using `default` if it is missing.
+fn optional_param<T>(params: &HashMap<String, String>, name: &str, default: T) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    match params.get(name) {
+        Some(_) => required_param(params, name),
+        None => Ok(default),
+    }
+}
+
+/// Parse a payment from the wallet from the `to`, `amount`, `fee`, `selection` and `max_excess`
+/// query parameters. The fee and the excess default to zero, and coins are selected by branch
+/// and bound unless `selection` is `largest-first`.
+fn parse_payment(params: &HashMap<String, String>) -> Result<wallet::Payment, String> {
+    Ok(wallet::Payment {
+        to: required_param(params, "to")?,
+        amount: required_param(params, "amount")?,
+        fee: optional_param(params, "fee", 0)?,
+        strategy: optional_param(params, "selection", wallet::CoinSelection::BranchAndBound)?,
+        max_excess: optional_param(params, "max_excess", 0)?,
+    })
+}
+
+/// Parse link conditions from the `delay`, `jitter`, `bandwidth` and `loss` query parameters.
+/// Missing parameters are left at zero.
+fn parse_link_conditions(params: &HashMap<String, String>) -> Result<LinkConditions, String> {
+    fn parse<T: std::str::FromStr + Default>(
+        params: &HashMap<String, String>,
+        name: &str,
+    ) -> Result<T, String>
+    where
+        T::Err: std::fmt::Display,
+    {
+        match params.get(name) {
+            Some(v) => v
+                .parse::<T>()
+                .map_err(|e| format!("error parsing {}: {}", name, e)),
+            None => Ok(T::default()),
+        }
+    }
+    let conditions = LinkConditions {
+        delay_ms: parse(params, "delay")?,
+        jitter_ms: parse(params, "jitter")?,
+        bandwidth: parse(params, "bandwidth")?,
+        loss: parse(params, "loss")?,
+    };
+    if !(0.0..=1.0).contains(&conditions.loss) {
+        return Err("loss must be between 0 and 1".to_string());
+    }
+    Ok(conditions)
+}
@@ -0,0 +1,79 @@
+//! JSON views of the peers of the P2P server and of the banned addresses.
+
+use crate::network::peer::Direction;
+use crate::network::server::PeerInfo;
+use serde::Serialize;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Serialize)]
+pub struct PeerView {
+    pub addr: String,
+    /// `inbound` if the peer connected to us, `outbound` if we connected to it
+    pub direction: &'static str,
+    /// Milliseconds since the Unix epoch
+    pub connected_since: u64,
+    /// Round trip time of the last answered ping
+    pub latency_ms: Option<f64>,
+    /// The latest block the peer announced, which is its tip right after it connects
+    pub announced_tip: Option<String>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
+}
+
+#[derive(Serialize)]
+pub struct BanList {
+    pub banned: Vec<String>,
+}
+
+pub fn peer_view(peer: &PeerInfo) -> PeerView {
+    let stats = &peer.stats;
+    PeerView {
+        addr: peer.addr.to_string(),
+        direction: match peer.direction {
+            Direction::Incoming => "inbound",
+            Direction::Outgoing => "outbound",
+        },
+        connected_since: unix_millis(stats.connected_since),
+        latency_ms: stats.latency.map(|latency| latency.as_secs_f64() * 1000.0),
+        announced_tip: stats.announced_tip.map(|hash| hash.to_string()),
+        bytes_sent: stats.bytes_sent,
+        bytes_received: stats.bytes_received,
+        messages_sent: stats.messages_sent,
+        messages_received: stats.messages_received,
+    }
+}
+
+pub fn unix_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::H256;
+    use crate::network::peer::PeerStats;
+    use std::time::Duration;
+
+    #[test]
+    fn views_peer_stats() {
+        let peer = PeerInfo {
+            addr: "10.0.0.2:6000".parse().unwrap(),
+            direction: Direction::Incoming,
+            stats: PeerStats {
+                connected_since: UNIX_EPOCH + Duration::from_millis(1500),
+                latency: Some(Duration::from_micros(2500)),
+                announced_tip: Some(H256::default()),
+                bytes_sent: 10,
+                bytes_received: 20,
+                messages_sent: 1,
+                messages_received: 2,
+            },
+        };
+        let view = peer_view(&peer);
+        assert_eq!((view.direction, view.connected_since), ("inbound", 1500));
+        assert_eq!(view.latency_ms, Some(2.5));
+        assert_eq!(view.announced_tip, Some(H256::default().to_string()));
+    }
+}
@@ -0,0 +1,429 @@
+//! A JSON-RPC 2.0 interface at `POST /rpc`, so that scripts can use existing RPC client
+//! libraries. Requests may be sent alone or in batches, and parameters may be given by position
+//! or by name. Method names, parameters and application error codes follow bitcoind where it
+//! has an equivalent method.
+//!
+//! Hashes are written in hex, and raw blocks and transactions are the hex of their bincode
+//! encoding, which is how they are sent to peers.
+
+use super::auth::{self, Role};
+use super::explorer;
+use crate::blockchain::Blockchain;
+use crate::crypto::hash::{H256, Hashable};
+use crate::memory_pool::TransactionMempool;
+use crate::network::peer::Direction;
+use crate::network::server::Handle as NetworkServerHandle;
+use crate::network::transport::Transport;
+use crate::transaction::SignTransaction;
+use crate::wallet::{CoinSelection, Payment, SendError, Wallet};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::{Arc, RwLock};
+
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+/// The requested block or transaction is unknown
+pub const NOT_FOUND: i64 = -5;
+/// A raw block or transaction could not be decoded
+pub const DESERIALIZATION_ERROR: i64 = -22;
+/// A transaction was refused by the mempool
+pub const VERIFY_REJECTED: i64 = -26;
+/// The wallet could not make a payment
+pub const WALLET_ERROR: i64 = -4;
+/// The wallet has no key with enough spendable coins for a payment
+pub const WALLET_INSUFFICIENT_FUNDS: i64 = -6;
+/// The method requires admin access, which the token of the request does not grant
+pub const FORBIDDEN: i64 = -32001;
+
+#[derive(Serialize, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl std::fmt::Display) -> Self {
+        RpcError {
+            code,
+            message: message.to_string(),
+            data: None,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PeerView {
+    addr: String,
+    inbound: bool,
+    /// Seconds since the Unix epoch
+    conntime: u64,
+    /// Round trip time of the last answered ping, in seconds
+    pingtime: Option<f64>,
+    bytessent: u64,
+    bytesrecv: u64,
+}
+
+/// The parts of the node the methods read and act on.
+pub struct Node<'a> {
+    pub blockchain: &'a Arc<RwLock<Blockchain>>,
+    pub tx_mempool: &'a Arc<RwLock<TransactionMempool>>,
+    pub network: &'a NetworkServerHandle,
+    pub wallet: &'a Arc<RwLock<Wallet>>,
+    /// The role granted to the client, which methods that submit transactions or change the
+    /// wallet require to be admin
+    pub role: Role,
+}
+
+impl<'a> Node<'a> {
+    /// Answer the body of an HTTP request. Returns none if there is nothing to answer, which is
+    /// the case when the body only holds notifications.
+    pub fn handle(&self, body: &str) -> Option<Value> {
+        let request: Value = match serde_json::from_str(body) {
+            Ok(v) => v,
+            Err(e) => return Some(error_response(Value::Null, RpcError::new(PARSE_ERROR, e))),
+        };
+        match request {
+            Value::Array(batch) => {
+                if batch.is_empty() {
+                    let error = RpcError::new(INVALID_REQUEST, "empty batch");
+                    return Some(error_response(Value::Null, error));
+                }
+                let responses: Vec<Value> =
+                    batch.into_iter().filter_map(|request| self.call(request)).collect();
+                if responses.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(responses))
+                }
+            }
+            request => self.call(request),
+        }
+    }
+
+    /// Run a single request, and build its response unless it is a notification.
+    fn call(&self, request: Value) -> Option<Value> {
+        let mut request = match request {
+            Value::Object(request) => request,
+            _ => {
+                let error = RpcError::new(INVALID_REQUEST, "request must be an object");
+                return Some(error_response(Value::Null, error));
+            }
+        };
+        let id = request.remove("id");
+        let valid_id = matches!(
+            id,
+            None | Some(Value::Null) | Some(Value::Number(_)) | Some(Value::String(_))
+        );
+        if !valid_id {
+            let error = RpcError::new(INVALID_REQUEST, "id must be a number, a string or null");
+            return Some(error_response(Value::Null, error));
+        }
+        let result =
+            parse_request(request).and_then(|(method, params)| self.dispatch(&method, params));
+        // notifications get no response, not even an error
+        let id = id?;
+        Some(match result {
+            Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+            Err(error) => error_response(id, error),
+        })
+    }
+
+    fn dispatch(&self, method: &str, params: Value) -> Result<Value, RpcError> {
+        if self.role < auth::rpc_required_role(method) {
+            return Err(RpcError::new(FORBIDDEN, "admin token required"));
+        }
+        match method {
+            "getblockcount" => {
+                let blockchain = self.blockchain.read().unwrap();
+                to_result(blockchain.height(&blockchain.tip()).unwrap())
+            }
+            "getbestblockhash" => to_result(self.blockchain.read().unwrap().tip().to_string()),
+            "getblock" => {
+                let hash = hash_param(&params, 0, "blockhash")?;
+                let verbose = param(&params, 1, "verbose")?.unwrap_or(true);
+                let blockchain = self.blockchain.read().unwrap();
+                if verbose {
+                    match explorer::block_view(&blockchain, &hash) {
+                        Some(view) => to_result(view),
+                        None => Err(RpcError::new(NOT_FOUND, "block not found")),
+                    }
+                } else {
+                    match blockchain.get_block(&hash) {
+                        Some(block) => to_result(hex::encode(bincode::serialize(block).unwrap())),
+                        None => Err(RpcError::new(NOT_FOUND, "block not found")),
+                    }
+                }
+            }
+            "getrawtransaction" => {
+                let hash = hash_param(&params, 0, "txid")?;
+                let verbose = param(&params, 1, "verbose")?.unwrap_or(false);
+                let blockchain = self.blockchain.read().unwrap();
+                let tx_mempool = self.tx_mempool.read().unwrap();
+                if verbose {
+                    match explorer::tx_lookup(&blockchain, &tx_mempool, &hash) {
+                        Some(lookup) => to_result(lookup),
+                        None => Err(RpcError::new(NOT_FOUND, "transaction not found")),
+                    }
+                } else {
+                    match find_transaction(&blockchain, &tx_mempool, &hash) {
+                        Some(signed_tx) => {
+                            to_result(hex::encode(bincode::serialize(signed_tx).unwrap()))
+                        }
+                        None => Err(RpcError::new(NOT_FOUND, "transaction not found")),
+                    }
+                }
+            }
+            "sendrawtransaction" => {
+                let raw: String = required(param(&params, 0, "hexstring")?, "hexstring")?;
+                let signed_tx = super::decode_transaction(&raw)
+                    .map_err(|e| RpcError::new(DESERIALIZATION_ERROR, e))?;
+                let admitted = {
+                    let blockchain = self.blockchain.read().unwrap();
+                    let mut tx_mempool = self.tx_mempool.write().unwrap();
+                    tx_mempool.admit(signed_tx, blockchain.tip_state())
+                };
+                match admitted {
+                    Ok(hash) => {
+                        self.network.relay_transactions(vec![hash], None);
+                        to_result(hash.to_string())
+                    }
+                    Err(rejection) => Err(RpcError {
+                        code: VERIFY_REJECTED,
+                        message: rejection.to_string(),
+                        data: Some(Value::from(rejection.code())),
+                    }),
+                }
+            }
+            "getmempoolinfo" => {
+                let blockchain = self.blockchain.read().unwrap();
+                let tx_mempool = self.tx_mempool.read().unwrap();
+                to_result(super::mempool::info(&blockchain, &tx_mempool))
+            }
+            "getpeerinfo" => {
+                let peers: Vec<PeerView> = self
+                    .network
+                    .peers()
+                    .into_iter()
+                    .map(|peer| PeerView {
+                        addr: peer.addr.to_string(),
+                        inbound: peer.direction == Direction::Incoming,
+                        conntime: super::peers::unix_millis(peer.stats.connected_since) / 1000,
+                        pingtime: peer.stats.latency.map(|latency| latency.as_secs_f64()),
+                        bytessent: peer.stats.bytes_sent,
+                        bytesrecv: peer.stats.bytes_received,
+                    })
+                    .collect();
+                to_result(peers)
+            }
+            "getbalance" => {
+                let wallet = self.wallet.read().unwrap();
+                let tx_mempool = self.tx_mempool.read().unwrap();
+                to_result(wallet.balance(&tx_mempool).spendable)
+            }
+            "getnewaddress" => match self.wallet.write().unwrap().new_address() {
+                Ok(address) => to_result(address.to_string()),
+                Err(e) => Err(RpcError::new(WALLET_ERROR, e)),
+            },
+            "sendtoaddress" => {
+                let to: String = required(param(&params, 0, "address")?, "address")?;
+                let payment = Payment {
+                    to: to.parse().map_err(|e| {
+                        RpcError::new(INVALID_PARAMS, format!("error parsing address: {}", e))
+                    })?,
+                    amount: required(param(&params, 1, "amount")?, "amount")?,
+                    fee: param(&params, 2, "fee")?.unwrap_or(0),
+                    strategy: CoinSelection::BranchAndBound,
+                    max_excess: 0,
+                };
+                let sent = {
+                    let blockchain = self.blockchain.read().unwrap();
+                    let wallet = self.wallet.read().unwrap();
+                    let mut tx_mempool = self.tx_mempool.write().unwrap();
+                    wallet.send(&blockchain, &mut tx_mempool, &payment)
+                };
+                match sent {
+                    Ok(hash) => {
+                        self.network.relay_transactions(vec![hash], None);
+                        to_result(hash.to_string())
+                    }
+                    Err(SendError::InsufficientFunds) => Err(RpcError::new(
+                        WALLET_INSUFFICIENT_FUNDS,
+                        SendError::InsufficientFunds,
+                    )),
+                    Err(SendError::Rejected(rejection)) => Err(RpcError {
+                        code: VERIFY_REJECTED,
+                        message: rejection.to_string(),
+                        data: Some(Value::from(rejection.code())),
+                    }),
+                    Err(e) => Err(RpcError::new(WALLET_ERROR, e)),
+                }
+            }
+            _ => Err(RpcError::new(METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        }
+    }
+}
+
+/// Check the members of a request object other than its id, returning its method and params.
+fn parse_request(mut request: serde_json::Map<String, Value>) -> Result<(String, Value), RpcError> {
+    if request.get("jsonrpc") != Some(&Value::from("2.0")) {
+        return Err(RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\""));
+    }
+    let method = match request.remove("method") {
+        Some(Value::String(method)) => method,
+        _ => return Err(RpcError::new(INVALID_REQUEST, "method must be a string")),
+    };
+    let params = match request.remove("params") {
+        None => Value::Array(vec![]),
+        Some(params @ Value::Array(_)) | Some(params @ Value::Object(_)) => params,
+        Some(_) => {
+            return Err(RpcError::new(INVALID_REQUEST, "params must be an array or an object"))
+        }
+    };
+    Ok((method, params))
+}
+
+fn error_response(id: Value, error: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": error, "id": id })
+}
+
+fn to_result<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e))
+}
+
+/// Read the parameter at `index` if params are positional, or named `name` otherwise. Missing
+/// and null parameters are none.
+fn param<T: DeserializeOwned>(
+    params: &Value,
+    index: usize,
+    name: &str,
+) -> Result<Option<T>, RpcError> {
+    let value = match params {
+        Value::Array(params) => params.get(index),
+        Value::Object(params) => params.get(name),
+        _ => None,
+    };
+    match value {
+        None | Some(Value::Null) => Ok(None),
+        Some(value) => serde_json::from_value(value.clone())
+            .map(Some)
+            .map_err(|e| RpcError::new(INVALID_PARAMS, format!("error parsing {}: {}", name, e))),
+    }
+}
+
+fn required<T>(value: Option<T>, name: &str) -> Result<T, RpcError> {
+    value.ok_or_else(|| RpcError::new(INVALID_PARAMS, format!("missing {}", name)))
+}
+
+fn hash_param(params: &Value, index: usize, name: &str) -> Result<H256, RpcError> {
+    let hash: String = required(param(params, index, name)?, name)?;
+    hash.parse()
+        .map_err(|e| RpcError::new(INVALID_PARAMS, format!("error parsing {}: {}", name, e)))
+}
+
+/// Find a transaction by the hash of the signed transaction, in connected blocks or the mempool.
+fn find_transaction<'a>(
+    blockchain: &'a Blockchain,
+    mempool: &'a TransactionMempool,
+    hash: &H256,
+) -> Option<&'a SignTransaction> {
+    match blockchain.blocks_containing(hash).first() {
+        Some(block) => blockchain.chain[block]
+            .Content
+            .content
+            .iter()
+            .find(|signed_tx| signed_tx.hash() == *hash),
+        None => mempool.tx_map.get(hash),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::server;
+
+    #[test]
+    fn requests_batches_and_errors() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let tx_mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let wallet = Arc::new(RwLock::new(Wallet::new()));
+        let (msg_sender, _msg_receiver) = crossbeam::channel::unbounded();
+        // the server is never started, so it has no peers
+        let (ctx, network) = server::new("127.0.0.1:0".parse().unwrap(), msg_sender).unwrap();
+        drop(ctx);
+        let node = Node {
+            blockchain: &blockchain,
+            tx_mempool: &tx_mempool,
+            network: &network,
+            wallet: &wallet,
+            role: Role::Admin,
+        };
+        let genesis = blockchain.read().unwrap().tip();
+
+        let response = node
+            .handle(r#"{"jsonrpc": "2.0", "method": "getblockcount", "id": 1}"#)
+            .unwrap();
+        assert_eq!(response, json!({ "jsonrpc": "2.0", "result": 0, "id": 1 }));
+
+        let batch = format!(
+            r#"[
+                {{"jsonrpc": "2.0", "method": "getbestblockhash", "id": "a"}},
+                {{"jsonrpc": "2.0", "method": "getblock", "params": {{"blockhash": "{}"}}, "id": 2}},
+                {{"jsonrpc": "2.0", "method": "getblock", "params": ["00"], "id": 3}},
+                {{"jsonrpc": "2.0", "method": "nosuchmethod", "id": 4}},
+                {{"jsonrpc": "2.0", "method": "getmempoolinfo"}},
+                {{"jsonrpc": "1.0", "method": "getpeerinfo", "id": 5}},
+                7
+            ]"#,
+            genesis
+        );
+        let responses = node.handle(&batch).unwrap();
+        let responses = responses.as_array().unwrap();
+        assert_eq!(responses.len(), 6);
+        assert_eq!(responses[0]["result"], json!(genesis.to_string()));
+        assert_eq!(responses[1]["result"]["height"], json!(0));
+        assert_eq!(responses[2]["error"]["code"], json!(INVALID_PARAMS));
+        assert_eq!(responses[3]["error"]["code"], json!(METHOD_NOT_FOUND));
+        assert_eq!(responses[4]["error"]["code"], json!(INVALID_REQUEST));
+        assert_eq!(responses[5]["id"], Value::Null);
+
+        let genesis_tx = blockchain.read().unwrap().chain[&genesis].Content.content[0].clone();
+        let raw = hex::encode(bincode::serialize(&genesis_tx).unwrap());
+        let request = format!(
+            r#"{{"jsonrpc": "2.0", "method": "getrawtransaction", "params": ["{}"], "id": 1}}"#,
+            genesis_tx.hash()
+        );
+        assert_eq!(node.handle(&request).unwrap()["result"], json!(raw));
+        let request = format!(
+            r#"{{"jsonrpc": "2.0", "method": "sendrawtransaction", "params": ["{}"], "id": 1}}"#,
+            raw
+        );
+        let error = &node.handle(&request).unwrap()["error"];
+        assert_eq!((&error["code"], &error["data"]), (&json!(VERIFY_REJECTED), &json!("coinbase")));
+
+        assert_eq!(node.handle("{").unwrap()["error"]["code"], json!(PARSE_ERROR));
+        assert_eq!(node.handle("[]").unwrap()["error"]["code"], json!(INVALID_REQUEST));
+        assert!(node.handle(r#"[{"jsonrpc": "2.0", "method": "getblockcount"}]"#).is_none());
+        let peers = node
+            .handle(r#"{"jsonrpc": "2.0", "method": "getpeerinfo", "id": null}"#)
+            .unwrap();
+        assert_eq!(peers["result"], json!([]));
+
+        let address = node
+            .handle(r#"{"jsonrpc": "2.0", "method": "getnewaddress", "id": 6}"#)
+            .unwrap();
+        let send = format!(
+            r#"{{"jsonrpc": "2.0", "method": "sendtoaddress", "params": [{}, 5], "id": 7}}"#,
+            address["result"]
+        );
+        let error = &node.handle(&send).unwrap()["error"];
+        assert_eq!(error["code"], json!(WALLET_INSUFFICIENT_FUNDS));
+        let balance = node.handle(r#"{"jsonrpc": "2.0", "method": "getbalance", "id": 8}"#).unwrap();
+        assert_eq!(balance["result"], json!(0));
+    }
+}
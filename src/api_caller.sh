@@ -11,3 +11,91 @@ curl http://127.0.0.1:7000/miner/start?lambda=1000000 & \
 curl http://127.0.0.1:7001/miner/start?lambda=1000001 & \
 curl http://127.0.0.1:7002/miner/start?lambda=1000002
 
+# command to add 200ms of delay (with up to 50ms of jitter) to every link of p1
+# curl "http://127.0.0.1:7000/network/link?delay=200&jitter=50"
+
+# command to check whether a transaction is final under K = 6 confirmations
+# curl "http://127.0.0.1:7000/tx/status?hash=<HASH>&k=6"
+
+# command to start a node on a chain described by a spec file (see src/chain_spec.rs)
+# cargo run --release -- -vvv --chain-spec chain.json --p2p 127.0.0.1:6000 --api 127.0.0.1:7000
+
+# commands to mine 10 blocks right away on a node started with --regtest, paying an address,
+# then to force a reorg by invalidating one of them and to undo it
+# curl "http://127.0.0.1:7000/miner/generate?n=10&address=3fd7186e306a90f63f77d4d94d9be83a762d4762"
+# curl "http://127.0.0.1:7000/chain/invalidate?hash=<HASH>"
+# curl "http://127.0.0.1:7000/chain/reconsider?hash=<HASH>"
+
+# commands to explore the blockchain: tip, block by hash or height, longest chain by pages,
+# transaction by hash, and number of forks and orphans
+# curl http://127.0.0.1:7000/chain/tip
+# curl "http://127.0.0.1:7000/chain/block?height=1"
+# curl "http://127.0.0.1:7000/chain/blocks?start=0&limit=20"
+# curl "http://127.0.0.1:7000/tx?hash=<HASH>"
+# curl http://127.0.0.1:7000/chain/forks
+
+# command to submit a transaction, as JSON or as the hex of its bincode encoding
+# curl -X POST --data-binary @tx.json http://127.0.0.1:7000/tx
+
+# commands to get the balance, unspent outputs and transactions of an address, as of the tip
+# or also counting the transactions of the mempool
+# curl "http://127.0.0.1:7000/address/balance?address=3fd7186e306a90f63f77d4d94d9be83a762d4762"
+# curl "http://127.0.0.1:7000/address/utxos?address=3fd7186e306a90f63f77d4d94d9be83a762d4762&mempool=true"
+# curl "http://127.0.0.1:7000/address/history?address=3fd7186e306a90f63f77d4d94d9be83a762d4762&mempool=true"
+
+# commands to control the miner of p1: pause and resume it, stop it until the next start,
+# pay its rewards to an address (or to nobody without an address), use 4 threads, and
+# report its hash rate, blocks found, stale rate and current template
+# curl http://127.0.0.1:7000/miner/pause
+# curl http://127.0.0.1:7000/miner/resume
+# curl http://127.0.0.1:7000/miner/stop
+# curl "http://127.0.0.1:7000/miner/payout?address=3fd7186e306a90f63f77d4d94d9be83a762d4762"
+# curl "http://127.0.0.1:7000/miner/threads?n=4"
+# curl http://127.0.0.1:7000/miner/status
+
+# JSON-RPC 2.0 calls, alone or in a batch: getblockcount, getbestblockhash, getblock,
+# getrawtransaction, sendrawtransaction, getmempoolinfo and getpeerinfo
+# curl -X POST --data '{"jsonrpc": "2.0", "method": "getblockcount", "id": 1}' http://127.0.0.1:7000/rpc
+# curl -X POST --data '[{"jsonrpc": "2.0", "method": "getbestblockhash", "id": 1}, {"jsonrpc": "2.0", "method": "getmempoolinfo", "id": 2}]' http://127.0.0.1:7000/rpc
+# curl -X POST --data '{"jsonrpc": "2.0", "method": "getblock", "params": ["<HASH>", false], "id": 1}' http://127.0.0.1:7000/rpc
+
+# command to follow tip changes, reorgs, new mempool transactions and peer connections as
+# server-sent events
+# curl -N http://127.0.0.1:7000/events
+
+# command to scrape the counters and histograms of the node in the Prometheus text format
+# curl http://127.0.0.1:7000/metrics
+
+# commands to call an API started with --api-token, --api-read-token or --api-cookie; miner,
+# network and block validity control require the admin token
+# cargo run --release -- --api-cookie .cookie --api-read-token reader --api-allow 10.0.0.0/8
+# curl -H "Authorization: Bearer $(cat .cookie)" http://127.0.0.1:7000/miner/status
+# curl -H "Authorization: Bearer reader" http://127.0.0.1:7000/chain/tip
+
+# commands to manage the peers of p1: list them with their latency and traffic, connect to or
+# disconnect from a peer, and ban, unban and list banned addresses
+# curl http://127.0.0.1:7000/network/peers
+# curl "http://127.0.0.1:7000/network/connect?addr=127.0.0.1:6002"
+# curl "http://127.0.0.1:7000/network/disconnect?addr=127.0.0.1:6002"
+# curl "http://127.0.0.1:7000/network/ban?ip=127.0.0.2"
+# curl "http://127.0.0.1:7000/network/unban?ip=127.0.0.2"
+# curl http://127.0.0.1:7000/network/banned
+
+# commands to inspect the mempool: size, bytes and fee histogram, pending transactions with
+# their fee rate and age, the pending ancestors and descendants of a transaction, and the
+# transactions of the next block
+# curl http://127.0.0.1:7000/mempool/info
+# curl http://127.0.0.1:7000/mempool/transactions
+# curl "http://127.0.0.1:7000/mempool/entry?hash=<HASH>"
+# curl http://127.0.0.1:7000/mempool/template
+
+# commands to use the wallet of a node started with --data-dir: list its addresses, add one,
+# read its balance, and pay 10 coins with a fee of 1, selecting coins by branch and bound or
+# largest first; the JSON-RPC methods are getbalance, getnewaddress and sendtoaddress
+# cargo run --release -- --data-dir data/p1
+# curl http://127.0.0.1:7000/wallet/addresses
+# curl http://127.0.0.1:7000/wallet/new-address
+# curl http://127.0.0.1:7000/wallet/balance
+# curl "http://127.0.0.1:7000/wallet/send?to=<ADDRESS>&amount=10&fee=1"
+# curl "http://127.0.0.1:7000/wallet/send?to=<ADDRESS>&amount=10&fee=1&selection=largest-first"
+# curl -d '{"jsonrpc": "2.0", "method": "getbalance", "id": 1}' http://127.0.0.1:7000/rpc
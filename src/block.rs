@@ -3,7 +3,8 @@
 use serde::{Serialize, Deserialize};
 use crate::crypto::hash::{H256, Hashable};
 use crate::crypto::merkle::{MerkleTree};
-use crate::transaction::{self,Transaction, SignTransaction, generate_random_signed_transaction};
+use crate::transaction::{self,Transaction, SignTransaction, UtxoOutput};
+use crate::chain_spec::ChainSpec;
 
 
 extern crate chrono;
@@ -11,7 +12,6 @@ use chrono::prelude::*;
 
 use rand::{thread_rng, Rng};
 use rand::distributions::Alphanumeric;
-use ring::{digest};
 
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
@@ -68,8 +68,9 @@ pub fn generate_random_block_(parent: &H256) -> Block {
     bytes32[0]=1;
     bytes32[1]=1;
     let difficulty : H256 = bytes32.into();
-    let mut transaction = Vec::<SignTransaction>::new();
-    transaction.push(generate_random_signed_transaction());
+    // random transactions would spend outputs that do not exist, so the block stays empty to
+    // pass the ledger checks
+    let transaction = Vec::<SignTransaction>::new();
     let mut MerkleTree = MerkleTree::new(&transaction);
 
 
@@ -94,21 +95,18 @@ pub fn generate_random_block_(parent: &H256) -> Block {
     return newBlock;
 }
 
-pub fn generate_genesis_block(parent: &H256) -> Block {
-    //let b:H256 = hex!("00011718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920").into();
-    let random = digest::digest(&ring::digest::SHA256,"00011718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920".as_bytes());
-    let b = <H256>::from(random);
-    let r1:u32 = 0;
-    let r2:u128 = 0;
-    //let local: DateTime<Local> = Local::now();
-    let h:Header = Header{parent:*parent,nonce:r1,difficulty:b,timestamp:r2,merkleRoot:b};
-    let t = transaction::generate_genesis_signed_transaction();
-    //transaction::pr();
-    let mut vect:Vec<SignTransaction> = vec![];
-    vect.push(t);
+/// Build the genesis block of a chain. Its only transaction creates the allocations, and its
+/// input is the hash of the whole specification, so that the genesis hash commits to it.
+pub fn generate_genesis_block(spec: &ChainSpec) -> Block {
+    let outputs = spec.allocations.iter()
+        .map(|allocation| UtxoOutput{recipient_address: allocation.address, value: allocation.value})
+        .collect();
+    let t = transaction::coinbase(spec.hash(), outputs);
+    let vect:Vec<SignTransaction> = vec![t];
+    let h:Header = Header{parent:H256::default(),nonce:0,difficulty:spec.initial_target,
+                          timestamp:spec.genesis_timestamp as u128,merkleRoot:MerkleTree::new(&vect).root()};
     let c:Content = Content{content:vect};
-    let b:Block = Block{Header:h,Content:c};
-    b
+    Block{Header:h,Content:c}
 }
 
 
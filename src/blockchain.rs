@@ -1,106 +1,508 @@
 use crate::block::{self, *};
+use crate::chain_spec::ChainSpec;
+use crate::crypto::address::H160;
 use crate::crypto::hash::{H256,Hashable};
-use log::info;
-use std::collections::HashMap;
+use crate::ledger::{self, BlockState, State};
+use crate::metrics;
+use crate::orphan_pool::OrphanPool;
+use crate::txs_check;
+use crossbeam::channel;
+use log::{debug, info};
+use std::collections::{HashMap, HashSet};
 use std::collections::VecDeque;
+use std::net::SocketAddr;
 use std::time;
 extern crate chrono;
 use chrono::prelude::*;
 
+/// Number of confirmations after which a block or transaction is considered final by default
+pub const DEFAULT_CONFIRMATION_DEPTH: u64 = 6;
+
+/// Where a block stands relative to the longest chain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockStatus {
+    /// Connected, and an ancestor of the tip (or the tip itself)
+    MainChain,
+    /// Connected, but on a fork that is not the longest chain
+    SideChain,
+    /// Received, but its parent is not connected yet
+    Orphan,
+    Unknown,
+}
+
+/// What inserting a block did to the blockchain.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InsertOutcome {
+    AlreadyKnown,
+    /// The longest chain grew; the block and any orphans it unlocked were connected in order
+    ExtendedTip { connected: Vec<H256> },
+    /// The block was connected on a fork that is not longer than the longest chain
+    SideChain,
+    /// A fork became the longest chain. `disconnected` goes from the old tip down to the fork
+    /// point, `connected` from the fork point up to the new tip.
+    Reorg { disconnected: Vec<H256>, connected: Vec<H256> },
+    /// The parent is unknown; the block is kept in the orphan pool unless its limits are reached
+    Orphan,
+    /// The block fails the proof of work or transaction checks
+    Invalid,
+}
+
+/// A block inserted into the blockchain, sent to subscribers.
+#[derive(Debug, Clone)]
+pub struct ChainEvent {
+    pub block: H256,
+    pub outcome: InsertOutcome,
+}
+
 pub struct Blockchain {
     pub chain:HashMap<H256,Block>,
     pub tiphash:H256,
-    pub heights:HashMap<H256,u8>,
-    pub buffer:HashMap<H256,Block>,
-    pub totaldelay:u128,
+    pub heights:HashMap<H256,u64>,
+    /// Hashes of the longest chain, indexed by height
+    main_chain:Vec<H256>,
+    /// Skip pointer of each connected block, to an ancestor at `skip_height` of its height
+    skip:HashMap<H256,H256>,
+    /// Blocks waiting for their parent
+    pub orphans:OrphanPool,
+    /// Ledger state after each block of the chain
+    pub block_state:BlockState,
+    subscribers:Vec<channel::Sender<ChainEvent>>,
+    /// Blocks containing each transaction, by hash of the signed transaction
+    tx_blocks:HashMap<H256,Vec<H256>>,
+    /// Transactions of connected blocks involving each address, in the order they were connected
+    address_txs:HashMap<H160,Vec<H256>>,
+    /// Number of confirmations (K) after which a block or transaction is final
+    pub confirmation_depth:u64,
+    /// If set, blocks forking off the longest chain deeper than this are rejected, so that
+    /// reorganizations never disconnect more blocks
+    pub max_reorg_depth:Option<u64>,
+    /// Parameters of the chain, from which the genesis block is built
+    pub spec:ChainSpec,
+    /// Blocks invalidated by hand and their descendants, which never become the tip
+    invalidated:HashSet<H256>,
 }
 
 impl Blockchain {
-    /// Create a new blockchain, only containing the genesis block
+    /// Create a new blockchain of the default chain, only containing the genesis block
     pub fn new() -> Self {
-        let buffer: [u8; 32] = [0; 32];
-        let b:H256 = buffer.into();
-        let genesis:Block = block::generate_genesis_block(&b);
+        Self::from_spec(ChainSpec::default())
+    }
+
+    /// Create a new blockchain of the given chain, only containing its genesis block
+    pub fn from_spec(spec: ChainSpec) -> Self {
+        let genesis:Block = block::generate_genesis_block(&spec);
+        let initial_state = ledger::genesis_state(&genesis);
         let genhash:H256 = genesis.hash();
         let mut chainmap:HashMap<H256,Block> = HashMap::new();
-        let mut heightsmap:HashMap<H256,u8> = HashMap::new();
-        let buffermap:HashMap<H256,Block> = HashMap::new();
+        let mut heightsmap:HashMap<H256,u64> = HashMap::new();
+        let mut statemap:HashMap<H256,State> = HashMap::new();
         chainmap.insert(genhash,genesis);
         heightsmap.insert(genhash,0);
+        statemap.insert(genhash,initial_state);
         let t:H256 = genhash;
-        let newchain:Blockchain = Blockchain{chain:chainmap,tiphash:t,heights:heightsmap,buffer:buffermap,totaldelay:0};
+        let mut newchain:Blockchain = Blockchain{chain:chainmap,tiphash:t,heights:heightsmap,main_chain:vec![genhash],
+                                             skip:vec![(genhash,genhash)].into_iter().collect(),
+                                             orphans:OrphanPool::new(),
+                                             block_state:BlockState{block_state_map:statemap},
+                                             subscribers:vec![],tx_blocks:HashMap::new(),address_txs:HashMap::new(),
+                                             confirmation_depth:DEFAULT_CONFIRMATION_DEPTH,
+                                             max_reorg_depth:None,spec,
+                                             invalidated:HashSet::new()};
+        newchain.index_transactions(&genhash);
         newchain
     }
 
     /// Insert a block into blockchain
-    pub fn insert(&mut self, block: &Block) {
-
+    pub fn insert(&mut self, block: &Block) -> InsertOutcome {
+        self.insert_from(block, None).0
+    }
 
+    /// Insert a block received from a peer, which is accounted for if the block is an orphan.
+    /// Also returns the orphans connected because the block was their missing ancestor, each
+    /// after its parent.
+    pub fn insert_from(&mut self, block: &Block, source: Option<SocketAddr>) -> (InsertOutcome, Vec<H256>) {
         let h:H256 = block.hash();
-        //let mut flag:bool = false;
-
-
-        match self.chain.get(&block.Header.parent){
-            Some(pblock) => { //insertion into mainchain
-
-                if h < pblock.Header.difficulty && !self.chain.contains_key(&h) {
-                let b_delay = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis() - block.Header.timestamp;
-                self.totaldelay = self.totaldelay + b_delay;
-                info!("Adding block with hash {} to chain",h);
-                println!("Block delay is: {:?}",(time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis() - block.Header.timestamp));
-                println!("Average delay is {}",self.totaldelay/(self.chain.len() as u128));
-                println!("Total number of blocks in blockchain:{}\n",self.chain.len());
-                self.chain.insert(h,block.clone());
-                let len = self.heights[&block.Header.parent]+1;
-                self.heights.insert(h,len);
-                if len>self.heights[&self.tiphash] {
-                    self.tiphash = h;
-                }
+        if self.contains(&h) {
+            return (InsertOutcome::AlreadyKnown, vec![]);
+        }
+        let mut connected_orphans = vec![];
+        let now = time::Instant::now();
+        self.orphans.expire(now);
 
-                //let mut bhash_copy:H256 = hash::generate_random_hash();
-                //if stale blocks parent has arrived, insert it into main chain
-                let mut bhash_vec = Vec::new();
+        let outcome = if self.chain.contains_key(&block.Header.parent) { //insertion into chain
+            let old_tip = self.tiphash;
+            if !self.connect(block) {
+                InsertOutcome::Invalid
+            } else {
+                //if orphans' parent has arrived, connect them and their own descendants
                 let mut phash_q: VecDeque<H256>= VecDeque::new();
                 phash_q.push_back(h);
-                while !phash_q.is_empty() {
-                    match phash_q.pop_front() {
-                        Some(h) => for (bhash,blck) in self.buffer.iter(){
-                                if blck.Header.parent == h {
-                                    //flag = true;
-                                    let bhash_copy:H256 = *bhash;
-                                    bhash_vec.push(bhash_copy);
-                                    self.chain.insert(bhash_copy,blck.clone());
-                                    let b_delay = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis() - block.Header.timestamp;
-                                    self.totaldelay = self.totaldelay + b_delay;
-                                    info!("Adding block with hash {} to chain",blck.hash());
-                                    println!("Block delay is: {:?}",(time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis() - blck.Header.timestamp));
-                                    println!("Average delay is {}",self.totaldelay/(self.chain.len() as u128));
-                                    println!("Total number of blocks in blockchain:{}\n",self.chain.len());
-                                    let len = self.heights[&blck.Header.parent]+1;
-                                    self.heights.insert(bhash_copy,len);
-                                    if len>self.heights[&self.tiphash] {
-                                        self.tiphash = bhash_copy;
-                                    }
-                                }
-                            },
-                        None => (),
+                while let Some(phash) = phash_q.pop_front() {
+                    for blck in self.orphans.take_children(&phash) {
+                        if self.connect(&blck) {
+                            phash_q.push_back(blck.hash());
+                            connected_orphans.push(blck.hash());
+                        }
                     }
                 }
+                self.tip_change(old_tip)
+            }
+        } else if h >= block.Header.difficulty || block.Header.difficulty > self.spec.initial_target {
+            // the parent is unknown, so only the header's own proof of work can be checked, with
+            // a target no easier than the chain's
+            debug!("Orphan block with hash {} does not meet the difficulty", h);
+            InsertOutcome::Invalid
+        } else {
+            if self.orphans.insert(block.clone(), source, now) {
+                debug!("Adding block with hash {} to orphan pool",h);
+            }
+            InsertOutcome::Orphan
+        };
+        if source.is_some() {
+            metrics::global().block_received();
+        }
+        match &outcome {
+            InsertOutcome::Orphan => metrics::global().block_orphaned(),
+            InsertOutcome::Invalid => metrics::global().block_invalid(),
+            InsertOutcome::Reorg { disconnected, .. } => metrics::global().reorg(disconnected.len()),
+            _ => {}
+        }
+        self.notify(h, &outcome);
+        (outcome, connected_orphans)
+    }
 
+    /// How the longest chain changed since `old_tip` was the tip
+    fn tip_change(&self, old_tip: H256) -> InsertOutcome {
+        if self.tiphash == old_tip {
+            return InsertOutcome::SideChain;
+        }
+        let (disconnected, connected) = self.fork_path(old_tip, self.tiphash);
+        if disconnected.is_empty() {
+            InsertOutcome::ExtendedTip { connected }
+        } else {
+            info!("Chain reorganization: {} blocks disconnected, {} connected",
+                  disconnected.len(), connected.len());
+            InsertOutcome::Reorg { disconnected, connected }
+        }
+    }
 
-                for bh in bhash_vec{
-                    self.buffer.remove(&bh);
-                }
-             }
-            }, // insert stale block into buffer
-            _ => {
-                  print!("Adding block with hash {} to buffer\n",h); 
-                  if !self.buffer.contains_key(&h){
-                  self.buffer.insert(h,block.clone()); 
-                  }
-                 },
+    fn notify(&mut self, block: H256, outcome: &InsertOutcome) {
+        let event = ChainEvent { block, outcome: outcome.clone() };
+        self.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
+    /// Treat a block and its descendants as invalid, and move the tip to the longest chain
+    /// without them. Returns how the longest chain changed, `SideChain` if it did not.
+    pub fn invalidate_block(&mut self, hash: &H256) -> Result<InsertOutcome, String> {
+        match self.height(hash) {
+            None => return Err(format!("unknown block {}", hash)),
+            Some(0) => return Err("cannot invalidate the genesis block".to_string()),
+            Some(_) => {}
+        }
+        info!("Invalidating block with hash {} and its descendants", hash);
+        let old_tip = self.tiphash;
+        self.invalidated.insert(*hash);
+        self.invalidated.extend(self.descendants(hash));
+        self.select_best_tip();
+        let outcome = self.tip_change(old_tip);
+        self.notify(*hash, &outcome);
+        Ok(outcome)
+    }
+
+    /// Undo `invalidate_block` on a block, its ancestors and its descendants, and move the tip
+    /// to the longest chain again. Returns how the longest chain changed, `SideChain` if it did
+    /// not.
+    pub fn reconsider_block(&mut self, hash: &H256) -> Result<InsertOutcome, String> {
+        if !self.chain.contains_key(hash) {
+            return Err(format!("unknown block {}", hash));
         }
+        info!("Reconsidering block with hash {}", hash);
+        let old_tip = self.tiphash;
+        for descendant in self.descendants(hash) {
+            self.invalidated.remove(&descendant);
+        }
+        let mut ancestor = *hash;
+        while self.invalidated.remove(&ancestor) {
+            ancestor = self.chain[&ancestor].Header.parent;
+        }
+        self.select_best_tip();
+        let outcome = self.tip_change(old_tip);
+        self.notify(*hash, &outcome);
+        Ok(outcome)
+    }
 
+    /// The connected blocks that have the given block as ancestor
+    fn descendants(&self, hash: &H256) -> Vec<H256> {
+        let mut candidates: Vec<(u64, H256)> = self.heights.iter()
+            .filter(|(_, &height)| height > self.heights[hash])
+            .map(|(&block, &height)| (height, block))
+            .collect();
+        candidates.sort();
+        let mut descendants: HashSet<H256> = HashSet::new();
+        for (_, block) in candidates {
+            let parent = self.chain[&block].Header.parent;
+            if parent == *hash || descendants.contains(&parent) {
+                descendants.insert(block);
+            }
+        }
+        descendants.into_iter().collect()
+    }
+
+    /// Make the highest block that is not invalidated the tip, keeping the current tip on ties
+    fn select_best_tip(&mut self) {
+        let current = Some(self.tiphash).filter(|tip| !self.invalidated.contains(tip));
+        let best = self.heights.iter()
+            .filter(|(block, _)| !self.invalidated.contains(block))
+            .max_by_key(|(&block, &height)| (height, Some(block) == current, block))
+            .map(|(&block, _)| block)
+            .unwrap();
+        self.set_tip(best);
+    }
+
+    /// Subscribe to the outcome of every block inserted from now on, except already known ones,
+    /// and of every block invalidated or reconsidered
+    pub fn subscribe(&mut self) -> channel::Receiver<ChainEvent> {
+        let (sender, receiver) = channel::unbounded();
+        self.subscribers.push(sender);
+        receiver
+    }
+
+    /// The blocks to disconnect, from `from` down to the common ancestor, and the blocks to
+    /// connect, from the common ancestor up to `to`
+    fn fork_path(&self, from: H256, to: H256) -> (Vec<H256>, Vec<H256>) {
+        let (mut a, mut b) = (from, to);
+        let mut disconnected = vec![];
+        let mut connected = vec![];
+        while self.heights[&a] > self.heights[&b] {
+            disconnected.push(a);
+            a = self.chain[&a].Header.parent;
+        }
+        while self.heights[&b] > self.heights[&a] {
+            connected.push(b);
+            b = self.chain[&b].Header.parent;
+        }
+        while a != b {
+            disconnected.push(a);
+            a = self.chain[&a].Header.parent;
+            connected.push(b);
+            b = self.chain[&b].Header.parent;
+        }
+        connected.reverse();
+        (disconnected, connected)
+    }
+
+    /// Attach a block whose parent is in the chain, after checking its proof of work and its
+    /// transactions against the ledger state of the parent. Returns whether it was attached.
+    fn connect(&mut self, block: &Block) -> bool {
+        let h:H256 = block.hash();
+        let parent = &self.chain[&block.Header.parent];
+        if h >= parent.Header.difficulty {
+            debug!("Block with hash {} does not meet the difficulty", h);
+            return false;
+        }
+        if block.Header.difficulty != parent.Header.difficulty {
+            info!("Rejecting block with hash {}: its target differs from its parent's", h);
+            return false;
+        }
+        if let Some(max_depth) = self.max_reorg_depth {
+            let fork = self.common_ancestor(&block.Header.parent, &self.tiphash).unwrap();
+            let depth = self.heights[&self.tiphash] - self.heights[&fork];
+            if depth > max_depth {
+                info!("Rejecting block with hash {}: forks {} blocks deep, beyond the checkpoint", h, depth);
+                return false;
+            }
+        }
+        if !self.within_block_limits(block) {
+            info!("Rejecting block with hash {}: exceeds the block size limits", h);
+            return false;
+        }
+        let parent_state = &self.block_state.block_state_map[&block.Header.parent];
+        let reward = self.spec.reward(self.heights[&block.Header.parent] + 1);
+        if !txs_check::is_blck_valid(block, parent_state, reward) {
+            info!("Rejecting block with hash {}: invalid transactions", h);
+            return false;
+        }
+
+        let now = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let b_delay = now.saturating_sub(block.Header.timestamp);
+        metrics::global().block_delay(b_delay);
+        info!("Adding block with hash {} to chain, {} ms after it was mined",h,b_delay);
+        self.chain.insert(h,block.clone());
+        ledger::update_block_state(block, &mut self.block_state);
+        self.index_transactions(&h);
+        let len = self.heights[&block.Header.parent]+1;
+        self.heights.insert(h,len);
+        let skip = self.ancestor(&block.Header.parent, skip_height(len)).unwrap();
+        self.skip.insert(h,skip);
+        if self.invalidated.contains(&block.Header.parent) {
+            self.invalidated.insert(h);
+        } else if len>self.heights[&self.tiphash] {
+            self.set_tip(h);
+        }
+        true
+    }
+
+    /// Record the transactions of a connected block in the transaction and address indexes
+    fn index_transactions(&mut self, hash: &H256) {
+        for signed_tx in &self.chain[hash].Content.content {
+            let tx_hash = signed_tx.hash();
+            let blocks = self.tx_blocks.entry(tx_hash).or_default();
+            blocks.push(*hash);
+            // a transaction included in several branches is indexed by address once
+            if blocks.len() > 1 {
+                continue;
+            }
+            for address in signed_tx.addresses() {
+                self.address_txs.entry(address).or_default().push(tx_hash);
+            }
+        }
+    }
+
+    /// Whether a block respects the limits on the number and size of transactions of the spec
+    fn within_block_limits(&self, block: &Block) -> bool {
+        let txs = &block.Content.content;
+        let size: u64 = txs.iter().map(|tx| bincode::serialized_size(tx).unwrap()).sum();
+        txs.len() <= self.spec.max_block_transactions && size <= self.spec.max_block_size
+    }
+
+    /// Make `tip` the tip of the longest chain and update the height index down to the fork
+    /// point with the previous longest chain
+    fn set_tip(&mut self, tip: H256) {
+        self.tiphash = tip;
+        let mut height = self.heights[&tip] as usize;
+        self.main_chain.resize(height + 1, H256::default());
+        let mut hash = tip;
+        while self.main_chain[height] != hash {
+            self.main_chain[height] = hash;
+            hash = self.chain[&hash].Header.parent;
+            height -= 1;
+        }
+    }
+
+    /// Get the height of a connected block, genesis being at height 0
+    pub fn height(&self, hash: &H256) -> Option<u64> {
+        self.heights.get(hash).copied()
+    }
+
+    /// Get the hash of the block of the longest chain at the given height
+    pub fn block_at_height(&self, height: u64) -> Option<H256> {
+        self.main_chain.get(height as usize).copied()
+    }
+
+    /// Whether a block is part of the longest chain
+    pub fn is_in_main_chain(&self, hash: &H256) -> bool {
+        self.height(hash).and_then(|height| self.block_at_height(height)) == Some(*hash)
+    }
+
+    /// Get the ancestor of a connected block at the given height, following skip pointers
+    pub fn ancestor(&self, hash: &H256, height: u64) -> Option<H256> {
+        let mut walk_height = self.height(hash)?;
+        if height > walk_height {
+            return None;
+        }
+        let mut walk = *hash;
+        while walk_height > height {
+            let skip = skip_height(walk_height);
+            let skip_prev = skip_height(walk_height - 1);
+            // take the skip pointer unless the parent's one gets closer without overshooting
+            if skip == height
+                || (skip > height && !(skip_prev + 2 < skip && skip_prev >= height)) {
+                walk = self.skip[&walk];
+                walk_height = skip;
+            } else {
+                walk = self.chain[&walk].Header.parent;
+                walk_height -= 1;
+            }
+        }
+        Some(walk)
+    }
+
+    /// Get the most recent block that is an ancestor of both connected blocks
+    pub fn common_ancestor(&self, a: &H256, b: &H256) -> Option<H256> {
+        let height = std::cmp::min(self.height(a)?, self.height(b)?);
+        let mut a = self.ancestor(a, height)?;
+        let mut b = self.ancestor(b, height)?;
+        while a != b {
+            a = self.chain[&a].Header.parent;
+            b = self.chain[&b].Header.parent;
+        }
+        Some(a)
+    }
+
+    /// Whether the block is known, either connected or waiting for its parent
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.chain.contains_key(hash) || self.orphans.contains(hash)
+    }
+
+    /// Get a known block, connected or not
+    pub fn get_block(&self, hash: &H256) -> Option<&Block> {
+        self.chain.get(hash).or_else(|| self.orphans.get(hash))
+    }
+
+    /// Get the header of a known block, connected or not
+    pub fn get_header(&self, hash: &H256) -> Option<&Header> {
+        self.get_block(hash).map(|block| &block.Header)
+    }
+
+    /// Get where a block stands relative to the longest chain
+    pub fn status(&self, hash: &H256) -> BlockStatus {
+        if self.is_in_main_chain(hash) {
+            BlockStatus::MainChain
+        } else if self.chain.contains_key(hash) {
+            BlockStatus::SideChain
+        } else if self.orphans.contains(hash) {
+            BlockStatus::Orphan
+        } else {
+            BlockStatus::Unknown
+        }
+    }
+
+    /// Number of blocks of the longest chain from the given block to the tip, both included.
+    /// Blocks that are not in the longest chain have no confirmations.
+    pub fn confirmations(&self, hash: &H256) -> u64 {
+        if !self.is_in_main_chain(hash) {
+            return 0;
+        }
+        self.heights[&self.tiphash] - self.heights[hash] + 1
+    }
+
+    /// Whether a block has at least `confirmation_depth` confirmations
+    pub fn is_final(&self, hash: &H256) -> bool {
+        self.confirmations(hash) >= self.confirmation_depth
+    }
+
+    /// Number of confirmations of the block of the longest chain that includes a transaction,
+    /// or `None` if no connected block includes it
+    pub fn tx_confirmations(&self, tx_hash: &H256) -> Option<u64> {
+        let blocks = self.tx_blocks.get(tx_hash)?;
+        blocks.iter().map(|hash| self.confirmations(hash)).max()
+    }
+
+    /// Whether a transaction is included in a block with at least `k` confirmations
+    pub fn is_tx_final(&self, tx_hash: &H256, k: u64) -> bool {
+        self.tx_confirmations(tx_hash).unwrap_or(0) >= k
+    }
+
+    /// Get the most recent block of the longest chain that is final
+    pub fn final_tip(&self) -> Option<H256> {
+        let tip_height = self.heights[&self.tiphash];
+        let depth = self.confirmation_depth.max(1) - 1;
+        tip_height.checked_sub(depth).and_then(|height| self.block_at_height(height))
+    }
+
+    /// Get the ledger state after the most recent final block
+    pub fn final_state(&self) -> Option<&State> {
+        self.final_tip().and_then(|hash| self.state(&hash))
+    }
+
+    /// Get the ledger state after the block with the given hash
+    pub fn state(&self, hash: &H256) -> Option<&State> {
+        self.block_state.block_state_map.get(hash)
+    }
+
+    /// Get the ledger state at the tip of the longest chain
+    pub fn tip_state(&self) -> &State {
+        &self.block_state.block_state_map[&self.tiphash]
     }
 
     /// Get the last block's hash of the longest chain
@@ -108,20 +510,41 @@ impl Blockchain {
         self.tiphash
     }
 
-    /// Get the last block's hash of the longest chain
-    #[cfg(any(test, test_utilities))]
+    /// Get the connected blocks that include a transaction, by hash of the signed transaction
+    pub fn blocks_containing(&self, tx_hash: &H256) -> &[H256] {
+        self.tx_blocks.get(tx_hash).map_or(&[], |blocks| &blocks[..])
+    }
+
+    /// Get the transactions of connected blocks that involve an address, as signer or recipient,
+    /// in the order they were first connected
+    pub fn transactions_of(&self, address: &H160) -> &[H256] {
+        self.address_txs.get(address).map_or(&[], |txs| &txs[..])
+    }
+
+    /// Get the connected blocks that have no children: the tip and the ends of side chains
+    pub fn chain_tips(&self) -> Vec<H256> {
+        let parents: HashSet<H256> = self.chain.values().map(|block| block.Header.parent).collect();
+        self.chain.keys().filter(|hash| !parents.contains(hash)).copied().collect()
+    }
+
+    /// Get the hashes of the blocks of the longest chain, from genesis to the tip
     pub fn all_blocks_in_longest_chain(&self) -> Vec<H256> {
+        self.main_chain.clone()
+    }
+}
 
-        let mut phash:H256 = self.tiphash;
-        let mut result:Vec<H256>=vec![];
-        let mut buffer: [u8; 32] = [0; 32];
-        let b:H256 = buffer.into();
-        while(phash!=b){
-            result.push(phash);
-            phash = self.chain[&phash].Header.parent;
-        }
-        let mut res = result.reverse();
-        result
+/// Height of the ancestor a block at `height` keeps a skip pointer to. Any ancestor can be
+/// reached in O(log n) steps by combining skip pointers and parent links.
+fn skip_height(height: u64) -> u64 {
+    fn invert_lowest_one(n: u64) -> u64 {
+        n & n.wrapping_sub(1)
+    }
+    if height < 2 {
+        0
+    } else if height & 1 == 1 {
+        invert_lowest_one(invert_lowest_one(height - 1)) + 1
+    } else {
+        invert_lowest_one(height)
     }
 }
 
@@ -131,12 +554,246 @@ mod tests {
     use crate::block;
     use crate::crypto::hash::Hashable;
 
+    /// Build a block on `parent` that meets the difficulty, with the given timestamp to tell
+    /// siblings apart
+    fn mine_on(blockchain: &Blockchain, parent: H256, timestamp: u128) -> Block {
+        let difficulty = blockchain.chain[&blockchain.tip()].Header.difficulty;
+        (0..)
+            .map(|nonce| crate::miner::assemble_block(parent, difficulty, timestamp, nonce, vec![]))
+            .find(|block| block.hash() < difficulty)
+            .unwrap()
+    }
+
     #[test]
     fn insert_one() {
         let mut blockchain = Blockchain::new();
         let genesis_hash = blockchain.tip();
-        let block = block::generate_random_block_(&genesis_hash);
+        let difficulty = blockchain.chain[&genesis_hash].Header.difficulty;
+        let mut block = block::generate_random_block_(&genesis_hash);
+        block.Header.difficulty = difficulty;
+        while block.hash() >= difficulty {
+            block.Header.nonce = block.Header.nonce.wrapping_add(1);
+        }
         blockchain.insert(&block);
         assert_eq!(blockchain.tip(), block.hash());
     }
+
+    #[test]
+    fn target_is_inherited() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let difficulty = blockchain.chain[&genesis].Header.difficulty;
+        let mut lower = <[u8; 32]>::from(difficulty);
+        lower[0] -= 1;
+        let lower: H256 = lower.into();
+        let harder = (0..)
+            .map(|nonce| crate::miner::assemble_block(genesis, lower, 0, nonce, vec![]))
+            .find(|block| block.hash() < lower)
+            .unwrap();
+        assert_eq!(blockchain.insert(&harder), InsertOutcome::Invalid);
+        assert_eq!(blockchain.tip(), genesis);
+    }
+
+    #[test]
+    fn block_status() {
+        let mut blockchain = Blockchain::new();
+        let genesis = blockchain.tip();
+        let a1 = mine_on(&blockchain, genesis, 1);
+        let a2 = mine_on(&blockchain, a1.hash(), 2);
+        let b1 = mine_on(&blockchain, genesis, 3);
+        let orphan = mine_on(&blockchain, b1.hash(), 4);
+        for block in &[&a1, &a2, &orphan] {
+            blockchain.insert(block);
+        }
+
+        assert_eq!(blockchain.status(&genesis), BlockStatus::MainChain);
+        assert_eq!(blockchain.status(&a1.hash()), BlockStatus::MainChain);
+        assert_eq!(blockchain.status(&a2.hash()), BlockStatus::MainChain);
+        assert_eq!(blockchain.status(&orphan.hash()), BlockStatus::Orphan);
+        assert_eq!(blockchain.status(&b1.hash()), BlockStatus::Unknown);
+        assert!(blockchain.contains(&orphan.hash()));
+        assert_eq!(blockchain.get_header(&a1.hash()).unwrap().parent, genesis);
+
+        // once its parent arrives the orphan is connected, but the fork is as long as the tip
+        blockchain.insert(&b1);
+        assert_eq!(blockchain.tip(), a2.hash());
+        assert_eq!(blockchain.status(&b1.hash()), BlockStatus::SideChain);
+        assert_eq!(blockchain.status(&orphan.hash()), BlockStatus::SideChain);
+    }
+
+    #[test]
+    fn ancestor_lookup() {
+        let mut blockchain = Blockchain::new();
+        let mut hashes = vec![blockchain.tip()];
+        for i in 0..300 {
+            let block = mine_on(&blockchain, blockchain.tip(), i);
+            blockchain.insert(&block);
+            hashes.push(block.hash());
+        }
+        let tip = blockchain.tip();
+        for (height, hash) in hashes.iter().enumerate() {
+            assert_eq!(blockchain.ancestor(&tip, height as u64), Some(*hash));
+            assert_eq!(blockchain.block_at_height(height as u64), Some(*hash));
+        }
+        assert_eq!(blockchain.ancestor(&hashes[10], 11), None);
+        let fork = mine_on(&blockchain, hashes[200], 1000);
+        blockchain.insert(&fork);
+        assert_eq!(blockchain.common_ancestor(&fork.hash(), &tip), Some(hashes[200]));
+        assert_eq!(blockchain.status(&fork.hash()), BlockStatus::SideChain);
+    }
+
+    #[test]
+    fn confirmations_and_checkpoint() {
+        let mut blockchain = Blockchain::new();
+        blockchain.confirmation_depth = 2;
+        blockchain.max_reorg_depth = Some(1);
+        let genesis = blockchain.tip();
+        let mut main = vec![];
+        for i in 0..3 {
+            let block = mine_on(&blockchain, blockchain.tip(), i);
+            blockchain.insert(&block);
+            main.push(block.hash());
+        }
+        assert_eq!(blockchain.confirmations(&main[0]), 3);
+        assert_eq!(blockchain.confirmations(&main[2]), 1);
+        assert!(blockchain.is_final(&main[1]));
+        assert!(!blockchain.is_final(&main[2]));
+        assert_eq!(blockchain.final_tip(), Some(main[1]));
+        assert_eq!(blockchain.tx_confirmations(&crate::crypto::hash::generate_random_hash()), None);
+
+        // a fork one block deep is accepted, one two blocks deep is refused
+        let shallow = mine_on(&blockchain, main[1], 100);
+        assert_eq!(blockchain.insert(&shallow), InsertOutcome::SideChain);
+        assert_eq!(blockchain.confirmations(&shallow.hash()), 0);
+        let deep = mine_on(&blockchain, main[0], 101);
+        assert_eq!(blockchain.insert(&deep), InsertOutcome::Invalid);
+        assert_eq!(blockchain.status(&genesis), BlockStatus::MainChain);
+    }
+
+    #[test]
+    fn spec_genesis_and_reward() {
+        use crate::chain_spec::{Allocation, ChainSpec};
+        use crate::crypto::address::generate_random_address;
+        use crate::transaction::{self, UtxoOutput};
+
+        let miner = generate_random_address();
+        let spec = ChainSpec {
+            allocations: vec![Allocation { address: miner, value: 5 }],
+            initial_reward: 10,
+            ..ChainSpec::default()
+        };
+        let mut blockchain = Blockchain::from_spec(spec.clone());
+        let genesis = blockchain.tip();
+        assert_ne!(genesis, Blockchain::new().tip());
+        assert_eq!(Blockchain::from_spec(spec).tip(), genesis);
+        let allocated: Vec<_> = blockchain.tip_state().iter().map(|(_, output)| output).collect();
+        assert_eq!(allocated.len(), 1);
+        assert_eq!(allocated[0].value, 5);
+
+        let difficulty = blockchain.chain[&genesis].Header.difficulty;
+        let mine = |parent: H256, content: Vec<_>| {
+            (0..)
+                .map(|nonce| crate::miner::assemble_block(parent, difficulty, 0, nonce, content.clone()))
+                .find(|block| block.hash() < difficulty)
+                .unwrap()
+        };
+        let pay = |value| vec![UtxoOutput { recipient_address: miner, value }];
+        let greedy = mine(genesis, vec![transaction::coinbase(genesis, pay(11))]);
+        assert_eq!(blockchain.insert(&greedy), InsertOutcome::Invalid);
+        let replayed = mine(genesis, vec![transaction::coinbase(H256::default(), pay(10))]);
+        assert_eq!(blockchain.insert(&replayed), InsertOutcome::Invalid);
+        let rewarded = mine(genesis, vec![transaction::coinbase(genesis, pay(10))]);
+        assert_eq!(blockchain.insert(&rewarded), InsertOutcome::ExtendedTip { connected: vec![rewarded.hash()] });
+        assert_eq!(blockchain.tip_state().iter().count(), 2);
+
+        blockchain.spec.max_block_transactions = 0;
+        let tip = blockchain.tip();
+        let too_many = mine(tip, vec![transaction::coinbase(tip, pay(1))]);
+        assert_eq!(blockchain.insert(&too_many), InsertOutcome::Invalid);
+    }
+
+    #[test]
+    fn invalidate_and_reconsider() {
+        use crate::chain_spec::ChainSpec;
+        use crate::crypto::address::generate_random_address;
+        use crate::memory_pool::TransactionMempool;
+
+        let mut blockchain = Blockchain::from_spec(ChainSpec::regtest());
+        let mempool = TransactionMempool::new();
+        let payout = generate_random_address();
+        let mut main = vec![];
+        for _ in 0..3 {
+            let content = crate::miner::block_template(&blockchain, &mempool, Some(payout));
+            assert!(content[0].is_coinbase());
+            let block = crate::miner::assemble_block(blockchain.tip(), blockchain.spec.initial_target, 0, 0, content);
+            assert!(matches!(blockchain.insert(&block), InsertOutcome::ExtendedTip { .. }));
+            main.push(block.hash());
+        }
+        let fork = mine_on(&blockchain, main[0], 7);
+        blockchain.insert(&fork);
+        assert!(blockchain.invalidate_block(&blockchain.all_blocks_in_longest_chain()[0]).is_err());
+
+        let outcome = blockchain.invalidate_block(&main[1]).unwrap();
+        assert_eq!(outcome, InsertOutcome::Reorg { disconnected: vec![main[2], main[1]], connected: vec![fork.hash()] });
+        assert_eq!(blockchain.tip(), fork.hash());
+        // blocks built on an invalidated block do not become the tip
+        let extension = mine_on(&blockchain, main[2], 8);
+        assert_eq!(blockchain.insert(&extension), InsertOutcome::SideChain);
+
+        let outcome = blockchain.reconsider_block(&main[2]).unwrap();
+        assert_eq!(outcome, InsertOutcome::Reorg {
+            disconnected: vec![fork.hash()],
+            connected: vec![main[1], main[2], extension.hash()],
+        });
+        assert_eq!(blockchain.reconsider_block(&main[2]).unwrap(), InsertOutcome::SideChain);
+    }
+
+    #[test]
+    fn insert_outcomes() {
+        let mut blockchain = Blockchain::new();
+        let events = blockchain.subscribe();
+        let genesis = blockchain.tip();
+        let a1 = mine_on(&blockchain, genesis, 1);
+        let b1 = mine_on(&blockchain, genesis, 2);
+        let b2 = mine_on(&blockchain, b1.hash(), 3);
+        let b3 = mine_on(&blockchain, b2.hash(), 4);
+        let mut invalid = mine_on(&blockchain, genesis, 5);
+        while invalid.hash() < invalid.Header.difficulty {
+            invalid.Header.nonce = invalid.Header.nonce.wrapping_add(1);
+        }
+
+        assert_eq!(blockchain.insert(&a1), InsertOutcome::ExtendedTip { connected: vec![a1.hash()] });
+        assert_eq!(blockchain.insert(&a1), InsertOutcome::AlreadyKnown);
+        assert_eq!(blockchain.insert(&b1), InsertOutcome::SideChain);
+        assert_eq!(blockchain.insert(&b3), InsertOutcome::Orphan);
+        assert_eq!(blockchain.insert(&invalid), InsertOutcome::Invalid);
+        // orphans without proof of work are not pooled
+        let mut unworked = mine_on(&blockchain, b2.hash(), 6);
+        while unworked.hash() < unworked.Header.difficulty {
+            unworked.Header.nonce = unworked.Header.nonce.wrapping_add(1);
+        }
+        let mut easy = mine_on(&blockchain, b2.hash(), 7);
+        easy.Header.difficulty = [0xff; 32].into();
+        assert_eq!(blockchain.insert(&unworked), InsertOutcome::Invalid);
+        assert_eq!(blockchain.insert(&easy), InsertOutcome::Invalid);
+        assert_eq!(blockchain.status(&unworked.hash()), BlockStatus::Unknown);
+        // b2 connects the orphan b3, which makes the b fork the longest chain
+        assert_eq!(
+            blockchain.insert(&b2),
+            InsertOutcome::Reorg {
+                disconnected: vec![a1.hash()],
+                connected: vec![b1.hash(), b2.hash(), b3.hash()],
+            }
+        );
+        assert_eq!(blockchain.tip(), b3.hash());
+        assert_eq!(blockchain.all_blocks_in_longest_chain(), vec![genesis, b1.hash(), b2.hash(), b3.hash()]);
+        assert!(!blockchain.is_in_main_chain(&a1.hash()));
+        assert_eq!(blockchain.common_ancestor(&a1.hash(), &b3.hash()), Some(genesis));
+
+        let blocks: Vec<H256> = events.try_iter().map(|event| event.block).collect();
+        assert_eq!(
+            blocks,
+            vec![a1.hash(), b1.hash(), b3.hash(), invalid.hash(), unworked.hash(), easy.hash(), b2.hash()]
+        );
+    }
 }
@@ -0,0 +1,169 @@
+//! The parameters of a chain: its genesis block, the initial coin allocations, the limits on
+//! block size and the block reward schedule.
+//!
+//! A chain specification is loaded from a JSON file. Missing fields take the value of the
+//! default chain, and hashes and addresses are written as hex strings:
+//!
+//! ```json
+//! {
+//!   "genesis_timestamp": 0,
+//!   "initial_target": "f230c1e86080832e1ee4b653b904df6812d74ef8e306c4112adaffeeb70bf09a",
+//!   "allocations": [{ "address": "3fd7186e306a90f63f77d4d94d9be83a762d4762", "value": 10000000 }],
+//!   "max_block_transactions": 100,
+//!   "max_block_size": 1000000,
+//!   "initial_reward": 50,
+//!   "halving_interval": 210000,
+//!   "regtest": false
+//! }
+//! ```
+
+use crate::crypto::address::{self, H160};
+use crate::crypto::hash::{H256, Hashable};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Coins given to an address by the genesis block.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Allocation {
+    #[serde(with = "hex_string")]
+    pub address: H160,
+    pub value: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainSpec {
+    /// Timestamp of the genesis block, in milliseconds since the Unix epoch
+    pub genesis_timestamp: u64,
+    /// Proof of work target of the genesis block, inherited by its descendants
+    #[serde(with = "hex_string")]
+    pub initial_target: H256,
+    /// Outputs created by the genesis block
+    pub allocations: Vec<Allocation>,
+    /// Maximum number of transactions in a block, coinbase included
+    pub max_block_transactions: usize,
+    /// Maximum total serialized size of the transactions in a block, in bytes
+    pub max_block_size: u64,
+    /// Value a coinbase may create in the first blocks, on top of the fees
+    pub initial_reward: u32,
+    /// Number of blocks after which the reward is halved, 0 to never halve it
+    pub halving_interval: u64,
+    /// Whether blocks may be generated on demand, which is only meant for tests
+    pub regtest: bool,
+}
+
+impl ChainSpec {
+    /// Read a chain specification from a JSON file.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+        serde_json::from_reader(file).map_err(|e| e.to_string())
+    }
+
+    /// The default chain with a trivial proof of work target, where blocks can be generated on
+    /// demand in tests.
+    pub fn regtest() -> Self {
+        ChainSpec {
+            initial_target: [0xff; 32].into(),
+            regtest: true,
+            ..ChainSpec::default()
+        }
+    }
+
+    /// The value a coinbase may create in the block at `height`, on top of the fees.
+    pub fn reward(&self, height: u64) -> u32 {
+        if self.halving_interval == 0 {
+            return self.initial_reward;
+        }
+        let halvings = height / self.halving_interval;
+        if halvings >= 32 {
+            return 0;
+        }
+        self.initial_reward >> halvings
+    }
+}
+
+impl Hashable for ChainSpec {
+    fn hash(&self) -> H256 {
+        let serialized = bincode::serialize(&self).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &serialized).into()
+    }
+}
+
+impl Default for ChainSpec {
+    /// The chain the client used before specifications could be loaded.
+    fn default() -> Self {
+        let public_keys: [&[u8]; 3] = [
+            b"LIYIJIANaC1lZDI1NTE5AAAAICYqyx/qrxvVPB2lPvV3ZmTH+uYwB6wL1hkBlGaYPmGu",
+            b"LIYIJIANaC1lZDI1NTE5AAAAIDfqgH+ezyswXrz2YNDkkYXCTCTMi+Ms6GWW5NQXNUc4",
+            b"LIYIJIANaC1lZDI1NTE5AAAAIMborH2X51+g+ziV0LmZY8p90+eEP/9jPAOUauBPorL/",
+        ];
+        let allocations = public_keys
+            .iter()
+            .map(|key| Allocation {
+                address: address::address_from_public_key_vec_ref(&key.to_vec()),
+                value: 10000000,
+            })
+            .collect();
+        ChainSpec {
+            genesis_timestamp: 0,
+            initial_target: hex!("f230c1e86080832e1ee4b653b904df6812d74ef8e306c4112adaffeeb70bf09a")
+                .into(),
+            allocations,
+            max_block_transactions: 100,
+            max_block_size: 1_000_000,
+            initial_reward: 50,
+            halving_interval: 210_000,
+            regtest: false,
+        }
+    }
+}
+
+/// Serialize a value as the string given by its `Display` implementation, and parse it back
+/// with `FromStr`.
+mod hex_string {
+    use serde::{Deserialize, Deserializer, Serializer};
+    use std::fmt::Display;
+    use std::str::FromStr;
+
+    pub fn serialize<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: FromStr<Err = String>,
+        D: Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_reward() {
+        let spec: ChainSpec = serde_json::from_str(
+            r#"{
+                "initial_target": "00ff000000000000000000000000000000000000000000000000000000000000",
+                "allocations": [{ "address": "000102030405060708090a0b0c0d0e0f10111213", "value": 7 }],
+                "initial_reward": 8,
+                "halving_interval": 10
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(spec.allocations[0].address.as_ref()[19], 0x13);
+        assert_eq!(spec.max_block_transactions, ChainSpec::default().max_block_transactions);
+        assert_eq!(spec.reward(9), 8);
+        assert_eq!(spec.reward(10), 4);
+        assert_eq!(spec.reward(1000), 0);
+        assert!(!spec.regtest);
+        assert!(ChainSpec::regtest().regtest);
+        let json = serde_json::to_string(&spec).unwrap();
+        assert_eq!(serde_json::from_str::<ChainSpec>(&json).unwrap(), spec);
+        assert_ne!(spec.hash(), ChainSpec::default().hash());
+        assert!(serde_json::from_str::<ChainSpec>(r#"{"initial_reward": 1, "typo": 2}"#).is_err());
+    }
+}
@@ -17,6 +17,31 @@ impl std::fmt::Debug for H160 {
     }
 }
 
+impl std::fmt::Display for H160 {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:>02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::str::FromStr for H160 {
+    type Err = String;
+
+    /// Parse an address from 40 hex digits, as printed by `Display`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 40 || !s.is_ascii() {
+            return Err("expected 40 hex digits".to_string());
+        }
+        let mut buffer: [u8; 20] = [0; 20];
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|e| e.to_string())?;
+        }
+        Ok(H160(buffer))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H160 {
     fn as_ref(&self) -> &[u8] {
         &self.0
@@ -49,6 +49,22 @@ impl std::fmt::Debug for H256 {
     }
 }
 
+impl std::str::FromStr for H256 {
+    type Err = String;
+
+    /// Parse a hash from 64 hex digits, as printed by `Display`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.len() != 64 || !s.is_ascii() {
+            return Err("expected 64 hex digits".to_string());
+        }
+        let mut buffer: [u8; 32] = [0; 32];
+        for (i, byte) in buffer.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[2 * i..2 * i + 2], 16).map_err(|e| e.to_string())?;
+        }
+        Ok(H256(buffer))
+    }
+}
+
 impl std::convert::AsRef<[u8]> for H256 {
     fn as_ref(&self) -> &[u8] {
         &self.0
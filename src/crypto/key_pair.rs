@@ -3,7 +3,11 @@ use ring::signature::Ed25519KeyPair;
 
 /// Generate a random key pair.
 pub fn random() -> Ed25519KeyPair {
+    Ed25519KeyPair::from_pkcs8(&random_pkcs8()).unwrap()
+}
+
+/// Generate a random key pair as a PKCS#8 document, the form in which it can be stored.
+pub fn random_pkcs8() -> Vec<u8> {
     let rng = rand::SystemRandom::new();
-    let pkcs8_bytes = Ed25519KeyPair::generate_pkcs8(&rng).unwrap();
-    Ed25519KeyPair::from_pkcs8(pkcs8_bytes.as_ref().into()).unwrap()
+    Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref().to_vec()
 }
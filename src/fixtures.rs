@@ -0,0 +1,38 @@
+//! Chains and transactions shared by the unit tests.
+
+use crate::blockchain::Blockchain;
+use crate::chain_spec::ChainSpec;
+use crate::crypto::address::H160;
+use crate::crypto::hash::Hashable;
+use crate::memory_pool::TransactionMempool;
+use crate::miner;
+use crate::transaction::{self, SignTransaction, Transaction, UtxoInput, UtxoOutput};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+
+/// The key pair derived from a seed of 32 `seed` bytes
+pub fn key(seed: u8) -> Ed25519KeyPair {
+    Ed25519KeyPair::from_seed_unchecked(&[seed; 32]).unwrap()
+}
+
+/// A regtest chain with one block after genesis, whose coinbase pays the block reward to
+/// `owner`. Also returns the coinbase output, as the input spending it, and the reward.
+pub fn chain_with_coinbase_to(owner: H160) -> (Blockchain, UtxoInput, u32) {
+    let mut blockchain = Blockchain::from_spec(ChainSpec::regtest());
+    let content = miner::block_template(&blockchain, &TransactionMempool::new(), Some(owner));
+    let reward = content[0].transaction.tx_output[0].value;
+    let coin = UtxoInput { prev_hash: content[0].transaction.hash(), index: 0 };
+    let block = miner::assemble_block(blockchain.tip(), blockchain.spec.initial_target, 0, 0, content);
+    blockchain.insert(&block);
+    (blockchain, coin, reward)
+}
+
+/// A transaction spending `inputs` to `outputs`, signed with `key`
+pub fn sign_spend(key: &Ed25519KeyPair, inputs: Vec<UtxoInput>, outputs: Vec<UtxoOutput>) -> SignTransaction {
+    let t = Transaction { tx_input: inputs, tx_output: outputs };
+    let signature = transaction::sign(&t, key);
+    SignTransaction {
+        transaction: t,
+        public_key: key.public_key().as_ref().to_vec(),
+        signature: signature.as_ref().to_vec(),
+    }
+}
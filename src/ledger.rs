@@ -1,16 +1,66 @@
-use crate::transaction::{UtxoInput, UtxoOutput};
+use crate::transaction::{SignTransaction, UtxoInput, UtxoOutput};
 use crate::crypto::hash::H256;
 use crate::block::Block;
 use crate::crypto::hash::Hashable;
-use crate::crypto::address::{self, H160};
-use ring::{digest};
+use crate::crypto::address::H160;
+use std::collections::HashSet;
 use std::collections::HashMap;
 use log::debug;
 
 #[derive(Debug, Default, Clone)]
 pub struct State{
     //We store the stat as UTXO model: HashMap<UtxoInput(transaction hash, output index), UtxoOutput(value, recipient)>
-    pub state_map: HashMap<UtxoInput, UtxoOutput>,  
+    state_map: HashMap<UtxoInput, UtxoOutput>,
+    //Index of the unspent outputs by recipient address, kept in sync by insert and remove
+    by_address: HashMap<H160, HashSet<UtxoInput>>,
+}
+
+impl State{
+    //Add an unspent output
+    pub fn insert(&mut self, input: UtxoInput, output: UtxoOutput) {
+        if let Some(previous) = self.state_map.insert(input.clone(), output) {
+            self.forget_address(&previous.recipient_address, &input);
+        }
+        self.by_address.entry(output.recipient_address).or_default().insert(input);
+    }
+
+    //Spend an output, returning it if it was unspent
+    pub fn remove(&mut self, input: &UtxoInput) -> Option<UtxoOutput> {
+        let output = self.state_map.remove(input)?;
+        self.forget_address(&output.recipient_address, input);
+        Some(output)
+    }
+
+    fn forget_address(&mut self, address: &H160, input: &UtxoInput) {
+        if let Some(inputs) = self.by_address.get_mut(address) {
+            inputs.remove(input);
+            if inputs.is_empty() {
+                self.by_address.remove(address);
+            }
+        }
+    }
+
+    //The unspent output of an input, if it is unspent
+    pub fn get(&self, input: &UtxoInput) -> Option<&UtxoOutput> {
+        self.state_map.get(input)
+    }
+
+    //All the unspent outputs, in no particular order
+    pub fn iter(&self) -> impl Iterator<Item = (&UtxoInput, &UtxoOutput)> {
+        self.state_map.iter()
+    }
+
+    //The unspent outputs of an address
+    pub fn utxos_of(&self, address: &H160) -> Vec<(UtxoInput, UtxoOutput)> {
+        self.by_address.get(address).map_or(vec![], |inputs| {
+            inputs.iter().map(|input| (input.clone(), self.state_map[input])).collect()
+        })
+    }
+
+    //The total value of the unspent outputs of an address
+    pub fn balance_of(&self, address: &H160) -> u64 {
+        self.utxos_of(address).iter().map(|(_, output)| output.value as u64).sum()
+    }
 }
 
 pub struct BlockState{
@@ -18,6 +68,17 @@ pub struct BlockState{
 }
 
 
+//Spend the inputs of a transaction and add its outputs to the state
+pub fn apply_transaction(signed_tx: &SignTransaction, state: &mut State) {
+    for tx_input in &signed_tx.transaction.tx_input {
+        state.remove(tx_input);
+    }
+    for (i, tx_output) in (&signed_tx.transaction.tx_output).iter().enumerate() {
+        let tx_input = UtxoInput{prev_hash: signed_tx.transaction.hash(), index: i as u8};
+        state.insert(tx_input, *tx_output);
+    }
+}
+
 //State updates
 pub fn update_block_state(block: &Block, block_state: &mut BlockState) {
     //In UTXO model, remove those inputs, and add outputs to the state.
@@ -27,13 +88,7 @@ pub fn update_block_state(block: &Block, block_state: &mut BlockState) {
     debug!{"The parent_state {:?}", parent_state}
   
     for signed_tx in &block.Content.content {
-        for tx_input in &signed_tx.transaction.tx_input {
-            cur_block_state.state_map.remove(tx_input);
-        }
-        for (i, tx_output) in (&signed_tx.transaction.tx_output).iter().enumerate() {
-            let tx_input = UtxoInput{prev_hash: signed_tx.transaction.hash(), index: i as u8};
-            cur_block_state.state_map.insert(tx_input, tx_output.clone());
-        }
+        apply_transaction(signed_tx, &mut cur_block_state);
     }
     
     debug!{"Now the cur_block_state is {:?}", cur_block_state}
@@ -41,33 +96,11 @@ pub fn update_block_state(block: &Block, block_state: &mut BlockState) {
     block_state.block_state_map.insert(block.hash(), cur_block_state);
   }
   
-  //Initial state (ICO)
-  pub fn ico() -> State {
-    let public_key1: Vec<u8> = b"LIYIJIANaC1lZDI1NTE5AAAAICYqyx/qrxvVPB2lPvV3ZmTH+uYwB6wL1hkBlGaYPmGu".to_vec();
-    let public_key2: Vec<u8> = b"LIYIJIANaC1lZDI1NTE5AAAAIDfqgH+ezyswXrz2YNDkkYXCTCTMi+Ms6GWW5NQXNUc4".to_vec();
-    let public_key3: Vec<u8> = b"LIYIJIANaC1lZDI1NTE5AAAAIMborH2X51+g+ziV0LmZY8p90+eEP/9jPAOUauBPorL/".to_vec();
-    
-    let mut address_vec: Vec<H160> = vec![];
-    let address1 = address::address_from_public_key_vec_ref(&public_key1);
-    let address2 = address::address_from_public_key_vec_ref(&public_key2);
-    let address3 = address::address_from_public_key_vec_ref(&public_key3);
-    
-    address_vec.push(address1);
-    address_vec.push(address2);
-    address_vec.push(address3);
-    
-
-    let initial_tx = digest::digest(&ring::digest::SHA256,"liyijian19991214c0932d964c0859397b9db4d93h4d62c368b95419db574db0".as_bytes());
-    let initial_tx_hash = <H256>::from(initial_tx);
-    //let initial_tx_hash: H256 = hex!("6b787718210e0b3b608814e04e61fde06d0df794319a12162f287412df3ec920").into() ;
-    let val: u32 = 10000000;
-    
-    let mut initial_state: State = State{state_map: HashMap::new()};
-    for (i,address) in  address_vec.iter().enumerate() {
-      let input = UtxoInput{prev_hash: initial_tx_hash, index: i as u8};
-      let output = UtxoOutput{recipient_address: *address, value: val};
-      initial_state.state_map.insert(input, output);
+  //Initial state (ICO): the outputs created by the genesis block
+  pub fn genesis_state(genesis: &Block) -> State {
+    let mut initial_state: State = State::default();
+    for signed_tx in &genesis.Content.content {
+      apply_transaction(signed_tx, &mut initial_state);
     }
-  
     initial_state
-  }
\ No newline at end of file
+  }
@@ -5,24 +5,32 @@ extern crate hex_literal;
 pub mod api;
 pub mod block;
 pub mod blockchain;
+pub mod chain_spec;
 pub mod crypto;
 pub mod miner;
 pub mod network;
 pub mod transaction;
 pub mod memory_pool;
+pub mod metrics;
+pub mod orphan_pool;
 pub mod txs_check;
+pub mod wallet;
 pub mod ledger;
+#[cfg(any(test, feature = "test-utilities"))]
+pub mod sim;
+#[cfg(test)]
+mod fixtures;
 
 use clap::clap_app;
 use crossbeam::channel;
-use log::{error, info};
+use log::{error, info, warn};
 use api::Server as ApiServer;
 use network::{server, worker};
 use std::net;
 use std::process;
 use std::thread;
 use std::time;
-use std::sync::{Arc, Mutex};
+use std::sync::{Arc, RwLock};
 
 fn main() {
     // parse command line arguments
@@ -34,6 +42,21 @@ fn main() {
      (@arg api_addr: --api [ADDR] default_value("127.0.0.1:7000") "Sets the IP address and the port of the API server")
      (@arg known_peer: -c --connect ... [PEER] "Sets the peers to connect to at start")
      (@arg p2p_workers: --("p2p-workers") [INT] default_value("4") "Sets the number of worker threads for P2P server")
+     (@arg link_delay: --delay [MS] default_value("0") "Adds a fixed delay to every outgoing P2P message")
+     (@arg link_jitter: --jitter [MS] default_value("0") "Adds a random delay of up to this value to every outgoing P2P message")
+     (@arg link_bandwidth: --bandwidth [BYTES] default_value("0") "Caps the outgoing bandwidth to each peer in bytes per second, 0 for unlimited")
+     (@arg link_loss: --loss [PROB] default_value("0") "Sets the probability of dropping an outgoing P2P message")
+     (@arg record: --record [FILE] "Records the messages received from peers to a file")
+     (@arg replay: --replay [FILE] conflicts_with("record") "Replays recorded messages against a fresh blockchain and exits")
+     (@arg chain_spec: --("chain-spec") [FILE] "Loads the chain parameters and genesis allocations from a JSON file")
+     (@arg regtest: --regtest conflicts_with("chain_spec") "Runs a chain with a trivial difficulty, where blocks are generated on demand through the API")
+     (@arg confirmations: --confirmations [K] default_value("6") "Sets the number of confirmations after which a block or transaction is final")
+     (@arg max_reorg_depth: --("max-reorg-depth") [BLOCKS] "Refuses reorganizations that disconnect more than this number of blocks")
+     (@arg api_token: --("api-token") [TOKEN] "Requires this bearer token for admin access to the API")
+     (@arg api_read_token: --("api-read-token") [TOKEN] "Grants read-only access to the API with this bearer token")
+     (@arg api_cookie: --("api-cookie") [FILE] "Writes a random admin token for the API to this file at start")
+     (@arg api_allow: --("api-allow") ... [NET] "Only accepts API clients from these addresses or networks, such as 10.0.0.0/8")
+     (@arg data_dir: --("data-dir") [DIR] "Stores the wallet keys in this directory, which is created if needed")
     )
     .get_matches();
 
@@ -61,24 +84,78 @@ fn main() {
             process::exit(1);
         });
 
+    // replay a recording instead of running the client
+    if let Some(path) = matches.value_of("replay") {
+        let records = network::replay::read(path.as_ref()).unwrap_or_else(|e| {
+            error!("Error reading recording {}: {}", path, e);
+            process::exit(1);
+        });
+        let blockchain = Arc::new(RwLock::new(new_blockchain(&matches)));
+        let tx_mempool = Arc::new(RwLock::new(memory_pool::TransactionMempool::new()));
+        let (transport, outbox) = network::loopback::new();
+        let (_, msg_rx) = channel::unbounded();
+        let worker_ctx = worker::new(1, msg_rx, &transport, &blockchain, &tx_mempool);
+        let processed = network::replay::replay(records, &worker_ctx, &outbox);
+        let blockchain = blockchain.read().unwrap();
+        println!(
+            "Replayed {} messages, {} blocks in chain, tip {}",
+            processed,
+            blockchain.chain.len(),
+            blockchain.tip()
+        );
+        return;
+    }
+
+    let api_access = api_access_control(&matches, &api_addr);
+
     // create channels between server and worker
     let (msg_tx, msg_rx) = channel::unbounded();
 
+    // record the messages from peers on their way to the workers
+    let msg_tx = match matches.value_of("record") {
+        Some(path) => {
+            let file = std::fs::File::create(path).unwrap_or_else(|e| {
+                error!("Error creating recording {}: {}", path, e);
+                process::exit(1);
+            });
+            let (tap_tx, tap_rx) = channel::unbounded();
+            network::replay::record(tap_rx, msg_tx, file).unwrap();
+            tap_tx
+        }
+        None => msg_tx,
+    };
 
+    // parse artificial link conditions
+    let link_conditions = network::peer::LinkConditions {
+        delay_ms: parse_arg(&matches, "link_delay"),
+        jitter_ms: parse_arg(&matches, "link_jitter"),
+        bandwidth: parse_arg(&matches, "link_bandwidth"),
+        loss: parse_arg(&matches, "link_loss"),
+    };
+    if !(0.0..=1.0).contains(&link_conditions.loss) {
+        error!("Error parsing link_loss: must be between 0 and 1");
+        process::exit(1);
+    }
 
     // start the p2p server
     let (server_ctx, server) = server::new(p2p_addr, msg_tx).unwrap();
     server_ctx.start().unwrap();
+    server.set_link_conditions(link_conditions, None);
 
     // start the miner
-    let blockchain = Arc::new(Mutex::new(blockchain::Blockchain::new()));
-    let tx_mempool = Arc::new(Mutex::new(memory_pool::TransactionMempool::new()));
+    let blockchain = Arc::new(RwLock::new(new_blockchain(&matches)));
+    let tx_mempool = Arc::new(RwLock::new(memory_pool::TransactionMempool::new()));
     let (miner_ctx, miner) = miner::new(
         &server,
         &blockchain,
+        &tx_mempool,
     );
     miner_ctx.start();
 
+    // load the wallet and keep it in sync with the chain
+    let wallet = Arc::new(RwLock::new(open_wallet(&matches)));
+    wallet::follow(&wallet, &blockchain);
+
     // start the worker
     let p2p_workers = matches
         .value_of("p2p_workers")
@@ -138,9 +215,107 @@ fn main() {
         api_addr,
         &miner,
         &server,
+        &blockchain,
+        &tx_mempool,
+        &wallet,
+        api_access,
     );
 
     loop {
         std::thread::park();
     }
-}
\ No newline at end of file
+}
+
+/// Create a blockchain of the chain and with the finality settings given on the command line.
+fn new_blockchain(matches: &clap::ArgMatches) -> blockchain::Blockchain {
+    let spec = match matches.value_of("chain_spec") {
+        Some(path) => chain_spec::ChainSpec::load(path.as_ref()).unwrap_or_else(|e| {
+            error!("Error loading chain spec {}: {}", path, e);
+            process::exit(1);
+        }),
+        None if matches.is_present("regtest") => chain_spec::ChainSpec::regtest(),
+        None => chain_spec::ChainSpec::default(),
+    };
+    let mut blockchain = blockchain::Blockchain::from_spec(spec);
+    info!("Genesis block hash is {}", blockchain.all_blocks_in_longest_chain()[0]);
+    blockchain.confirmation_depth = parse_arg(matches, "confirmations");
+    if matches.is_present("max_reorg_depth") {
+        blockchain.max_reorg_depth = Some(parse_arg(matches, "max_reorg_depth"));
+    }
+    blockchain
+}
+
+/// Open the wallet of the data directory given on the command line, or create a wallet that is
+/// only kept in memory without one.
+fn open_wallet(matches: &clap::ArgMatches) -> wallet::Wallet {
+    match matches.value_of("data_dir") {
+        Some(dir) => wallet::Wallet::open(dir.as_ref()).unwrap_or_else(|e| {
+            error!("Error opening wallet: {}", e);
+            process::exit(1);
+        }),
+        None => {
+            let mut wallet = wallet::Wallet::new();
+            wallet.new_address().unwrap();
+            warn!("No --data-dir given, wallet keys will be lost when the node stops");
+            wallet
+        }
+    }
+}
+
+/// Build the access control of the API server from the command line. An API server reachable
+/// from other hosts must require a token or restrict its clients.
+fn api_access_control(
+    matches: &clap::ArgMatches,
+    api_addr: &net::SocketAddr,
+) -> api::auth::AccessControl {
+    let mut access = api::auth::AccessControl::default();
+    if let Some(token) = matches.value_of("api_token") {
+        access.add_token(token, api::auth::Role::Admin);
+    }
+    if let Some(token) = matches.value_of("api_read_token") {
+        if !matches.is_present("api_token") && !matches.is_present("api_cookie") {
+            error!("A read-only API token requires an admin token or cookie");
+            process::exit(1);
+        }
+        access.add_token(token, api::auth::Role::ReadOnly);
+    }
+    if let Some(path) = matches.value_of("api_cookie") {
+        let token = api::auth::write_cookie(path.as_ref()).unwrap_or_else(|e| {
+            error!("Error writing API cookie {}: {}", path, e);
+            process::exit(1);
+        });
+        access.add_token(&token, api::auth::Role::Admin);
+        info!("API admin token written to {}", path);
+    }
+    for network in matches.values_of("api_allow").into_iter().flatten() {
+        if let Err(e) = access.allow(network) {
+            error!("Error parsing api-allow: {}", e);
+            process::exit(1);
+        }
+    }
+    if !api_addr.ip().is_loopback() && !access.requires_token() && !access.restricts_clients() {
+        error!(
+            "Refusing to expose the API at {} without authentication, use --api-token, \
+             --api-cookie or --api-allow",
+            api_addr
+        );
+        process::exit(1);
+    }
+    access
+}
+
+/// Parse a command line argument that has a default value, exiting on error.
+fn parse_arg<T>(matches: &clap::ArgMatches, name: &str) -> T
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    matches
+        .value_of(name)
+        .unwrap()
+        .parse::<T>()
+        .unwrap_or_else(|e| {
+            error!("Error parsing {}: {}", name, e);
+            process::exit(1);
+        })
+}
@@ -1,19 +1,288 @@
-use crate::crypto::hash::H256;
+use crate::block::Block;
+use crate::blockchain::{Blockchain, InsertOutcome};
+use crate::crypto::hash::{H256, Hashable};
+use crate::ledger::State;
+use crate::metrics;
 use crate::transaction::SignTransaction;
+use crate::txs_check;
+use crossbeam::channel;
 
 use std::collections::VecDeque;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::SystemTime;
+
+/// Why a transaction was not added to the mempool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+  /// Coinbases are only valid in the block that creates them
+  Coinbase,
+  InvalidSignature,
+  AlreadyKnown,
+  /// Spends outputs that are neither unspent on the tip nor created by a pending transaction,
+  /// or more than their value
+  Unspendable,
+  /// Spends an output that a pending transaction already spends
+  Conflict,
+}
+
+impl Rejection {
+  /// A short identifier of the reason, for API clients
+  pub fn code(&self) -> &'static str {
+    match self {
+      Rejection::Coinbase => "coinbase",
+      Rejection::InvalidSignature => "invalid_signature",
+      Rejection::AlreadyKnown => "already_known",
+      Rejection::Unspendable => "unspendable",
+      Rejection::Conflict => "conflict",
+    }
+  }
+}
+
+impl std::fmt::Display for Rejection {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    let reason = match self {
+      Rejection::Coinbase => "coinbase transactions cannot be relayed",
+      Rejection::InvalidSignature => "invalid signature",
+      Rejection::AlreadyKnown => "transaction already known",
+      Rejection::Unspendable => "spends unavailable outputs",
+      Rejection::Conflict => "conflicts with a mempool transaction",
+    };
+    write!(f, "{}", reason)
+  }
+}
 
 pub struct TransactionMempool{
   pub tx_hash_queue: VecDeque<H256>,
   pub tx_to_process: HashMap<H256, bool>,
   pub tx_map: HashMap<H256, SignTransaction>,
+  subscribers: Vec<channel::Sender<H256>>,
+  /// When each transaction last became pending
+  entered: HashMap<H256, SystemTime>,
 }
 
 impl TransactionMempool{
   pub fn new() -> Self{
     TransactionMempool{tx_hash_queue: VecDeque::new(), 
                        tx_to_process: HashMap::new(), 
-                       tx_map: HashMap::new()}  
+                       tx_map: HashMap::new(),
+                       subscribers: vec![],
+                       entered: HashMap::new()}
+  }
+
+  /// Add a transaction to the pending ones if it is signed, spends outputs available in the
+  /// tip state or created by pending transactions, and does not conflict with a pending
+  /// transaction. Returns its hash.
+  pub fn admit(&mut self, signed_tx: SignTransaction, tip_state: &State) -> Result<H256, Rejection> {
+    let admitted = self.check_and_add(signed_tx, tip_state);
+    if let Err(rejection) = admitted {
+      metrics::global().transaction_rejected(rejection.code());
+    }
+    admitted
+  }
+
+  fn check_and_add(&mut self, signed_tx: SignTransaction, tip_state: &State) -> Result<H256, Rejection> {
+    if signed_tx.is_coinbase() {
+      return Err(Rejection::Coinbase);
+    }
+    if !txs_check::is_tx_valid(&signed_tx) {
+      return Err(Rejection::InvalidSignature);
+    }
+    let signed_tx_hash = signed_tx.hash();
+    if self.tx_to_process.contains_key(&signed_tx_hash) {
+      return Err(Rejection::AlreadyKnown);
+    }
+    if !txs_check::is_tx_spendable(&signed_tx, &self.spendable_outputs(&signed_tx, tip_state)) {
+      return Err(Rejection::Unspendable);
+    }
+    if self.conflicts(&signed_tx) {
+      return Err(Rejection::Conflict);
+    }
+    self.tx_to_process.insert(signed_tx_hash, true);
+    self.tx_map.insert(signed_tx_hash, signed_tx);
+    self.tx_hash_queue.push_back(signed_tx_hash);
+    self.entered.insert(signed_tx_hash, SystemTime::now());
+    self.notify(signed_tx_hash);
+    Ok(signed_tx_hash)
+  }
+
+  /// The outputs that the inputs of `signed_tx` spend, either unspent on the tip or created by
+  /// a pending transaction
+  fn spendable_outputs(&self, signed_tx: &SignTransaction, tip_state: &State) -> State {
+    let mut outputs = State::default();
+    for input in &signed_tx.transaction.tx_input {
+      let output = tip_state.get(input).copied().or_else(|| {
+        self.pending()
+            .map(|(_, pending_tx)| &pending_tx.transaction)
+            .find(|pending_tx| pending_tx.hash() == input.prev_hash)
+            .and_then(|pending_tx| pending_tx.tx_output.get(input.index as usize).copied())
+      });
+      if let Some(output) = output {
+        outputs.insert(input.clone(), output);
+      }
+    }
+    outputs
+  }
+
+  /// Subscribe to the hash of every transaction that becomes pending from now on, either
+  /// admitted or back from a disconnected block
+  pub fn subscribe(&mut self) -> channel::Receiver<H256> {
+    let (sender, receiver) = channel::unbounded();
+    self.subscribers.push(sender);
+    receiver
+  }
+
+  /// When a pending transaction became pending, either admitted or back from a disconnected block
+  pub fn entered(&self, tx_hash: &H256) -> Option<SystemTime> {
+    self.entered.get(tx_hash).copied()
+  }
+
+  fn notify(&mut self, tx_hash: H256) {
+    self.subscribers.retain(|subscriber| subscriber.send(tx_hash).is_ok());
   }
-}
\ No newline at end of file
+
+  /// Transactions waiting to be included in a block, in arrival order
+  pub fn pending(&self) -> impl Iterator<Item = (&H256, &SignTransaction)> {
+    self.tx_hash_queue.iter()
+        .filter(move |tx_hash| self.tx_to_process.get(*tx_hash) == Some(&true))
+        .filter_map(move |tx_hash| self.tx_map.get(tx_hash).map(|tx| (tx_hash, tx)))
+  }
+
+  /// Mark the transactions of a block as processed, and stop processing the pending
+  /// transactions that spend the same outputs since they became double spends, along with the
+  /// pending transactions spending their outputs
+  pub fn mark_included(&mut self, block: &Block) {
+    let spent: Vec<_> = block.Content.content.iter()
+        .flat_map(|signed_tx| signed_tx.transaction.tx_input.iter())
+        .collect();
+    let included: HashSet<H256> = block.Content.content.iter().map(|signed_tx| signed_tx.hash()).collect();
+    // a pending transaction arrived after the pending transactions it spends from
+    let mut dropped_txids = HashSet::new();
+    let mut conflicting: Vec<H256> = vec![];
+    for (tx_hash, pending_tx) in self.pending().filter(|(tx_hash, _)| !included.contains(*tx_hash)) {
+      let inputs = &pending_tx.transaction.tx_input;
+      if inputs.iter().any(|input| spent.contains(&input) || dropped_txids.contains(&input.prev_hash)) {
+        dropped_txids.insert(pending_tx.transaction.hash());
+        conflicting.push(*tx_hash);
+      }
+    }
+    for tx_hash in conflicting {
+      self.tx_to_process.insert(tx_hash, false);
+      self.entered.remove(&tx_hash);
+    }
+    for signed_tx in &block.Content.content {
+      let signed_tx_hash = signed_tx.hash();
+      self.tx_to_process.insert(signed_tx_hash, false);
+      self.entered.remove(&signed_tx_hash);
+      self.tx_map.entry(signed_tx_hash).or_insert_with(|| signed_tx.clone());
+    }
+  }
+
+  /// Follow a change of the longest chain: the transactions of connected blocks are marked as
+  /// processed, and those of disconnected blocks become pending again if they are still valid
+  /// on the new tip
+  pub fn update(&mut self, outcome: &InsertOutcome, blockchain: &Blockchain) {
+    let (disconnected, connected) = match outcome {
+      InsertOutcome::ExtendedTip { connected } => (&[][..], &connected[..]),
+      InsertOutcome::Reorg { disconnected, connected } => (&disconnected[..], &connected[..]),
+      _ => return,
+    };
+    for blck_hash in connected {
+      self.mark_included(&blockchain.chain[blck_hash]);
+    }
+    for blck_hash in disconnected {
+      for signed_tx in &blockchain.chain[blck_hash].Content.content {
+        let signed_tx_hash = signed_tx.hash();
+        if self.tx_to_process.get(&signed_tx_hash) == Some(&true)
+            || !txs_check::is_tx_spendable(signed_tx, &self.spendable_outputs(signed_tx, blockchain.tip_state()))
+            || self.conflicts(signed_tx) {
+          continue;
+        }
+        self.tx_to_process.insert(signed_tx_hash, true);
+        self.tx_map.entry(signed_tx_hash).or_insert_with(|| signed_tx.clone());
+        if !self.tx_hash_queue.contains(&signed_tx_hash) {
+          self.tx_hash_queue.push_back(signed_tx_hash);
+        }
+        self.entered.insert(signed_tx_hash, SystemTime::now());
+        self.notify(signed_tx_hash);
+      }
+    }
+  }
+
+  /// Whether a pending transaction already spends one of the inputs of `signed_tx`
+  pub fn conflicts(&self, signed_tx: &SignTransaction) -> bool {
+    self.pending().any(|(_, pending_tx)| {
+      pending_tx.transaction.tx_input.iter()
+          .any(|input| signed_tx.transaction.tx_input.contains(input))
+    })
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::crypto::address;
+  use crate::fixtures;
+  use crate::transaction::{self, UtxoInput, UtxoOutput};
+  use ring::signature::KeyPair;
+
+  #[test]
+  fn admission() {
+    let key = fixtures::key(3);
+    let owner = address::address_from_public_key_ref(key.public_key());
+    let input = UtxoInput{prev_hash: H256::from([7; 32]), index: 0};
+    let mut state = State::default();
+    state.insert(input.clone(), UtxoOutput{recipient_address: owner, value: 10});
+    let pay = |value| fixtures::sign_spend(&key, vec![input.clone()], vec![UtxoOutput{recipient_address: owner, value}]);
+
+    let mut mempool = TransactionMempool::new();
+    let admitted = mempool.subscribe();
+    assert_eq!(mempool.admit(pay(11), &state), Err(Rejection::Unspendable));
+    let mut forged = pay(9);
+    forged.transaction.tx_output[0].value = 10;
+    assert_eq!(mempool.admit(forged, &state), Err(Rejection::InvalidSignature));
+    assert_eq!(mempool.admit(transaction::coinbase(H256::default(), vec![]), &state), Err(Rejection::Coinbase));
+    assert_eq!(mempool.admit(pay(9), &state), Ok(pay(9).hash()));
+    assert_eq!(mempool.admit(pay(9), &state), Err(Rejection::AlreadyKnown));
+    assert_eq!(mempool.admit(pay(8), &state), Err(Rejection::Conflict));
+    assert_eq!(mempool.pending().count(), 1);
+    assert_eq!(admitted.try_iter().collect::<Vec<_>>(), vec![pay(9).hash()]);
+
+    // the entry time is forgotten once the transaction is included in a block
+    assert!(mempool.entered(&pay(9).hash()).is_some());
+    let block = crate::miner::assemble_block(H256::default(), H256::default(), 0, 0, vec![pay(9)]);
+    mempool.mark_included(&block);
+    assert_eq!(mempool.pending().count(), 0);
+    assert_eq!(mempool.entered(&pay(9).hash()), None);
+  }
+
+  #[test]
+  fn chained_spends() {
+    let key = fixtures::key(4);
+    let owner = address::address_from_public_key_ref(key.public_key());
+    let input = UtxoInput{prev_hash: H256::from([7; 32]), index: 0};
+    let mut state = State::default();
+    state.insert(input.clone(), UtxoOutput{recipient_address: owner, value: 10});
+    let pay = |input: &UtxoInput, value| fixtures::sign_spend(&key, vec![input.clone()], vec![UtxoOutput{recipient_address: owner, value}]);
+    let change = |signed_tx: &SignTransaction| UtxoInput{prev_hash: signed_tx.transaction.hash(), index: 0};
+    let parent = pay(&input, 9);
+    let child = pay(&change(&parent), 8);
+
+    // a pending output can be spent, within its value
+    let mut mempool = TransactionMempool::new();
+    mempool.admit(parent.clone(), &state).unwrap();
+    assert_eq!(mempool.admit(pay(&change(&child), 9), &state), Err(Rejection::Unspendable));
+    mempool.admit(child.clone(), &state).unwrap();
+    assert_eq!(mempool.pending().count(), 2);
+
+    // the child stays pending once its parent is confirmed
+    let mut confirmed = TransactionMempool::new();
+    confirmed.admit(parent.clone(), &state).unwrap();
+    confirmed.admit(child.clone(), &state).unwrap();
+    confirmed.mark_included(&crate::miner::assemble_block(H256::default(), H256::default(), 0, 0, vec![parent]));
+    assert_eq!(confirmed.pending().map(|(tx_hash, _)| *tx_hash).collect::<Vec<_>>(), vec![child.hash()]);
+
+    // and is dropped with it when a block double spends the parent
+    mempool.mark_included(&crate::miner::assemble_block(H256::default(), H256::default(), 0, 0, vec![pay(&input, 7)]));
+    assert_eq!(mempool.pending().count(), 0);
+  }
+}
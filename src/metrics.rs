@@ -0,0 +1,373 @@
+//! Process-wide counters and histograms, exported in the Prometheus text format by the
+//! `/metrics` endpoint of the API server.
+//!
+//! Events are recorded where they happen, on the registry returned by `global`. Values that can
+//! be read from the node at any time, such as the mempool size or the number of peers, are not
+//! recorded but passed as gauges when rendering.
+
+use crate::network::message::Message;
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Upper bounds of the buckets of the block propagation delay, in seconds
+const DELAY_BUCKETS: &[f64] = &[0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0];
+/// Upper bounds of the buckets of the reorg depth, in blocks
+const REORG_BUCKETS: &[f64] = &[1.0, 2.0, 3.0, 4.0, 6.0, 10.0, 20.0, 50.0, 100.0];
+
+static METRICS: Metrics = Metrics::new();
+
+/// The registry of the process.
+pub fn global() -> &'static Metrics {
+    &METRICS
+}
+
+pub struct Metrics {
+    blocks_mined: AtomicU64,
+    blocks_received: AtomicU64,
+    blocks_orphaned: AtomicU64,
+    blocks_invalid: AtomicU64,
+    compact_block_bytes_saved: AtomicU64,
+    block_delay: Histogram,
+    reorg_depth: Histogram,
+    transactions_rejected: Mutex<BTreeMap<&'static str, u64>>,
+    compact_block_reconstructions: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_received: Mutex<BTreeMap<&'static str, u64>>,
+    bytes_sent: Mutex<BTreeMap<&'static str, u64>>,
+    /// Time spent by the workers on each kind of message, in the order of `Message::KINDS`
+    handlers: [HandlerTimes; Message::KINDS.len()],
+}
+
+impl Metrics {
+    pub const fn new() -> Self {
+        Metrics {
+            blocks_mined: AtomicU64::new(0),
+            blocks_received: AtomicU64::new(0),
+            blocks_orphaned: AtomicU64::new(0),
+            blocks_invalid: AtomicU64::new(0),
+            compact_block_bytes_saved: AtomicU64::new(0),
+            block_delay: Histogram::new(DELAY_BUCKETS),
+            reorg_depth: Histogram::new(REORG_BUCKETS),
+            transactions_rejected: Mutex::new(BTreeMap::new()),
+            compact_block_reconstructions: Mutex::new(BTreeMap::new()),
+            bytes_received: Mutex::new(BTreeMap::new()),
+            bytes_sent: Mutex::new(BTreeMap::new()),
+            handlers: [const { HandlerTimes::new() }; Message::KINDS.len()],
+        }
+    }
+
+    pub fn block_mined(&self) {
+        self.blocks_mined.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A block that was not known yet arrived from a peer
+    pub fn block_received(&self) {
+        self.blocks_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn block_orphaned(&self) {
+        self.blocks_orphaned.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A block failed the proof of work, size or transaction checks
+    pub fn block_invalid(&self) {
+        self.blocks_invalid.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// A compact block was received, whose transactions were either all in the mempool (a hit)
+    /// or not (a miss)
+    pub fn compact_block_reconstructed(&self, from_mempool: bool) {
+        let result = if from_mempool { "hit" } else { "miss" };
+        *self.compact_block_reconstructions.lock().unwrap().entry(result).or_default() += 1;
+    }
+
+    /// A block was rebuilt from a compact block `bytes` smaller than the block
+    pub fn compact_block_bytes_saved(&self, bytes: i64) {
+        self.compact_block_bytes_saved.fetch_add(bytes.max(0) as u64, Ordering::Relaxed);
+    }
+
+    /// Time between the timestamp of a block and its connection to the chain
+    pub fn block_delay(&self, millis: u128) {
+        self.block_delay.observe(millis as f64 / 1000.0);
+    }
+
+    /// A reorg disconnected `depth` blocks
+    pub fn reorg(&self, depth: usize) {
+        self.reorg_depth.observe(depth as f64);
+    }
+
+    /// The mempool refused a transaction, for the reason given by `Rejection::code`
+    pub fn transaction_rejected(&self, reason: &'static str) {
+        *self.transactions_rejected.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn bytes_received(&self, kind: &'static str, bytes: usize) {
+        *self.bytes_received.lock().unwrap().entry(kind).or_default() += bytes as u64;
+    }
+
+    pub fn bytes_sent(&self, kind: &'static str, bytes: usize) {
+        *self.bytes_sent.lock().unwrap().entry(kind).or_default() += bytes as u64;
+    }
+
+    /// A worker handled a message of the given kind in `elapsed`
+    pub fn message_handled(&self, kind: &str, elapsed: Duration) {
+        if let Some(times) = self.handler(kind) {
+            let micros = elapsed.as_micros() as u64;
+            times.calls.fetch_add(1, Ordering::Relaxed);
+            times.micros.fetch_add(micros, Ordering::Relaxed);
+            times.max_micros.fetch_max(micros, Ordering::Relaxed);
+        }
+    }
+
+    /// Number of messages of the given kind handled by the workers
+    pub fn messages_handled(&self, kind: &str) -> u64 {
+        self.handler(kind).map_or(0, |times| times.calls.load(Ordering::Relaxed))
+    }
+
+    fn handler(&self, kind: &str) -> Option<&HandlerTimes> {
+        let index = Message::KINDS.iter().position(|known| *known == kind)?;
+        Some(&self.handlers[index])
+    }
+
+    /// The metrics in the Prometheus text format, followed by `gauges` given as name, help text
+    /// and value.
+    pub fn render(&self, gauges: &[(&str, &str, u64)]) -> String {
+        let mut out = String::new();
+        let counters = [
+            ("bitcoin_blocks_mined_total", "Blocks mined by this node", &self.blocks_mined),
+            (
+                "bitcoin_blocks_received_total",
+                "New blocks received from peers",
+                &self.blocks_received,
+            ),
+            (
+                "bitcoin_blocks_orphaned_total",
+                "Blocks received before their parent",
+                &self.blocks_orphaned,
+            ),
+            (
+                "bitcoin_blocks_invalid_total",
+                "Blocks that failed validation",
+                &self.blocks_invalid,
+            ),
+            (
+                "bitcoin_compact_block_bytes_saved_total",
+                "Bytes not downloaded thanks to compact blocks",
+                &self.compact_block_bytes_saved,
+            ),
+        ];
+        for (name, help, counter) in counters.iter() {
+            header(&mut out, name, help, "counter");
+            writeln!(out, "{} {}", name, counter.load(Ordering::Relaxed)).unwrap();
+        }
+        let labelled = [
+            (
+                "bitcoin_transactions_rejected_total",
+                "Transactions refused by the mempool",
+                "reason",
+                &self.transactions_rejected,
+            ),
+            (
+                "bitcoin_compact_block_reconstructions_total",
+                "Compact blocks whose transactions were all in the mempool, or not",
+                "result",
+                &self.compact_block_reconstructions,
+            ),
+            (
+                "bitcoin_network_received_bytes_total",
+                "Bytes of messages received from peers",
+                "message",
+                &self.bytes_received,
+            ),
+            (
+                "bitcoin_network_sent_bytes_total",
+                "Bytes of messages sent to peers",
+                "message",
+                &self.bytes_sent,
+            ),
+        ];
+        for (name, help, label, values) in labelled.iter() {
+            header(&mut out, name, help, "counter");
+            for (value, count) in values.lock().unwrap().iter() {
+                writeln!(out, "{}{{{}=\"{}\"}} {}", name, label, value, count).unwrap();
+            }
+        }
+        // name, help text, type and value of the metrics of each message handler
+        type HandlerMetric = (&'static str, &'static str, &'static str, fn(&HandlerTimes) -> f64);
+        let handlers: [HandlerMetric; 3] = [
+            (
+                "bitcoin_messages_handled_total",
+                "Messages handled by the workers",
+                "counter",
+                |times| times.calls.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "bitcoin_message_handler_seconds_total",
+                "Time spent by the workers handling messages",
+                "counter",
+                |times| times.micros.load(Ordering::Relaxed) as f64 / 1e6,
+            ),
+            (
+                "bitcoin_message_handler_max_seconds",
+                "Longest time spent handling a single message",
+                "gauge",
+                |times| times.max_micros.load(Ordering::Relaxed) as f64 / 1e6,
+            ),
+        ];
+        for (name, help, kind, value) in handlers.iter() {
+            header(&mut out, name, help, kind);
+            for (message, times) in Message::KINDS.iter().zip(self.handlers.iter()) {
+                writeln!(out, "{}{{message=\"{}\"}} {}", name, message, value(times)).unwrap();
+            }
+        }
+        self.block_delay.render(
+            &mut out,
+            "bitcoin_block_propagation_delay_seconds",
+            "Time between the timestamp of a block and its connection to the chain",
+        );
+        self.reorg_depth.render(
+            &mut out,
+            "bitcoin_reorg_depth_blocks",
+            "Blocks disconnected by each reorg",
+        );
+        for (name, help, value) in gauges {
+            header(&mut out, name, help, "gauge");
+            writeln!(out, "{} {}", name, value).unwrap();
+        }
+        out
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Metrics::new()
+    }
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP {} {}", name, help).unwrap();
+    writeln!(out, "# TYPE {} {}", name, kind).unwrap();
+}
+
+/// Calls of the handler of one kind of message, and the time they took.
+struct HandlerTimes {
+    calls: AtomicU64,
+    micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl HandlerTimes {
+    const fn new() -> Self {
+        HandlerTimes {
+            calls: AtomicU64::new(0),
+            micros: AtomicU64::new(0),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+}
+
+/// A histogram with fixed buckets, which are cumulative when rendered.
+struct Histogram {
+    bounds: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+struct HistogramState {
+    /// Observations per bucket, the last one counting those above every bound
+    buckets: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(bounds: &'static [f64]) -> Self {
+        Histogram {
+            bounds,
+            state: Mutex::new(HistogramState {
+                buckets: Vec::new(),
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    fn observe(&self, value: f64) {
+        let mut state = self.state.lock().unwrap();
+        if state.buckets.is_empty() {
+            state.buckets = vec![0; self.bounds.len() + 1];
+        }
+        let bucket = self
+            .bounds
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.bounds.len());
+        state.buckets[bucket] += 1;
+        state.sum += value;
+        state.count += 1;
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        header(out, name, help, "histogram");
+        let state = self.state.lock().unwrap();
+        let mut cumulative = 0;
+        for (i, bound) in self.bounds.iter().enumerate() {
+            cumulative += state.buckets.get(i).copied().unwrap_or(0);
+            writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative).unwrap();
+        }
+        writeln!(out, "{}_bucket{{le=\"+Inf\"}} {}", name, state.count).unwrap();
+        writeln!(out, "{}_sum {}", name, state.sum).unwrap();
+        writeln!(out, "{}_count {}", name, state.count).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn renders_counters_and_histograms() {
+        let metrics = Metrics::new();
+        metrics.block_mined();
+        metrics.bytes_sent("Ping", 10);
+        metrics.bytes_sent("Ping", 5);
+        metrics.transaction_rejected("conflict");
+        metrics.compact_block_reconstructed(false);
+        metrics.compact_block_bytes_saved(100);
+        metrics.compact_block_bytes_saved(-20);
+        metrics.block_delay(300);
+        metrics.block_delay(120_000);
+        metrics.reorg(2);
+        metrics.message_handled("GetBlocks", Duration::from_millis(250));
+        metrics.message_handled("GetBlocks", Duration::from_millis(500));
+
+        let text = metrics.render(&[("bitcoin_peers", "Connected peers", 3)]);
+        let lines: Vec<&str> = text.lines().collect();
+        for expected in &[
+            "bitcoin_blocks_mined_total 1",
+            "bitcoin_blocks_orphaned_total 0",
+            "bitcoin_network_sent_bytes_total{message=\"Ping\"} 15",
+            "bitcoin_transactions_rejected_total{reason=\"conflict\"} 1",
+            "bitcoin_compact_block_reconstructions_total{result=\"miss\"} 1",
+            "bitcoin_compact_block_bytes_saved_total 100",
+            "bitcoin_messages_handled_total{message=\"GetBlocks\"} 2",
+            "bitcoin_messages_handled_total{message=\"Ping\"} 0",
+            "bitcoin_message_handler_seconds_total{message=\"GetBlocks\"} 0.75",
+            "bitcoin_message_handler_max_seconds{message=\"GetBlocks\"} 0.5",
+            "# TYPE bitcoin_block_propagation_delay_seconds histogram",
+            "bitcoin_block_propagation_delay_seconds_bucket{le=\"0.25\"} 0",
+            "bitcoin_block_propagation_delay_seconds_bucket{le=\"0.5\"} 1",
+            "bitcoin_block_propagation_delay_seconds_bucket{le=\"60\"} 1",
+            "bitcoin_block_propagation_delay_seconds_bucket{le=\"+Inf\"} 2",
+            "bitcoin_block_propagation_delay_seconds_count 2",
+            "bitcoin_reorg_depth_blocks_bucket{le=\"2\"} 1",
+            "bitcoin_reorg_depth_blocks_sum 2",
+            "# TYPE bitcoin_peers gauge",
+            "bitcoin_peers 3",
+        ] {
+            assert!(lines.contains(expected), "missing {}", expected);
+        }
+    }
+}
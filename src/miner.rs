@@ -1,91 +1,243 @@
 use crate::network::server::Handle as ServerHandle;
-use crate::transaction::{self,Transaction, SignTransaction};
+use crate::transaction::{self, SignTransaction, UtxoOutput};
+use crate::crypto::address::H160;
 use crate::crypto::hash::{H256, Hashable};
 use crate::crypto::merkle::{MerkleTree};
 use crate::block::{Block, Header, Content};
 use crate::blockchain::Blockchain;
+use crate::memory_pool::TransactionMempool;
+use crate::network::compact::CompactBlock;
+use crate::network::transport::Transport;
+use crate::ledger::{self, State};
+use crate::metrics;
+use crate::txs_check;
 use rand::Rng;
-use crate::network::message::Message;
-use std::sync::{Arc, Mutex};
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
 use bincode;
 //use log::{debug, info};
 use log::{info,debug};
 
 
-use crossbeam::channel::{unbounded, Receiver, Sender, TryRecvError};
+use crossbeam::channel::{select, unbounded, Receiver, Sender};
 use std::time;
 
 use std::thread;
 
+/// Maximum number of blocks generated by a single request.
+pub const MAX_GENERATE: u64 = 1000;
+
+/// Error of the control methods once the miner thread has exited.
+const MINER_GONE: &str = "the miner is not running";
+
 enum ControlSignal {
     Start(u64), // the number controls the lambda of interval between block generation
+    Pause,
+    Resume,
+    Stop,
+    SetPayout(Option<H160>),
+    SetThreads(usize),
+    Generate(u64, H160, Sender<Result<Vec<H256>, String>>), // mine that number of blocks paying the address now
+    Status(Sender<Status>),
     Exit,
 }
 
+#[derive(Clone, Copy)]
 enum OperatingState {
-    Paused,
+    Stopped,
+    Paused(u64), // the lambda to resume with
     Run(u64),
     ShutDown,
 }
 
-pub struct Context {
+/// The state of the miner, as reported to operators.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    /// `stopped`, `paused` or `running`
+    pub state: &'static str,
+    pub lambda: Option<u64>,
+    pub threads: usize,
+    pub payout: Option<String>,
+    /// Hashes per second since mining last started
+    pub hash_rate: f64,
+    pub blocks_found: usize,
+    /// Blocks found that are no longer in the longest chain
+    pub stale_blocks: usize,
+    pub stale_rate: f64,
+    /// The block the mining threads last worked on
+    pub template: Option<Template>,
+}
+
+/// A summary of the block being mined.
+#[derive(Serialize, Debug, Clone)]
+pub struct Template {
+    pub parent: String,
+    pub height: u64,
+    pub difficulty: String,
+    pub transactions: usize,
+    /// Value created by the coinbase, none without a payout address
+    pub coinbase_value: Option<u32>,
+}
+
+/// What the mining threads report, shared with the control thread.
+#[derive(Default)]
+struct Shared {
+    /// Incremented to make the running mining threads exit
+    epoch: AtomicU64,
+    hashes: AtomicU64,
+    progress: Mutex<Progress>,
+}
+
+#[derive(Default)]
+struct Progress {
+    /// Hashes of the blocks mined here, oldest first
+    mined: Vec<H256>,
+    template: Option<Template>,
+    /// When mining last started, and the hash count then
+    started: Option<(time::Instant, u64)>,
+}
+
+/// The structures the miner works on.
+#[derive(Clone)]
+struct Node<T: Transport> {
+    server: T,
+    blockchain: Arc<RwLock<Blockchain>>,
+    tx_mempool: Arc<RwLock<TransactionMempool>>,
+}
+
+pub struct Context<T: Transport = ServerHandle> {
     /// Channel for receiving control signal
     control_chan: Receiver<ControlSignal>,
     operating_state: OperatingState,
-    server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    num_mined:u8,
+    node: Node<T>,
+    payout: Option<H160>,
+    threads: usize,
+    shared: Arc<Shared>,
+    /// Never sent on, dropped with the context so that handles see the miner is gone
+    _alive: Sender<()>,
+}
+
+/// A mining thread, which runs until the epoch changes.
+struct Worker<T: Transport> {
+    epoch: u64,
+    lambda: u64,
+    payout: Option<H160>,
+    node: Node<T>,
+    shared: Arc<Shared>,
 }
 
 #[derive(Clone)]
 pub struct Handle {
     /// Channel for sending signal to the miner thread
     control_chan: Sender<ControlSignal>,
+    /// Disconnected once the miner thread has exited
+    alive: Receiver<()>,
 }
 
-pub fn new(
-    server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>,
-) -> (Context, Handle) {
+pub fn new<T: Transport>(
+    server: &T,
+    blockchain: &Arc<RwLock<Blockchain>>,
+    tx_mempool: &Arc<RwLock<TransactionMempool>>,
+) -> (Context<T>, Handle) {
     let (signal_chan_sender, signal_chan_receiver) = unbounded();
+    let (alive_sender, alive_receiver) = unbounded();
 
     let ctx = Context {
         control_chan: signal_chan_receiver,
-        operating_state: OperatingState::Paused,
-        server: server.clone(),
-        blockchain: Arc::clone(blockchain),
-        num_mined:0,
+        operating_state: OperatingState::Stopped,
+        node: Node {
+            server: server.clone(),
+            blockchain: Arc::clone(blockchain),
+            tx_mempool: Arc::clone(tx_mempool),
+        },
+        payout: None,
+        threads: 1,
+        shared: Arc::new(Shared::default()),
+        _alive: alive_sender,
     };
 
     let handle = Handle {
         control_chan: signal_chan_sender,
+        alive: alive_receiver,
     };
 
     (ctx, handle)
 }
 
 impl Handle {
-    pub fn exit(&self) {
-        self.control_chan.send(ControlSignal::Exit).unwrap();
+    pub fn exit(&self) -> Result<(), String> {
+        self.send(ControlSignal::Exit)
     }
 
-    pub fn start(&self, lambda: u64) {
-        self.control_chan
-            .send(ControlSignal::Start(lambda))
-            .unwrap();
+    pub fn start(&self, lambda: u64) -> Result<(), String> {
+        self.send(ControlSignal::Start(lambda))
+    }
+
+    /// Suspend mining, keeping the lambda to resume with
+    pub fn pause(&self) -> Result<(), String> {
+        self.send(ControlSignal::Pause)
+    }
+
+    /// Continue mining after a pause
+    pub fn resume(&self) -> Result<(), String> {
+        self.send(ControlSignal::Resume)
+    }
+
+    /// Stop mining until the next start
+    pub fn stop(&self) -> Result<(), String> {
+        self.send(ControlSignal::Stop)
+    }
+
+    /// Set the address the blocks mined from now on pay their reward to, none to mine blocks
+    /// without a coinbase
+    pub fn set_payout(&self, payout: Option<H160>) -> Result<(), String> {
+        self.send(ControlSignal::SetPayout(payout))
     }
 
+    /// Set the number of mining threads, at least one
+    pub fn set_threads(&self, threads: usize) -> Result<(), String> {
+        self.send(ControlSignal::SetThreads(threads))
+    }
+
+    pub fn status(&self) -> Result<Status, String> {
+        let (sender, receiver) = unbounded();
+        self.send(ControlSignal::Status(sender))?;
+        self.reply(receiver)
+    }
+
+    /// Mine `n` blocks on the tip right away, paying the rewards to `payout`, and return their
+    /// hashes. This is refused unless the chain specification enables regtest, and for more than
+    /// `MAX_GENERATE` blocks.
+    pub fn generate(&self, n: u64, payout: H160) -> Result<Vec<H256>, String> {
+        let (sender, receiver) = unbounded();
+        self.send(ControlSignal::Generate(n, payout, sender))?;
+        self.reply(receiver)?
+    }
+
+    /// Send a signal to the miner thread, which fails if it has exited
+    fn send(&self, signal: ControlSignal) -> Result<(), String> {
+        self.control_chan.send(signal).map_err(|_| MINER_GONE.to_string())
+    }
+
+    /// Wait for the reply to a signal, which never comes if the miner thread exits first
+    fn reply<R>(&self, receiver: Receiver<R>) -> Result<R, String> {
+        select! {
+            recv(receiver) -> reply => reply.map_err(|_| MINER_GONE.to_string()),
+            recv(self.alive) -> _ => receiver.try_recv().map_err(|_| MINER_GONE.to_string()),
+        }
+    }
 }
 
-impl Context {
+impl<T: Transport> Context<T> {
     pub fn start(mut self) {
         thread::Builder::new()
             .name("miner".to_string())
             .spawn(move || {
-                self.miner_loop();
+                self.control_loop();
             })
             .unwrap();
-        info!("Miner initialized into paused mode");
+        info!("Miner initialized into stopped mode");
     }
 
     fn handle_control_signal(&mut self, signal: ControlSignal) {
@@ -98,87 +250,337 @@ impl Context {
                 info!("Miner starting in continuous mode with lambda {}", i);
                 self.operating_state = OperatingState::Run(i);
             }
+            ControlSignal::Pause => {
+                if let OperatingState::Run(i) = self.operating_state {
+                    info!("Miner pausing");
+                    self.operating_state = OperatingState::Paused(i);
+                }
+            }
+            ControlSignal::Resume => {
+                if let OperatingState::Paused(i) = self.operating_state {
+                    info!("Miner resuming with lambda {}", i);
+                    self.operating_state = OperatingState::Run(i);
+                }
+            }
+            ControlSignal::Stop => {
+                info!("Miner stopping");
+                self.operating_state = OperatingState::Stopped;
+            }
+            ControlSignal::SetPayout(payout) => {
+                info!("Miner paying rewards to {:?}", payout);
+                self.payout = payout;
+            }
+            ControlSignal::SetThreads(threads) => {
+                info!("Miner using {} threads", threads.max(1));
+                self.threads = threads.max(1);
+            }
+            ControlSignal::Generate(n, payout, reply) => {
+                let result = if !self.node.blockchain.read().unwrap().spec.regtest {
+                    Err("blocks can only be generated on a regtest chain".to_string())
+                } else if n > MAX_GENERATE {
+                    Err(format!("cannot generate more than {} blocks at once", MAX_GENERATE))
+                } else {
+                    info!("Miner generating {} blocks", n);
+                    Ok((0..n).map(|_| self.generate_block(payout)).collect())
+                };
+                // the caller may have given up waiting
+                let _ = reply.send(result);
+                return;
+            }
+            ControlSignal::Status(reply) => {
+                let _ = reply.send(self.status());
+                return;
+            }
         }
+        self.restart_workers();
     }
 
-    fn miner_loop(&mut self) {
-        let time_0 = time::Instant::now();
-        let mut next_time = 0.01;
-        let next_time_inc = 0.01;
+    /// Make the running mining threads exit, and start new ones if mining
+    fn restart_workers(&mut self) {
+        let epoch = self.shared.epoch.fetch_add(1, Ordering::SeqCst) + 1;
+        if let OperatingState::Run(lambda) = self.operating_state {
+            let hashes = self.shared.hashes.load(Ordering::Relaxed);
+            self.shared.progress.lock().unwrap().started = Some((time::Instant::now(), hashes));
+            for i in 0..self.threads {
+                let worker = Worker {
+                    epoch,
+                    lambda,
+                    payout: self.payout,
+                    node: self.node.clone(),
+                    shared: Arc::clone(&self.shared),
+                };
+                thread::Builder::new()
+                    .name(format!("miner-{}", i))
+                    .spawn(move || worker.mining_loop())
+                    .unwrap();
+            }
+        }
+    }
 
-        // main mining loop
-        loop {
-            // check and react to control signals
-            match self.operating_state {
-                OperatingState::Paused => {
-                    let signal = self.control_chan.recv().unwrap();
-                    self.handle_control_signal(signal);
-                    continue;
-                }
-                OperatingState::ShutDown => {
-                    return;
-                }
-                _ => match self.control_chan.try_recv() {
-                    Ok(signal) => {
-                        self.handle_control_signal(signal);
-                    }
-                    Err(TryRecvError::Empty) => {}
-                    Err(TryRecvError::Disconnected) => panic!("Miner control channel detached"),
-                },
+    fn status(&self) -> Status {
+        let (state, lambda) = match self.operating_state {
+            OperatingState::Run(i) => ("running", Some(i)),
+            OperatingState::Paused(i) => ("paused", Some(i)),
+            _ => ("stopped", None),
+        };
+        let progress = self.shared.progress.lock().unwrap();
+        let hash_rate = match progress.started {
+            Some((since, hashes)) => {
+                let hashed = self.shared.hashes.load(Ordering::Relaxed) - hashes;
+                hashed as f64 / since.elapsed().as_secs_f64().max(1e-3)
             }
+            None => 0.0,
+        };
+        let blockchain = self.node.blockchain.read().unwrap();
+        let stale_blocks = progress.mined.iter()
+            .filter(|hash| !blockchain.is_in_main_chain(hash))
+            .count();
+        let blocks_found = progress.mined.len();
+        Status {
+            state,
+            lambda,
+            threads: self.threads,
+            payout: self.payout.map(|payout| payout.to_string()),
+            hash_rate,
+            blocks_found,
+            stale_blocks,
+            stale_rate: if blocks_found == 0 { 0.0 } else { stale_blocks as f64 / blocks_found as f64 },
+            template: progress.template.clone(),
+        }
+    }
+
+    /// Mine a block on the tip paying `payout`, trying nonces until one meets the difficulty
+    fn generate_block(&mut self, payout: H160) -> H256 {
+        let (parent, difficulty, vect) = self.node.template(Some(payout)).0;
+        let timestamp = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis();
+        let new_block = (0..)
+            .map(|nonce| assemble_block(parent, difficulty, timestamp, nonce, vect.clone()))
+            .find(|block| block.hash() < difficulty)
+            .unwrap();
+        self.node.publish_block(&new_block);
+        self.shared.progress.lock().unwrap().mined.push(new_block.hash());
+        new_block.hash()
+    }
+
+    fn control_loop(&mut self) {
+        loop {
+            let signal = match self.control_chan.recv() {
+                Ok(signal) => signal,
+                Err(_) => ControlSignal::Exit,
+            };
+            self.handle_control_signal(signal);
             if let OperatingState::ShutDown = self.operating_state {
                 return;
             }
+        }
+    }
+}
+
+impl<T: Transport> Node<T> {
+    /// The parent, difficulty and transactions of a block on the tip, and its summary
+    fn template(&self, payout: Option<H160>) -> ((H256, H256, Vec<SignTransaction>), Template) {
+        let locked_blockchain = self.blockchain.read().unwrap();
+        let parent = locked_blockchain.tip();
+        let difficulty = locked_blockchain.chain[&parent].Header.difficulty;
+        let vect = block_template(&locked_blockchain, &self.tx_mempool.read().unwrap(), payout);
+        let summary = Template {
+            parent: parent.to_string(),
+            height: locked_blockchain.height(&parent).unwrap() + 1,
+            difficulty: difficulty.to_string(),
+            transactions: vect.len(),
+            coinbase_value: payout.map(|_| vect[0].transaction.tx_output[0].value),
+        };
+        ((parent, difficulty, vect), summary)
+    }
+
+    /// Insert a block mined here into the blockchain and announce it to the peers
+    fn publish_block(&self, new_block: &Block) {
+        {
+            let mut locked_blockchain = self.blockchain.write().unwrap();
+            let outcome = locked_blockchain.insert(new_block);
+            self.tx_mempool.write().unwrap().update(&outcome, &locked_blockchain);
+        }
+        metrics::global().block_mined();
+        let compact = CompactBlock::from_block(new_block, &self.tx_mempool.read().unwrap());
+        self.server.relay_compact_block(compact, None);
+    }
+}
 
-            // TODO: actual mining
-            //let mut blockchain_1 = self.blockchain.lock().unwrap();
-
-            let parent = self.blockchain.lock().unwrap().tip();
-            let timestamp = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis();
-            let difficulty = self.blockchain.lock().unwrap().chain[&parent].Header.difficulty;
-            
-            //Creating Content
-            //It will also be used for Merkel Root for the Header
-            let t = transaction::generate_random_signed_transaction();
-            let mut vect: Vec<SignTransaction> = vec![];
-            vect.push(t);
-            let content: Content = Content{content:vect};
-
-            let mut rng = rand::thread_rng();
-            let nonce: u32 = rng.gen();
-            let merkle_root = H256::from([0; 32]);
-            let header = Header{parent,nonce,difficulty,timestamp,merkleRoot:merkle_root};
-
-            let new_block = Block{Header: header,Content: content};
-        
+impl<T: Transport> Worker<T> {
+    fn mining_loop(self) {
+        let time_0 = time::Instant::now();
+        let mut rng = rand::thread_rng();
+        // transactions becoming pending, which call for a new template
+        let new_txs = self.node.tx_mempool.write().unwrap().subscribe();
+        let mut template: Option<Block> = None;
+
+        // main mining loop
+        while self.shared.epoch.load(Ordering::SeqCst) == self.epoch {
+            let tip = self.node.blockchain.read().unwrap().tip();
+            let mempool_changed = new_txs.try_iter().count() > 0;
+            let stale = !matches!(&template, Some(block) if block.Header.parent == tip);
+            if stale || mempool_changed {
+                let ((parent, difficulty, vect), summary) = self.node.template(self.payout);
+                self.shared.progress.lock().unwrap().template = Some(summary);
+                template = Some(assemble_block(parent, difficulty, 0, 0, vect));
+            }
+            let new_block = template.as_mut().unwrap();
+            new_block.Header.timestamp = time::SystemTime::now().duration_since(time::SystemTime::UNIX_EPOCH).unwrap().as_millis();
+            new_block.Header.nonce = rng.gen();
+            self.shared.hashes.fetch_add(1, Ordering::Relaxed);
           
             //Check whether block solved the puzzle
             //If passed, add it to blockchain
-            if new_block.hash() <= difficulty {
-                println!("block with hash:{} generated\n",new_block.hash());
-                //println!("Number of blocks mined until now:{}\n",self.num_mined+1);
-                self.blockchain.lock().unwrap().insert(&new_block);
+            if new_block.hash() < new_block.Header.difficulty {
+                self.node.publish_block(new_block);
+                let num_mined = {
+                    let mut progress = self.shared.progress.lock().unwrap();
+                    progress.mined.push(new_block.hash());
+                    progress.mined.len()
+                };
                 let encodedhead: Vec<u8> = bincode::serialize(&new_block).unwrap();
                 debug!("Size of block generated is {} bytes\n",encodedhead.len());
-                print!("Total number of blocks in blockchain:{}\n",self.blockchain.lock().unwrap().chain.len());
-                self.num_mined = self.num_mined +1;
-                let time_1 = time::Instant::now();
-                println!("mined {}, hash = {:?}, {:?}", self.num_mined+1, new_block.hash(), time_1.checked_duration_since(time_0));
-                let mut new_block_hash : Vec<H256> = vec![];
-                new_block_hash.push(new_block.hash());
-                self.server.broadcast(Message::NewBlockHashes(new_block_hash)); 
+                info!("Mined block {} with hash {} after {:?}", num_mined, new_block.hash(), time_0.elapsed());
             }
-            let mut length = self.blockchain.lock().unwrap().chain.keys().len();
-            println!("Number of all the blocks mined until now:{:?}\n", length);
-            
-
-            if let OperatingState::Run(i) = self.operating_state {
-                if i != 0 {
-                    let interval = time::Duration::from_micros(i as u64);
-                    thread::sleep(interval);
-                }
+
+            if self.lambda != 0 {
+                let interval = time::Duration::from_micros(self.lambda);
+                thread::sleep(interval);
             }
         }
     }
 }
 
+/// The transactions of a block on the tip of `blockchain`: if `payout` is set, a coinbase paying
+/// it the block reward and the fees, then pending transactions within the block limits
+pub fn block_template(
+    blockchain: &Blockchain,
+    mempool: &TransactionMempool,
+    payout: Option<H160>,
+) -> Vec<SignTransaction> {
+    let spec = &blockchain.spec;
+    let parent = blockchain.tip();
+    let payout = match payout {
+        Some(payout) => payout,
+        None => return select_transactions(mempool, blockchain.tip_state(), spec.max_block_transactions, spec.max_block_size),
+    };
+    let coinbase = |value| transaction::coinbase(parent, vec![UtxoOutput{recipient_address: payout, value}]);
+    let coinbase_size = bincode::serialized_size(&coinbase(0)).unwrap();
+    let txs = select_transactions(
+        mempool,
+        blockchain.tip_state(),
+        spec.max_block_transactions.saturating_sub(1),
+        spec.max_block_size.saturating_sub(coinbase_size),
+    );
+    let mut state = blockchain.tip_state().clone();
+    let mut fees: u64 = 0;
+    for signed_tx in &txs {
+        fees += txs_check::tx_fee(signed_tx, &state);
+        ledger::apply_transaction(signed_tx, &mut state);
+    }
+    let reward = spec.reward(blockchain.height(&parent).unwrap() + 1) as u64 + fees;
+    let mut content = vec![coinbase(reward.min(u32::MAX as u64) as u32)];
+    content.extend(txs);
+    content
+}
+
+/// Pick pending transactions from the mempool that are valid on top of `state`, in arrival order,
+/// up to `max_transactions` of them and `max_size` bytes in total
+pub fn select_transactions(
+    mempool: &TransactionMempool,
+    state: &State,
+    max_transactions: usize,
+    max_size: u64,
+) -> Vec<SignTransaction> {
+    let mut state = state.clone();
+    let mut selected = vec![];
+    let mut size = 0;
+    for (_, signed_tx) in mempool.pending() {
+        if selected.len() >= max_transactions {
+            break;
+        }
+        let tx_size = bincode::serialized_size(signed_tx).unwrap();
+        if size + tx_size > max_size {
+            continue;
+        }
+        if txs_check::is_tx_spendable(signed_tx, &state) {
+            size += tx_size;
+            ledger::apply_transaction(signed_tx, &mut state);
+            selected.push(signed_tx.clone());
+        }
+    }
+    selected
+}
+
+/// Build a block on top of `parent` with the given transactions
+pub fn assemble_block(
+    parent: H256,
+    difficulty: H256,
+    timestamp: u128,
+    nonce: u32,
+    content: Vec<SignTransaction>,
+) -> Block {
+    //Content is also used for Merkel Root for the Header
+    let merkle_root = MerkleTree::new(&content).root();
+    let header = Header{parent,nonce,difficulty,timestamp,merkleRoot:merkle_root};
+    Block{Header: header,Content: Content{content}}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_spec::ChainSpec;
+    use crate::crypto::address::generate_random_address;
+    use crate::network::loopback;
+
+    #[test]
+    fn control_and_status() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::from_spec(ChainSpec::regtest())));
+        let mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let (transport, _outbox) = loopback::new();
+        let (ctx, miner) = new(&transport, &blockchain, &mempool);
+        ctx.start();
+
+        let payout = generate_random_address();
+        miner.set_payout(Some(payout)).unwrap();
+        miner.set_threads(2).unwrap();
+        assert!(miner.generate(MAX_GENERATE + 1, payout).is_err());
+        let generated = miner.generate(3, payout).unwrap();
+        assert_eq!(generated.len(), 3);
+        assert_eq!(blockchain.read().unwrap().tip(), generated[2]);
+        let status = miner.status().unwrap();
+        assert_eq!((status.state, status.threads, status.blocks_found), ("stopped", 2, 3));
+        assert_eq!(status.payout, Some(payout.to_string()));
+
+        miner.start(1000).unwrap();
+        miner.pause().unwrap();
+        assert_eq!(miner.status().unwrap().state, "paused");
+        miner.resume().unwrap();
+        assert_eq!(miner.status().unwrap().lambda, Some(1000));
+        while miner.status().unwrap().template.is_none() {
+            thread::sleep(time::Duration::from_millis(1));
+        }
+        miner.stop().unwrap();
+        let status = miner.status().unwrap();
+        assert_eq!((status.state, status.lambda), ("stopped", None));
+        assert_eq!(status.template.unwrap().coinbase_value, Some(blockchain.read().unwrap().spec.initial_reward));
+        miner.exit().unwrap();
+        assert!(miner.status().is_err());
+        assert!(miner.generate(1, payout).is_err());
+    }
+
+    #[test]
+    fn generate_requires_regtest() {
+        // a trivial target alone does not allow generating blocks
+        let spec = ChainSpec { regtest: false, ..ChainSpec::regtest() };
+        let blockchain = Arc::new(RwLock::new(Blockchain::from_spec(spec)));
+        let mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let (transport, _outbox) = loopback::new();
+        let (ctx, miner) = new(&transport, &blockchain, &mempool);
+        ctx.start();
+
+        assert!(miner.generate(1, generate_random_address()).is_err());
+        assert_eq!(blockchain.read().unwrap().all_blocks_in_longest_chain().len(), 1);
+        miner.exit().unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use serde::{Serialize, Deserialize};
+use crate::block::{Block, Content, Header};
+use crate::crypto::hash::{H256, Hashable};
+use crate::crypto::merkle::MerkleTree;
+use crate::memory_pool::TransactionMempool;
+use crate::transaction::SignTransaction;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+
+/// Short transaction identifier: the first 8 bytes of the hash of a `SignTransaction`.
+pub type ShortId = u64;
+
+pub fn short_id(tx_hash: &H256) -> ShortId {
+    u64::from_be_bytes(tx_hash.as_ref()[0..8].try_into().unwrap())
+}
+
+/// A block announced as its header plus short IDs of its transactions. Transactions the sender
+/// does not expect the receiver to have (those not in the sender's mempool) are sent in full.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CompactBlock {
+    pub header: Header,
+    pub short_ids: Vec<ShortId>,
+    /// Full transactions together with their index in the block.
+    pub prefilled: Vec<(u32, SignTransaction)>,
+}
+
+impl Hashable for CompactBlock {
+    fn hash(&self) -> H256 {
+        let header_serialized = bincode::serialize(&self.header).unwrap();
+        ring::digest::digest(&ring::digest::SHA256, &header_serialized).into()
+    }
+}
+
+impl CompactBlock {
+    /// Build the compact form of a block, prefilling the transactions missing from `mempool`.
+    pub fn from_block(block: &Block, mempool: &TransactionMempool) -> Self {
+        let mut short_ids = vec![];
+        let mut prefilled = vec![];
+        for (i, tx) in block.Content.content.iter().enumerate() {
+            let tx_hash = tx.hash();
+            if mempool.tx_map.contains_key(&tx_hash) {
+                short_ids.push(short_id(&tx_hash));
+            } else {
+                prefilled.push((i as u32, tx.clone()));
+            }
+        }
+        CompactBlock {
+            header: block.Header.clone(),
+            short_ids,
+            prefilled,
+        }
+    }
+
+    pub fn tx_count(&self) -> usize {
+        self.short_ids.len() + self.prefilled.len()
+    }
+
+    /// Fill in as many transactions as possible from the prefilled list and `mempool`.
+    pub fn reconstruct(&self, mempool: &TransactionMempool) -> PartialBlock {
+        let mut txs: Vec<Option<SignTransaction>> = vec![None; self.tx_count()];
+        for (index, tx) in &self.prefilled {
+            if let Some(slot) = txs.get_mut(*index as usize) {
+                *slot = Some(tx.clone());
+            }
+        }
+
+        let by_short_id: HashMap<ShortId, &SignTransaction> = mempool
+            .tx_map
+            .iter()
+            .map(|(tx_hash, tx)| (short_id(tx_hash), tx))
+            .collect();
+        let mut short_ids = self.short_ids.iter();
+        for slot in txs.iter_mut().filter(|slot| slot.is_none()) {
+            if let Some(id) = short_ids.next() {
+                *slot = by_short_id.get(id).map(|tx| (*tx).clone());
+            }
+        }
+
+        PartialBlock {
+            header: self.header.clone(),
+            txs,
+        }
+    }
+}
+
+/// A block being reconstructed from a compact block.
+#[derive(Debug, Clone)]
+pub struct PartialBlock {
+    header: Header,
+    txs: Vec<Option<SignTransaction>>,
+}
+
+impl PartialBlock {
+    /// Indexes of the transactions that still have to be fetched with `GetBlockTxn`.
+    pub fn missing(&self) -> Vec<u32> {
+        self.txs
+            .iter()
+            .enumerate()
+            .filter(|(_, tx)| tx.is_none())
+            .map(|(i, _)| i as u32)
+            .collect()
+    }
+
+    /// Fill in missing transactions in the order of `missing()`.
+    pub fn fill(&mut self, txs: Vec<SignTransaction>) {
+        let mut txs = txs.into_iter();
+        for slot in self.txs.iter_mut().filter(|slot| slot.is_none()) {
+            match txs.next() {
+                Some(tx) => *slot = Some(tx),
+                None => break,
+            }
+        }
+    }
+
+    /// Turn the partial block into a block. Returns `None` if transactions are still missing
+    /// or if the transactions do not match the merkle root (e.g. short ID collision).
+    pub fn into_block(self) -> Option<Block> {
+        let content: Option<Vec<SignTransaction>> = self.txs.into_iter().collect();
+        let content = content?;
+        if MerkleTree::new(&content).root() != self.header.merkleRoot {
+            return None;
+        }
+        Some(Block {
+            Header: self.header,
+            Content: Content { content },
+        })
+    }
+}
+
+/// Number of bytes saved on the wire by sending `compact` instead of the full `block`.
+pub fn bytes_saved(block: &Block, compact: &CompactBlock) -> i64 {
+    let full = bincode::serialized_size(block).unwrap() as i64;
+    let compact = bincode::serialized_size(compact).unwrap() as i64;
+    full - compact
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transaction::generate_random_signed_transaction;
+
+    fn block_with(txs: Vec<SignTransaction>) -> Block {
+        let mut block = crate::block::generate_random_block_(&H256::from([0; 32]));
+        block.Header.merkleRoot = MerkleTree::new(&txs).root();
+        block.Content.content = txs;
+        block
+    }
+
+    #[test]
+    fn reconstruct_from_mempool() {
+        let txs: Vec<SignTransaction> = (0..4).map(|_| generate_random_signed_transaction()).collect();
+        let block = block_with(txs.clone());
+        let mut mempool = TransactionMempool::new();
+        for tx in &txs[1..] {
+            mempool.tx_map.insert(tx.hash(), tx.clone());
+        }
+        let compact = CompactBlock::from_block(&block, &mempool);
+        assert_eq!(compact.prefilled.len(), 1);
+        assert!(bytes_saved(&block, &compact) > 0);
+
+        let partial = compact.reconstruct(&mempool);
+        assert!(partial.missing().is_empty());
+        assert_eq!(partial.into_block().unwrap().hash(), block.hash());
+    }
+
+    #[test]
+    fn fetch_missing_transactions() {
+        let txs: Vec<SignTransaction> = (0..3).map(|_| generate_random_signed_transaction()).collect();
+        let block = block_with(txs.clone());
+        let mut mempool = TransactionMempool::new();
+        for tx in &txs {
+            mempool.tx_map.insert(tx.hash(), tx.clone());
+        }
+        let compact = CompactBlock::from_block(&block, &mempool);
+
+        // the receiver only has the first transaction
+        let mut receiver_mempool = TransactionMempool::new();
+        receiver_mempool.tx_map.insert(txs[0].hash(), txs[0].clone());
+        let mut partial = compact.reconstruct(&receiver_mempool);
+        assert_eq!(partial.missing(), vec![1, 2]);
+        partial.fill(vec![txs[1].clone(), txs[2].clone()]);
+        assert_eq!(partial.into_block().unwrap().Content.content.len(), 3);
+    }
+}
@@ -0,0 +1,90 @@
+//! A transport over in-memory queues, for running workers without sockets.
+//!
+//! Messages the worker sends to the network are queued on the `Loopback` handle and applied to
+//! a set of `LoopbackPeer`s by whoever owns the matching `Outbox`. Messages written to a peer
+//! are queued unserialized on its receiver.
+
+use super::message::Message;
+use super::transport::{KnownInventory, Outgoing, PeerSink, Transport};
+
+use crossbeam::channel;
+use log::trace;
+
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+pub fn new() -> (Loopback, Outbox) {
+    let (sender, receiver) = channel::unbounded();
+    (Loopback { outgoing: sender }, Outbox { outgoing: receiver })
+}
+
+/// The transport handle given to the worker.
+#[derive(Clone)]
+pub struct Loopback {
+    outgoing: channel::Sender<Outgoing>,
+}
+
+impl Transport for Loopback {
+    type Peer = LoopbackPeer;
+
+    fn send(&self, outgoing: Outgoing) {
+        if self.outgoing.send(outgoing).is_err() {
+            trace!("Loopback outbox dropped, discarding outgoing messages");
+        }
+    }
+}
+
+/// The messages sent to the network through a `Loopback` that have not been delivered yet.
+pub struct Outbox {
+    outgoing: channel::Receiver<Outgoing>,
+}
+
+impl Outbox {
+    /// Write the pending messages to `peers`.
+    pub fn deliver<'a>(&self, peers: impl Iterator<Item = &'a LoopbackPeer> + Clone) {
+        while let Ok(outgoing) = self.outgoing.try_recv() {
+            outgoing.send_to(peers.clone());
+        }
+    }
+
+    /// Drop the pending messages.
+    pub fn discard(&self) {
+        while self.outgoing.try_recv().is_ok() {}
+    }
+}
+
+/// A peer whose messages are queued in memory.
+#[derive(Clone)]
+pub struct LoopbackPeer {
+    addr: SocketAddr,
+    outbox: channel::Sender<Message>,
+    known: Arc<Mutex<KnownInventory>>,
+}
+
+/// Create a peer at the given address. Messages written to it can be read from the returned
+/// receiver.
+pub fn peer(addr: SocketAddr) -> (LoopbackPeer, channel::Receiver<Message>) {
+    let (sender, receiver) = channel::unbounded();
+    let peer = LoopbackPeer {
+        addr,
+        outbox: sender,
+        known: Arc::new(Mutex::new(KnownInventory::new())),
+    };
+    (peer, receiver)
+}
+
+impl PeerSink for LoopbackPeer {
+    fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    fn write(&self, msg: Message) {
+        if self.outbox.send(msg).is_err() {
+            trace!("Loopback peer {} dropped, discarding message", self.addr);
+        }
+    }
+
+    fn known_inventory(&self) -> &Arc<Mutex<KnownInventory>> {
+        &self.known
+    }
+}
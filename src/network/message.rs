@@ -1,5 +1,6 @@
 use serde::{Serialize, Deserialize};
 use crate::block::Block;
+use super::compact::CompactBlock;
 use crate::crypto::hash::H256;
 use crate::transaction::{SignTransaction};
 
@@ -13,4 +14,42 @@ pub enum Message {
     NewTransactionHashes(Vec<H256>),
     GetTransactions(Vec<H256>),
     Transactions(Vec<SignTransaction>),
+    CompactBlock(CompactBlock),
+    /// Request the transactions at the given indexes of a block
+    GetBlockTxn(H256, Vec<u32>),
+    BlockTxn(H256, Vec<SignTransaction>),
+}
+
+impl Message {
+    /// Names of the variants, as returned by `kind`.
+    pub const KINDS: [&'static str; 11] = [
+        "Ping",
+        "Pong",
+        "NewBlockHashes",
+        "GetBlocks",
+        "Blocks",
+        "NewTransactionHashes",
+        "GetTransactions",
+        "Transactions",
+        "CompactBlock",
+        "GetBlockTxn",
+        "BlockTxn",
+    ];
+
+    /// Name of the variant, used to label per-message-kind metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Message::Ping(_) => "Ping",
+            Message::Pong(_) => "Pong",
+            Message::NewBlockHashes(_) => "NewBlockHashes",
+            Message::GetBlocks(_) => "GetBlocks",
+            Message::Blocks(_) => "Blocks",
+            Message::NewTransactionHashes(_) => "NewTransactionHashes",
+            Message::GetTransactions(_) => "GetTransactions",
+            Message::Transactions(_) => "Transactions",
+            Message::CompactBlock(_) => "CompactBlock",
+            Message::GetBlockTxn(_, _) => "GetBlockTxn",
+            Message::BlockTxn(_, _) => "BlockTxn",
+        }
+    }
 }
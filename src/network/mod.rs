@@ -1,4 +1,8 @@
+pub mod compact;
+pub mod loopback;
 pub mod message;
 pub mod peer;
+pub mod replay;
 pub mod server;
+pub mod transport;
 pub mod worker;
@@ -1,10 +1,21 @@
 use super::message;
+use super::transport::{KnownInventory, PeerSink};
+use crate::crypto::hash::H256;
+use crate::metrics;
+use crossbeam::channel as cbchannel;
 use log::{trace, warn};
 use mio;
 use mio_extras::channel;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::convert::TryInto;
 use std::io::{Read, Write};
-use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 
 enum DecodeState {
     Length,
@@ -155,9 +166,133 @@ impl WriteContext {
     }
 }
 
+/// Artificial network conditions applied to the messages we send to a peer, used to
+/// experiment with propagation delay on a single machine.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq)]
+pub struct LinkConditions {
+    /// Fixed delay added to every message, in milliseconds.
+    pub delay_ms: u64,
+    /// Maximum random delay added on top of `delay_ms`, in milliseconds. Messages may be
+    /// reordered when this is non-zero.
+    pub jitter_ms: u64,
+    /// Outgoing bandwidth cap in bytes per second, 0 for unlimited.
+    pub bandwidth: u64,
+    /// Probability of dropping a message, between 0 and 1.
+    pub loss: f64,
+}
+
+impl LinkConditions {
+    fn is_ideal(&self) -> bool {
+        *self == LinkConditions::default()
+    }
+}
+
+/// Delays, throttles and drops outgoing messages according to the link conditions of the peer
+/// before handing them to its write queue.
+struct Shaper {
+    input: cbchannel::Receiver<Vec<u8>>,
+    output: channel::Sender<Vec<u8>>,
+    conditions: Arc<RwLock<LinkConditions>>,
+    queue: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>>,
+    next_seq: u64,
+    link_free_at: Instant,
+    addr: std::net::SocketAddr,
+}
+
+impl Shaper {
+    fn run(mut self) {
+        loop {
+            let received = match self.queue.peek() {
+                None => self
+                    .input
+                    .recv()
+                    .map_err(|_| cbchannel::RecvTimeoutError::Disconnected),
+                Some(Reverse((release, _, _))) => {
+                    let now = Instant::now();
+                    if *release > now {
+                        self.input.recv_timeout(*release - now)
+                    } else {
+                        Err(cbchannel::RecvTimeoutError::Timeout)
+                    }
+                }
+            };
+            match received {
+                Ok(msg) => self.schedule(msg),
+                Err(cbchannel::RecvTimeoutError::Timeout) => {}
+                Err(cbchannel::RecvTimeoutError::Disconnected) => {
+                    // deliver what is still in flight, then stop
+                    while let Some(Reverse((release, _, _))) = self.queue.peek() {
+                        let now = Instant::now();
+                        if *release > now {
+                            thread::sleep(*release - now);
+                        }
+                        if !self.release_due() {
+                            break;
+                        }
+                    }
+                    return;
+                }
+            }
+            if !self.release_due() {
+                trace!("Write queue of peer {} closed, stopping shaper", self.addr);
+                return;
+            }
+        }
+    }
+
+    fn schedule(&mut self, msg: Vec<u8>) {
+        let conditions = *self.conditions.read().unwrap();
+        let now = Instant::now();
+        if conditions.is_ideal() {
+            self.push(now, msg);
+            return;
+        }
+        let mut rng = rand::thread_rng();
+        if conditions.loss > 0.0 && rng.gen::<f64>() < conditions.loss {
+            trace!("Dropping {} byte message to peer {}", msg.len(), self.addr);
+            return;
+        }
+        let mut release = now;
+        if let Some(transmit) = (msg.len() as u64 * 1_000_000).checked_div(conditions.bandwidth) {
+            let start = std::cmp::max(now, self.link_free_at);
+            self.link_free_at = start + Duration::from_micros(transmit);
+            release = self.link_free_at;
+        }
+        let jitter = if conditions.jitter_ms > 0 {
+            rng.gen_range(0, conditions.jitter_ms + 1)
+        } else {
+            0
+        };
+        release += Duration::from_millis(conditions.delay_ms + jitter);
+        self.push(release, msg);
+    }
+
+    fn push(&mut self, release: Instant, msg: Vec<u8>) {
+        self.queue.push(Reverse((release, self.next_seq, msg)));
+        self.next_seq += 1;
+    }
+
+    /// Forward the messages whose release time has passed. Returns false if the write queue
+    /// is gone.
+    fn release_due(&mut self) -> bool {
+        let now = Instant::now();
+        while let Some(Reverse((release, _, _))) = self.queue.peek() {
+            if *release > now {
+                break;
+            }
+            let Reverse((_, _, msg)) = self.queue.pop().unwrap();
+            if self.output.send(msg).is_err() {
+                return false;
+            }
+        }
+        true
+    }
+}
+
 pub fn new(
     stream: mio::net::TcpStream,
     direction: Direction,
+    conditions: LinkConditions,
 ) -> std::io::Result<(Context, Handle)> {
     let reader_stream = stream.try_clone()?;
     let writer_stream = stream.try_clone()?;
@@ -181,9 +316,26 @@ pub fn new(
         written_length: 0,
         state: WriteState::Payload,
     };
+    let (shaper_sender, shaper_receiver) = cbchannel::unbounded();
+    let conditions = Arc::new(RwLock::new(conditions));
+    let shaper = Shaper {
+        input: shaper_receiver,
+        output: write_sender,
+        conditions: Arc::clone(&conditions),
+        queue: BinaryHeap::new(),
+        next_seq: 0,
+        link_free_at: Instant::now(),
+        addr,
+    };
+    thread::Builder::new()
+        .name(format!("shaper-{}", addr))
+        .spawn(move || shaper.run())?;
     let handle = Handle {
-        write_queue: write_sender,
+        write_queue: shaper_sender,
         addr,
+        known: Arc::new(Mutex::new(KnownInventory::new())),
+        conditions,
+        traffic: Arc::new(Traffic::new()),
     };
     let ctx = Context {
         addr,
@@ -196,7 +348,7 @@ pub fn new(
     Ok((ctx, handle))
 }
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Direction {
     Incoming,
     Outgoing,
@@ -214,15 +366,171 @@ pub struct Context {
 #[derive(Clone)]
 pub struct Handle {
     addr: std::net::SocketAddr,
-    write_queue: channel::Sender<Vec<u8>>,
+    write_queue: cbchannel::Sender<Vec<u8>>,
+    known: Arc<Mutex<KnownInventory>>,
+    conditions: Arc<RwLock<LinkConditions>>,
+    traffic: Arc<Traffic>,
+}
+
+/// What was exchanged with a peer since it connected, shared by its handles.
+struct Traffic {
+    connected_since: SystemTime,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+    messages_sent: AtomicU64,
+    messages_received: AtomicU64,
+    ping: Mutex<PingState>,
+    announced_tip: Mutex<Option<H256>>,
+}
+
+#[derive(Default)]
+struct PingState {
+    /// Nonce and sending time of the ping waiting for its pong
+    pending: Option<(String, Instant)>,
+    /// Round trip time of the last answered ping
+    latency: Option<Duration>,
+}
+
+impl Traffic {
+    fn new() -> Self {
+        Traffic {
+            connected_since: SystemTime::now(),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            messages_sent: AtomicU64::new(0),
+            messages_received: AtomicU64::new(0),
+            ping: Mutex::new(PingState::default()),
+            announced_tip: Mutex::new(None),
+        }
+    }
+}
+
+/// A snapshot of the traffic and latency of a peer.
+#[derive(Clone, Debug)]
+pub struct PeerStats {
+    pub connected_since: SystemTime,
+    /// Round trip time of the last answered ping, none if no ping was answered yet
+    pub latency: Option<Duration>,
+    /// The latest block the peer announced, which is its tip right after it connects
+    pub announced_tip: Option<H256>,
+    pub bytes_sent: u64,
+    pub bytes_received: u64,
+    pub messages_sent: u64,
+    pub messages_received: u64,
 }
 
 impl Handle {
-    pub fn write(&self, msg: message::Message) {
+    pub fn stats(&self) -> PeerStats {
+        let traffic = &self.traffic;
+        PeerStats {
+            connected_since: traffic.connected_since,
+            latency: traffic.ping.lock().unwrap().latency,
+            announced_tip: *traffic.announced_tip.lock().unwrap(),
+            bytes_sent: traffic.bytes_sent.load(Ordering::Relaxed),
+            bytes_received: traffic.bytes_received.load(Ordering::Relaxed),
+            messages_sent: traffic.messages_sent.load(Ordering::Relaxed),
+            messages_received: traffic.messages_received.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Send a ping whose pong measures the latency of the peer. A ping still waiting for its
+    /// pong is forgotten.
+    pub fn ping(&self) {
+        let nonce = format!("{:016x}", rand::thread_rng().gen::<u64>());
+        self.traffic.ping.lock().unwrap().pending = Some((nonce.clone(), Instant::now()));
+        self.write(message::Message::Ping(nonce));
+    }
+
+    /// Account for a message of `bytes` bytes received from the peer.
+    pub fn record_received(&self, bytes: usize) {
+        self.traffic.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        self.traffic.messages_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn link_conditions(&self) -> LinkConditions {
+        *self.conditions.read().unwrap()
+    }
+
+    pub fn set_link_conditions(&self, conditions: LinkConditions) {
+        *self.conditions.write().unwrap() = conditions;
+    }
+}
+
+impl PeerSink for Handle {
+    fn addr(&self) -> std::net::SocketAddr {
+        self.addr
+    }
+
+    fn known_inventory(&self) -> &Arc<Mutex<KnownInventory>> {
+        &self.known
+    }
+
+    fn record_pong(&self, nonce: &str) {
+        let mut ping = self.traffic.ping.lock().unwrap();
+        let answered = match &ping.pending {
+            Some((pending, _)) => pending == nonce,
+            None => false,
+        };
+        if answered {
+            let (_, sent) = ping.pending.take().unwrap();
+            ping.latency = Some(sent.elapsed());
+        }
+    }
+
+    fn record_announced_tip(&self, hash: H256) {
+        *self.traffic.announced_tip.lock().unwrap() = Some(hash);
+    }
+
+    fn write(&self, msg: message::Message) {
         // TODO: return result
         let buffer = bincode::serialize(&msg).unwrap();
+        metrics::global().bytes_sent(msg.kind(), buffer.len());
+        self.traffic.bytes_sent.fetch_add(buffer.len() as u64, Ordering::Relaxed);
+        self.traffic.messages_sent.fetch_add(1, Ordering::Relaxed);
         if self.write_queue.send(buffer).is_err() {
             warn!("Failed to send write request for peer {}, channel detached", self.addr);
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shaper(conditions: LinkConditions) -> (Shaper, channel::Receiver<Vec<u8>>) {
+        let (_input_sender, input) = cbchannel::unbounded();
+        let (output, output_receiver) = channel::channel();
+        let shaper = Shaper {
+            input,
+            output,
+            conditions: Arc::new(RwLock::new(conditions)),
+            queue: BinaryHeap::new(),
+            next_seq: 0,
+            link_free_at: Instant::now(),
+            addr: "127.0.0.1:6000".parse().unwrap(),
+        };
+        (shaper, output_receiver)
+    }
+
+    #[test]
+    fn shaper_delays_and_drops() {
+        let (mut lossy, lossy_output) = shaper(LinkConditions {
+            loss: 1.0,
+            ..Default::default()
+        });
+        lossy.schedule(vec![1, 2, 3]);
+        assert!(lossy.release_due());
+        assert!(lossy_output.try_recv().is_err());
+
+        let (mut slow, slow_output) = shaper(LinkConditions {
+            delay_ms: 20,
+            ..Default::default()
+        });
+        slow.schedule(vec![1, 2, 3]);
+        assert!(slow.release_due());
+        assert!(slow_output.try_recv().is_err());
+        thread::sleep(Duration::from_millis(25));
+        assert!(slow.release_due());
+        assert_eq!(slow_output.try_recv().unwrap(), vec![1, 2, 3]);
+    }
+}
@@ -0,0 +1,153 @@
+//! Recording of the messages received from peers, and replay of a recording against a worker.
+//!
+//! A recording is a sequence of bincode-encoded `Record`s, in the order the messages were handed
+//! to the workers.
+
+use super::loopback::{self, LoopbackPeer};
+use super::message::Message;
+use super::transport::PeerSink;
+use super::worker;
+
+use crossbeam::channel;
+use log::{debug, error, warn};
+use serde::{Deserialize, Serialize};
+
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::thread;
+
+/// A message as received from a peer.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Record {
+    pub addr: SocketAddr,
+    pub msg: Vec<u8>,
+}
+
+/// Forward the messages from `input` to `output`, appending each of them to `file`.
+pub fn record<P: PeerSink>(
+    input: channel::Receiver<(Vec<u8>, P)>,
+    output: channel::Sender<(Vec<u8>, P)>,
+    file: File,
+) -> std::io::Result<()> {
+    let mut writer = BufWriter::new(file);
+    thread::Builder::new()
+        .name("recorder".to_string())
+        .spawn(move || {
+            for (msg, peer) in input.iter() {
+                let record = Record {
+                    addr: peer.addr(),
+                    msg,
+                };
+                let written = bincode::serialize_into(&mut writer, &record)
+                    .map_err(|e| e.to_string())
+                    .and_then(|_| writer.flush().map_err(|e| e.to_string()));
+                if let Err(e) = written {
+                    error!("Error recording message from {}: {}", record.addr, e);
+                }
+                if output.send((record.msg, peer)).is_err() {
+                    warn!("Worker message channel detached, stopping recorder");
+                    return;
+                }
+            }
+        })?;
+    Ok(())
+}
+
+/// Read every record of a recording.
+pub fn read(path: &Path) -> std::io::Result<Vec<Record>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut records = vec![];
+    loop {
+        match bincode::deserialize_from(&mut reader) {
+            Ok(record) => records.push(record),
+            Err(e) => match *e {
+                bincode::ErrorKind::Io(ref io) if io.kind() == std::io::ErrorKind::UnexpectedEof => {
+                    return Ok(records);
+                }
+                _ => {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        e.to_string(),
+                    ))
+                }
+            },
+        }
+    }
+}
+
+/// Feed the records to a worker in order, as if they came from peers at the recorded addresses.
+/// What the worker sends back is discarded. Returns the number of messages processed.
+pub fn replay(
+    records: Vec<Record>,
+    worker: &worker::Context<loopback::Loopback>,
+    outbox: &loopback::Outbox,
+) -> usize {
+    let mut peers: HashMap<SocketAddr, LoopbackPeer> = HashMap::new();
+    let mut processed = 0;
+    for record in records {
+        let msg: Message = match bincode::deserialize(&record.msg) {
+            Ok(msg) => msg,
+            Err(e) => {
+                debug!("Skipping undecodable message from {}: {}", record.addr, e);
+                continue;
+            }
+        };
+        let peer = peers
+            .entry(record.addr)
+            .or_insert_with(|| loopback::peer(record.addr).0);
+        worker.handle_message(msg, peer);
+        outbox.discard();
+        processed += 1;
+    }
+    processed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain::Blockchain;
+    use crate::crypto::hash::Hashable;
+    use crate::memory_pool::TransactionMempool;
+    use crate::miner;
+    use std::sync::{Arc, RwLock};
+
+    #[test]
+    fn record_and_replay() {
+        let blockchain = Blockchain::new();
+        let parent = blockchain.tip();
+        let difficulty = blockchain.chain[&parent].Header.difficulty;
+        let block = (0..)
+            .map(|nonce| miner::assemble_block(parent, difficulty, 0, nonce, vec![]))
+            .find(|block| block.hash() < difficulty)
+            .unwrap();
+
+        let path = std::env::temp_dir().join(format!("replay-test-{}", std::process::id()));
+        let (input_sender, input) = channel::unbounded();
+        let (output, output_receiver) = channel::unbounded();
+        record::<LoopbackPeer>(input, output, File::create(&path).unwrap()).unwrap();
+        let addr: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let (sender, _) = loopback::peer(addr);
+        let msg = bincode::serialize(&Message::Blocks(vec![block.clone()])).unwrap();
+        input_sender.send((msg.clone(), sender)).unwrap();
+        drop(input_sender);
+        // the recorder forwards messages once they are written
+        assert_eq!(output_receiver.recv().unwrap().0, msg);
+        assert!(output_receiver.recv().is_err());
+
+        let records = read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].addr, addr);
+
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let (transport, outbox) = loopback::new();
+        let (_, msg_rx) = channel::unbounded::<(Vec<u8>, LoopbackPeer)>();
+        let worker = worker::new(1, msg_rx, &transport, &blockchain, &mempool);
+        assert_eq!(replay(records, &worker, &outbox), 1);
+        assert_eq!(blockchain.read().unwrap().tip(), block.hash());
+    }
+}
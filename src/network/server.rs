@@ -1,9 +1,10 @@
-use super::message;
 use super::peer::{self, ReadResult, WriteResult};
+use super::transport::{Outgoing, Transport};
 use crossbeam::channel as cbchannel;
 use log::{debug, error, info, trace, warn};
 use mio::{self, net};
 use mio_extras::channel;
+use std::collections::HashSet;
 use std::sync::mpsc;
 use std::thread;
 
@@ -25,11 +26,35 @@ pub fn new(
         poll: mio::Poll::new()?,
         control_chan: control_signal_receiver,
         new_msg_chan: msg_sink,
+        event_subscribers: vec![],
+        banned: HashSet::new(),
+        link_conditions: peer::LinkConditions::default(),
+        running: true,
         _handle: handle.clone(),
     };
     Ok((ctx, handle))
 }
 
+/// Lifecycle events of the peers managed by the server.
+#[derive(Clone)]
+pub enum PeerEvent {
+    /// A connection was established and registered in the event loop.
+    Connected(peer::Handle, peer::Direction),
+    /// A peer was removed, either because it dropped the connection, because of an I/O error,
+    /// or because we disconnected it.
+    Disconnected(std::net::SocketAddr),
+    /// An address was banned. Its peers are disconnected and new connections are refused.
+    Banned(std::net::IpAddr),
+}
+
+/// A connected peer, as listed by `Handle::peers`.
+#[derive(Clone, Debug)]
+pub struct PeerInfo {
+    pub addr: std::net::SocketAddr,
+    pub direction: peer::Direction,
+    pub stats: peer::PeerStats,
+}
+
 pub struct Context {
     peers: slab::Slab<peer::Context>,
     peer_list: Vec<usize>,
@@ -37,6 +62,10 @@ pub struct Context {
     poll: mio::Poll,
     control_chan: channel::Receiver<ControlSignal>,
     new_msg_chan: cbchannel::Sender<(Vec<u8>, peer::Handle)>,
+    event_subscribers: Vec<cbchannel::Sender<PeerEvent>>,
+    banned: HashSet<std::net::IpAddr>,
+    link_conditions: peer::LinkConditions,
+    running: bool,
     _handle: Handle,
 }
 
@@ -79,7 +108,7 @@ impl Context {
             mio::Ready::readable(),
             mio::PollOpt::edge(),
         )?;
-        let (ctx, handle) = peer::new(stream, direction)?;
+        let (ctx, handle) = peer::new(stream, direction, self.link_conditions)?;
 
         // register the writer queue
         self.poll.register(
@@ -94,11 +123,46 @@ impl Context {
         // record the key of this peer
         self.peer_list.push(key);
         trace!("Registering peer with event token={}", key);
+        handle.ping();
+        self.notify(PeerEvent::Connected(handle.clone(), direction));
         Ok(handle)
     }
 
+    /// Deregister a peer from the event loop, close its socket and free its slot.
+    fn disconnect(&mut self, peer_id: usize) {
+        if !self.peers.contains(peer_id) {
+            return;
+        }
+        let peer = self.peers.remove(peer_id);
+        if let Err(e) = self.poll.deregister(&peer.stream) {
+            trace!("Error deregistering socket of peer {}: {}", peer.addr, e);
+        }
+        if let Err(e) = self.poll.deregister(&peer.writer.queue) {
+            trace!("Error deregistering write queue of peer {}: {}", peer.addr, e);
+        }
+        if let Err(e) = peer.stream.shutdown(std::net::Shutdown::Both) {
+            trace!("Error shutting down socket of peer {}: {}", peer.addr, e);
+        }
+        if let Some(index) = self.peer_list.iter().position(|&x| x == peer_id) {
+            self.peer_list.swap_remove(index);
+        }
+        self.notify(PeerEvent::Disconnected(peer.addr));
+    }
+
+    /// Send a peer event to every subscriber, forgetting those that hung up.
+    fn notify(&mut self, event: PeerEvent) {
+        self.event_subscribers
+            .retain(|subscriber| subscriber.send(event.clone()).is_ok());
+    }
+
     /// Connect to a peer, and register this peer
     fn connect(&mut self, addr: &std::net::SocketAddr) -> std::io::Result<peer::Handle> {
+        if self.banned.contains(&addr.ip()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "address is banned",
+            ));
+        }
         // we need to estabilsh a stdlib tcp stream, since we need it to block
         debug!("Establishing connection to peer {}", addr);
         let stream = std::net::TcpStream::connect(addr)?;
@@ -113,6 +177,10 @@ impl Context {
         addr: std::net::SocketAddr,
     ) -> std::io::Result<()> {
         debug!("New incoming connection from {}", addr);
+        if self.banned.contains(&addr.ip()) {
+            info!("Refusing incoming connection from banned address {}", addr);
+            return Ok(());
+        }
         match self.register(stream, peer::Direction::Incoming) {
             Ok(_) => {
                 info!("Connected to incoming peer {}", addr);
@@ -129,21 +197,127 @@ impl Context {
             ControlSignal::ConnectNewPeer(req) => {
                 trace!("Processing ConnectNewPeer command");
                 let handle = self.connect(&req.addr);
-                req.result_chan.send(handle).unwrap();
+                if req.result_chan.send(handle).is_err() {
+                    debug!("Requester of connection to {} is gone", req.addr);
+                }
+            }
+            ControlSignal::Send(outgoing) => {
+                trace!("Processing Send command");
+                let peers = self.peer_list.iter().map(|peer_id| &self.peers[*peer_id].handle);
+                outgoing.send_to(peers);
             }
-            ControlSignal::BroadcastMessage(msg) => {
-                trace!("Processing BroadcastMessage command");
+            ControlSignal::Subscribe(subscriber) => {
+                trace!("Processing Subscribe command");
+                self.event_subscribers.push(subscriber);
+            }
+            ControlSignal::ListPeers(result_chan) => {
+                trace!("Processing ListPeers command");
+                let peers = self
+                    .peer_list
+                    .iter()
+                    .map(|peer_id| {
+                        let peer = &self.peers[*peer_id];
+                        PeerInfo {
+                            addr: peer.addr,
+                            direction: peer.direction,
+                            stats: peer.handle.stats(),
+                        }
+                    })
+                    .collect();
+                if result_chan.send(peers).is_err() {
+                    debug!("Requester of the peer list is gone");
+                }
+            }
+            ControlSignal::Disconnect(addr, result_chan) => {
+                trace!("Processing Disconnect command");
+                let peer_id = self
+                    .peer_list
+                    .iter()
+                    .copied()
+                    .find(|peer_id| self.peers[*peer_id].addr == addr);
+                if let Some(peer_id) = peer_id {
+                    info!("Disconnecting peer {}", addr);
+                    self.disconnect(peer_id);
+                }
+                if result_chan.send(peer_id.is_some()).is_err() {
+                    debug!("Requester of disconnection of {} is gone", addr);
+                }
+            }
+            ControlSignal::Unban(ip, result_chan) => {
+                trace!("Processing Unban command");
+                let unbanned = self.banned.remove(&ip);
+                if unbanned {
+                    info!("Unbanning address {}", ip);
+                }
+                if result_chan.send(unbanned).is_err() {
+                    debug!("Requester of unban of {} is gone", ip);
+                }
+            }
+            ControlSignal::ListBanned(result_chan) => {
+                trace!("Processing ListBanned command");
+                let mut banned: Vec<_> = self.banned.iter().copied().collect();
+                banned.sort();
+                if result_chan.send(banned).is_err() {
+                    debug!("Requester of the banned addresses is gone");
+                }
+            }
+            ControlSignal::PingPeers => {
+                trace!("Processing PingPeers command");
                 for peer_id in &self.peer_list {
-                    self.peers[*peer_id].handle.write(msg.clone());
+                    self.peers[*peer_id].handle.ping();
                 }
             }
+            ControlSignal::Ban(ip) => {
+                trace!("Processing Ban command");
+                info!("Banning address {}", ip);
+                self.banned.insert(ip);
+                let to_disconnect: Vec<usize> = self
+                    .peer_list
+                    .iter()
+                    .copied()
+                    .filter(|peer_id| self.peers[*peer_id].addr.ip() == ip)
+                    .collect();
+                for peer_id in to_disconnect {
+                    self.disconnect(peer_id);
+                }
+                self.notify(PeerEvent::Banned(ip));
+            }
+            ControlSignal::SetLinkConditions(conditions, target) => {
+                trace!("Processing SetLinkConditions command");
+                if target.is_none() {
+                    self.link_conditions = conditions;
+                }
+                for peer_id in &self.peer_list {
+                    let peer = &self.peers[*peer_id];
+                    if target.is_none() || target == Some(peer.addr) {
+                        peer.handle.set_link_conditions(conditions);
+                    }
+                }
+            }
+            ControlSignal::Shutdown => {
+                trace!("Processing Shutdown command");
+                self.shutdown();
+            }
         }
         Ok(())
     }
 
+    /// Disconnect every peer and stop the event loop.
+    fn shutdown(&mut self) {
+        info!("P2P server shutting down, disconnecting all peers");
+        let peer_ids = self.peer_list.clone();
+        for peer_id in peer_ids {
+            self.disconnect(peer_id);
+        }
+        self.running = false;
+    }
+
     fn register_write_interest(&mut self, peer_id: usize) -> std::io::Result<()> {
         trace!("Registering socket write interest for peer {}", peer_id);
-        let peer = &mut self.peers[peer_id];
+        let peer = match self.peers.get_mut(peer_id) {
+            Some(peer) => peer,
+            None => return Ok(()),
+        };
         // we have stuff to write at the writer queue
         let socket_token = mio::Token(peer_id * 2);
         // register for writable event
@@ -159,14 +333,13 @@ impl Context {
     fn process_readable(&mut self, peer_id: usize) -> std::io::Result<()> {
         // we are using edge-triggered events, loop until block
         let peer = &mut self.peers[peer_id];
+        let mut drop_peer = false;
         loop {
             match peer.reader.read() {
                 Ok(ReadResult::EOF) => {
                     // EOF, remove it from the connections set
                     info!("Peer {} dropped connection", peer.addr);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    drop_peer = true;
                     break;
                 }
                 Ok(ReadResult::Continue) => {
@@ -177,24 +350,27 @@ impl Context {
                 Ok(ReadResult::Message(m)) => {
                     trace!("Peer {} yield message", peer_id);
                     // we just received a full message
-                    self.new_msg_chan.send((m, peer.handle.clone())).unwrap();
+                    peer.handle.record_received(m.len());
+                    if self.new_msg_chan.send((m, peer.handle.clone())).is_err() {
+                        warn!("Message channel detached, dropping message from {}", peer.addr);
+                    }
                     continue;
                 }
                 Err(e) => {
                     if e.kind() == std::io::ErrorKind::WouldBlock {
                         trace!("Peer {} finished reading", peer_id);
                         // socket is not ready anymore, stop reading
-                        break;
                     } else {
                         warn!("Error reading peer {}, disconnecting: {}", peer.addr, e);
-                        self.peers.remove(peer_id);
-                        let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                        self.peer_list.swap_remove(index);
-                        break;
+                        drop_peer = true;
                     }
+                    break;
                 }
             }
         }
+        if drop_peer {
+            self.disconnect(peer_id);
+        }
         Ok(())
     }
 
@@ -224,9 +400,7 @@ impl Context {
             Ok(WriteResult::EOF) => {
                 // EOF, remove it from the connections set
                 info!("Peer {} dropped connection", peer.addr);
-                self.peers.remove(peer_id);
-                let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                self.peer_list.swap_remove(index);
+                self.disconnect(peer_id);
             }
             Ok(WriteResult::ChanClosed) => {
                 // the channel is closed. no more writes.
@@ -246,9 +420,7 @@ impl Context {
                 // socket is not ready anymore, stop reading
                 } else {
                     warn!("Error writing peer {}, disconnecting: {}", peer.addr, e);
-                    self.peers.remove(peer_id);
-                    let index = self.peer_list.iter().position(|&x| x == peer_id).unwrap();
-                    self.peer_list.swap_remove(index);
+                    self.disconnect(peer_id);
                 }
             }
         }
@@ -283,7 +455,7 @@ impl Context {
         // initialize space for polled events
         let mut events = mio::Events::with_capacity(MAX_EVENT);
 
-        loop {
+        while self.running {
             self.poll.poll(&mut events, None)?;
 
             for event in events.iter() {
@@ -295,13 +467,16 @@ impl Context {
                             // get the new control singal from the channel
                             match self.control_chan.try_recv() {
                                 Ok(req) => {
-                                    self.process_control(req).unwrap();
+                                    if let Err(e) = self.process_control(req) {
+                                        error!("Error processing control signal: {}", e);
+                                    }
                                 }
                                 Err(e) => match e {
                                     mpsc::TryRecvError::Empty => break,
                                     mpsc::TryRecvError::Disconnected => {
                                         warn!("P2P server dropped, disconnecting all peers");
                                         self.poll.deregister(&self.control_chan)?;
+                                        self.shutdown();
                                         break;
                                     }
                                 },
@@ -316,7 +491,9 @@ impl Context {
                             // accept the connection
                             match server.accept() {
                                 Ok((stream, client_addr)) => {
-                                    self.accept(stream, client_addr).unwrap();
+                                    if let Err(e) = self.accept(stream, client_addr) {
+                                        error!("Error accepting peer {}: {}", client_addr, e);
+                                    }
                                 }
                                 Err(e) => {
                                     if e.kind() == std::io::ErrorKind::WouldBlock {
@@ -341,19 +518,28 @@ impl Context {
                                     if !self.peers.contains(peer_id) {
                                         continue;
                                     }
-                                    self.process_readable(peer_id).unwrap();
+                                    if let Err(e) = self.process_readable(peer_id) {
+                                        warn!("Error processing peer {}, disconnecting: {}", peer_id, e);
+                                        self.disconnect(peer_id);
+                                    }
                                 }
                                 if readiness.is_writable() {
                                     trace!("Peer {} writable", peer_id);
                                     if !self.peers.contains(peer_id) {
                                         continue;
                                     }
-                                    self.process_writable(peer_id).unwrap();
+                                    if let Err(e) = self.process_writable(peer_id) {
+                                        warn!("Error processing peer {}, disconnecting: {}", peer_id, e);
+                                        self.disconnect(peer_id);
+                                    }
                                 }
                             }
                             1 => {
                                 trace!("Peer {} outgoing queue readable", peer_id);
-                                self.register_write_interest(peer_id)?;
+                                if let Err(e) = self.register_write_interest(peer_id) {
+                                    warn!("Error processing peer {}, disconnecting: {}", peer_id, e);
+                                    self.disconnect(peer_id);
+                                }
                             }
                             _ => unreachable!(),
                         }
@@ -361,6 +547,8 @@ impl Context {
                 }
             }
         }
+        info!("P2P server stopped");
+        Ok(())
     }
 }
 
@@ -376,22 +564,106 @@ impl Handle {
             addr,
             result_chan: sender,
         };
+        let stopped = || std::io::Error::new(std::io::ErrorKind::NotConnected, "P2P server stopped");
         self.control_chan
             .send(ControlSignal::ConnectNewPeer(request))
-            .unwrap();
-        receiver.recv().unwrap()
+            .map_err(|_| stopped())?;
+        receiver.recv().map_err(|_| stopped())?
     }
 
-    pub fn broadcast(&self, msg: message::Message) {
-        self.control_chan
-            .send(ControlSignal::BroadcastMessage(msg))
-            .unwrap();
+    /// Subscribe to peer lifecycle events. The channel is closed when the server stops.
+    pub fn subscribe(&self) -> cbchannel::Receiver<PeerEvent> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.send_control(ControlSignal::Subscribe(sender));
+        receiver
+    }
+
+    /// The peers currently connected, empty if the server is stopped.
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.send_control(ControlSignal::ListPeers(sender));
+        receiver.recv().unwrap_or_default()
+    }
+
+    /// Disconnect all peers from the address and refuse further connections with it.
+    pub fn ban(&self, ip: std::net::IpAddr) {
+        self.send_control(ControlSignal::Ban(ip));
+    }
+
+    /// Accept connections with a banned address again. Returns whether it was banned.
+    pub fn unban(&self, ip: std::net::IpAddr) -> bool {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.send_control(ControlSignal::Unban(ip, sender));
+        receiver.recv().unwrap_or(false)
+    }
+
+    /// The banned addresses, in order.
+    pub fn banned(&self) -> Vec<std::net::IpAddr> {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.send_control(ControlSignal::ListBanned(sender));
+        receiver.recv().unwrap_or_default()
+    }
+
+    /// Disconnect the peer at the given address. Returns whether it was connected.
+    pub fn disconnect(&self, addr: std::net::SocketAddr) -> bool {
+        let (sender, receiver) = cbchannel::unbounded();
+        self.send_control(ControlSignal::Disconnect(addr, sender));
+        receiver.recv().unwrap_or(false)
+    }
+
+    /// Ping every peer to measure its latency, which peers then report in their stats.
+    pub fn ping_peers(&self) {
+        self.send_control(ControlSignal::PingPeers);
+    }
+
+    /// Apply link conditions to the peer at the given address, or to all current and future
+    /// peers if no address is given.
+    pub fn set_link_conditions(
+        &self,
+        conditions: peer::LinkConditions,
+        peer: Option<std::net::SocketAddr>,
+    ) {
+        self.send_control(ControlSignal::SetLinkConditions(conditions, peer));
+    }
+
+    /// Disconnect all peers and stop the event loop.
+    pub fn shutdown(&self) {
+        self.send_control(ControlSignal::Shutdown);
+    }
+
+    fn send_control(&self, signal: ControlSignal) {
+        if self.control_chan.send(signal).is_err() {
+            warn!("P2P server stopped, dropping control signal");
+        }
+    }
+}
+
+impl Transport for Handle {
+    type Peer = peer::Handle;
+
+    fn send(&self, outgoing: Outgoing) {
+        self.send_control(ControlSignal::Send(outgoing));
+    }
+
+    fn drop_peer(&self, addr: std::net::SocketAddr) {
+        // nobody waits for the result, so that the worker does not block on the event loop
+        let (sender, _) = cbchannel::unbounded();
+        self.send_control(ControlSignal::Disconnect(addr, sender));
     }
 }
 
 enum ControlSignal {
     ConnectNewPeer(ConnectRequest),
-    BroadcastMessage(message::Message),
+    Send(Outgoing),
+    Subscribe(cbchannel::Sender<PeerEvent>),
+    ListPeers(cbchannel::Sender<Vec<PeerInfo>>),
+    Disconnect(std::net::SocketAddr, cbchannel::Sender<bool>),
+    Unban(std::net::IpAddr, cbchannel::Sender<bool>),
+    ListBanned(cbchannel::Sender<Vec<std::net::IpAddr>>),
+    PingPeers,
+    Ban(std::net::IpAddr),
+    SetLinkConditions(peer::LinkConditions, Option<std::net::SocketAddr>),
+    Shutdown,
 }
 
 struct ConnectRequest {
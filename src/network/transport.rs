@@ -0,0 +1,250 @@
+//! The interface between the worker's protocol logic and the network carrying its messages.
+//!
+//! `Transport` is what the worker announces and broadcasts to, `PeerSink` is the peer a message
+//! came from and that replies are written to. The TCP implementation is `server::Handle` and
+//! `peer::Handle`; `loopback` runs nodes over in-memory queues and `replay` feeds recorded
+//! messages to a worker.
+
+use super::compact::CompactBlock;
+use super::message::Message;
+use crate::crypto::hash::{H256, Hashable};
+
+use std::collections::{HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+
+/// Maximum number of block hashes remembered per peer.
+const MAX_KNOWN_BLOCKS: usize = 10000;
+/// Maximum number of transaction hashes remembered per peer.
+const MAX_KNOWN_TRANSACTIONS: usize = 50000;
+
+/// A connected peer that messages can be written to.
+pub trait PeerSink: Clone + Send + 'static {
+    fn addr(&self) -> SocketAddr;
+
+    fn write(&self, msg: Message);
+
+    /// Record the answer of the peer to a ping, to measure its latency.
+    fn record_pong(&self, _nonce: &str) {}
+
+    /// Record the latest block the peer announced, which is its tip right after it connects.
+    fn record_announced_tip(&self, _hash: H256) {}
+
+    /// The blocks and transactions the peer is known to have.
+    fn known_inventory(&self) -> &Arc<Mutex<KnownInventory>>;
+
+    /// Record that the peer has the given blocks.
+    fn mark_blocks_known(&self, hashes: &[H256]) {
+        let mut known = self.known_inventory().lock().unwrap();
+        for hash in hashes {
+            known.blocks.insert(*hash);
+        }
+    }
+
+    /// Record that the peer has the given transactions.
+    fn mark_transactions_known(&self, hashes: &[H256]) {
+        let mut known = self.known_inventory().lock().unwrap();
+        for hash in hashes {
+            known.transactions.insert(*hash);
+        }
+    }
+
+    fn knows_block(&self, hash: &H256) -> bool {
+        self.known_inventory().lock().unwrap().blocks.contains(hash)
+    }
+
+    fn knows_transaction(&self, hash: &H256) -> bool {
+        self.known_inventory().lock().unwrap().transactions.contains(hash)
+    }
+
+    /// Return the blocks the peer does not know yet, and mark them as known.
+    fn filter_unknown_blocks(&self, hashes: &[H256]) -> Vec<H256> {
+        let mut known = self.known_inventory().lock().unwrap();
+        hashes
+            .iter()
+            .copied()
+            .filter(|hash| known.blocks.insert(*hash))
+            .collect()
+    }
+
+    /// Return the transactions the peer does not know yet, and mark them as known.
+    fn filter_unknown_transactions(&self, hashes: &[H256]) -> Vec<H256> {
+        let mut known = self.known_inventory().lock().unwrap();
+        hashes
+            .iter()
+            .copied()
+            .filter(|hash| known.transactions.insert(*hash))
+            .collect()
+    }
+}
+
+/// The set of peers of a node.
+pub trait Transport: Clone + Send + 'static {
+    type Peer: PeerSink;
+
+    /// Send messages to the peers of the node.
+    fn send(&self, outgoing: Outgoing);
+
+    /// Disconnect the peer at the given address, for instance after it sent a malformed message.
+    fn drop_peer(&self, _addr: SocketAddr) {}
+
+    fn broadcast(&self, msg: Message) {
+        self.send(Outgoing::Broadcast(msg));
+    }
+
+    /// Send a message to every peer except the one at the given address.
+    fn broadcast_except(&self, msg: Message, except: SocketAddr) {
+        self.send(Outgoing::BroadcastExcept(msg, except));
+    }
+
+    /// Announce blocks to the peers that do not know them yet, skipping the peer they came from.
+    fn relay_blocks(&self, hashes: Vec<H256>, origin: Option<SocketAddr>) {
+        self.send(Outgoing::Relay(Inventory::Blocks(hashes), origin));
+    }
+
+    /// Announce transactions to the peers that do not know them yet, skipping the peer they
+    /// came from.
+    fn relay_transactions(&self, hashes: Vec<H256>, origin: Option<SocketAddr>) {
+        self.send(Outgoing::Relay(Inventory::Transactions(hashes), origin));
+    }
+
+    /// Push a compact block to the peers that do not know it yet, skipping the peer it came from.
+    fn relay_compact_block(&self, compact: CompactBlock, origin: Option<SocketAddr>) {
+        self.send(Outgoing::Relay(Inventory::CompactBlock(compact), origin));
+    }
+}
+
+pub enum Inventory {
+    Blocks(Vec<H256>),
+    Transactions(Vec<H256>),
+    CompactBlock(CompactBlock),
+}
+
+/// Messages to send to the peers of a node.
+pub enum Outgoing {
+    Broadcast(Message),
+    BroadcastExcept(Message, SocketAddr),
+    /// Announce the inventory to the peers that do not know it, except the one it came from.
+    Relay(Inventory, Option<SocketAddr>),
+}
+
+impl Outgoing {
+    /// Write the messages to the given peers.
+    pub fn send_to<'a, P: PeerSink>(&self, peers: impl Iterator<Item = &'a P>) {
+        for peer in peers {
+            let msg = match self {
+                Outgoing::Broadcast(msg) => msg.clone(),
+                Outgoing::BroadcastExcept(msg, except) => {
+                    if peer.addr() == *except {
+                        continue;
+                    }
+                    msg.clone()
+                }
+                Outgoing::Relay(inventory, origin) => {
+                    if Some(peer.addr()) == *origin {
+                        continue;
+                    }
+                    match inventory {
+                        Inventory::Blocks(hashes) => {
+                            let unknown = peer.filter_unknown_blocks(hashes);
+                            if unknown.is_empty() {
+                                continue;
+                            }
+                            Message::NewBlockHashes(unknown)
+                        }
+                        Inventory::Transactions(hashes) => {
+                            let unknown = peer.filter_unknown_transactions(hashes);
+                            if unknown.is_empty() {
+                                continue;
+                            }
+                            Message::NewTransactionHashes(unknown)
+                        }
+                        Inventory::CompactBlock(compact) => {
+                            if peer.filter_unknown_blocks(&[compact.hash()]).is_empty() {
+                                continue;
+                            }
+                            Message::CompactBlock(compact.clone())
+                        }
+                    }
+                }
+            };
+            peer.write(msg);
+        }
+    }
+}
+
+/// A set of hashes that forgets the oldest entries once it reaches its capacity.
+struct BoundedHashSet {
+    set: HashSet<H256>,
+    order: VecDeque<H256>,
+    capacity: usize,
+}
+
+impl BoundedHashSet {
+    fn new(capacity: usize) -> Self {
+        Self {
+            set: HashSet::new(),
+            order: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    fn contains(&self, hash: &H256) -> bool {
+        self.set.contains(hash)
+    }
+
+    /// Insert a hash, returning whether it was not already present.
+    fn insert(&mut self, hash: H256) -> bool {
+        if !self.set.insert(hash) {
+            return false;
+        }
+        self.order.push_back(hash);
+        if self.order.len() > self.capacity {
+            if let Some(oldest) = self.order.pop_front() {
+                self.set.remove(&oldest);
+            }
+        }
+        true
+    }
+}
+
+/// The blocks and transactions a peer is known to have, either because it sent or announced
+/// them to us or because we announced them to it.
+pub struct KnownInventory {
+    blocks: BoundedHashSet,
+    transactions: BoundedHashSet,
+}
+
+impl KnownInventory {
+    pub fn new() -> Self {
+        Self {
+            blocks: BoundedHashSet::new(MAX_KNOWN_BLOCKS),
+            transactions: BoundedHashSet::new(MAX_KNOWN_TRANSACTIONS),
+        }
+    }
+}
+
+impl Default for KnownInventory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BoundedHashSet;
+    use crate::crypto::hash::generate_random_hash;
+
+    #[test]
+    fn bounded_set_forgets_oldest() {
+        let mut set = BoundedHashSet::new(2);
+        let hashes: Vec<_> = (0..3).map(|_| generate_random_hash()).collect();
+        assert!(set.insert(hashes[0]));
+        assert!(!set.insert(hashes[0]));
+        assert!(set.insert(hashes[1]));
+        assert!(set.insert(hashes[2]));
+        assert!(!set.contains(&hashes[0]));
+        assert!(set.contains(&hashes[1]));
+        assert!(set.contains(&hashes[2]));
+    }
+}
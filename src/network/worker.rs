@@ -1,45 +1,67 @@
+use super::compact::{self, CompactBlock, PartialBlock};
 use super::message::Message;
-use super::peer;
-use crate::network::server::Handle as ServerHandle;
-use crate::blockchain::Blockchain;
+use super::transport::{PeerSink, Transport};
+use crate::network::server::{Handle as ServerHandle, PeerEvent};
+use crate::blockchain::{Blockchain, BlockStatus, InsertOutcome};
 use crate::block::*;
 use crate::transaction::SignTransaction;
 use crate::txs_check;
-use crate::memory_pool::TransactionMempool;
+use crate::memory_pool::{Rejection, TransactionMempool};
+use crate::metrics;
 use crate::crypto::hash::{H256, Hashable};
 
 use crossbeam::channel;
-use log::{debug, warn};
+use log::{debug, info, warn};
 
-use std::sync::{Arc, Mutex};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread;
+use std::time::{Duration, Instant};
 
+/// Maximum number of compact blocks waiting for missing transactions.
+const MAX_PENDING_BLOCKS: usize = 64;
+/// Time after which a compact block whose missing transactions did not arrive is dropped.
+const PENDING_BLOCK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A compact block waiting for the transactions requested from the peer that sent it.
+struct PendingBlock {
+    compact: CompactBlock,
+    partial: PartialBlock,
+    requested: Instant,
+}
+
+/// Processes the messages received from peers. `T` is the network the worker relays to, which
+/// is the P2P server in the client.
 #[derive(Clone)]
-pub struct Context {
-    msg_chan: channel::Receiver<(Vec<u8>, peer::Handle)>,
+pub struct Context<T: Transport = ServerHandle> {
+    msg_chan: channel::Receiver<(Vec<u8>, T::Peer)>,
     num_worker: usize,
-    server: ServerHandle,
-    blockchain: Arc<Mutex<Blockchain>>,
-    tx_mempool: Arc<Mutex<TransactionMempool>>,
+    server: T,
+    blockchain: Arc<RwLock<Blockchain>>,
+    tx_mempool: Arc<RwLock<TransactionMempool>>,
+    /// Compact blocks being reconstructed, by the peer asked for their transactions and hash
+    pending_blocks: Arc<Mutex<HashMap<(SocketAddr, H256), PendingBlock>>>,
 }
 
-pub fn new(
+pub fn new<T: Transport>(
     num_worker: usize,
-    msg_src: channel::Receiver<(Vec<u8>, peer::Handle)>,
-    server: &ServerHandle,
-    blockchain: &Arc<Mutex<Blockchain>>,
-    tx_mempool: &Arc<Mutex<TransactionMempool>>
-) -> Context {
+    msg_src: channel::Receiver<(Vec<u8>, T::Peer)>,
+    server: &T,
+    blockchain: &Arc<RwLock<Blockchain>>,
+    tx_mempool: &Arc<RwLock<TransactionMempool>>
+) -> Context<T> {
     Context {
         msg_chan: msg_src,
         num_worker,
         server: server.clone(),
         blockchain: Arc::clone(blockchain),
-        tx_mempool: Arc::clone(tx_mempool)
+        tx_mempool: Arc::clone(tx_mempool),
+        pending_blocks: Arc::new(Mutex::new(HashMap::new())),
     }
 }
 
-impl Context {
+impl Context<ServerHandle> {
     pub fn start(self) {
         let num_worker = self.num_worker;
         for i in 0..num_worker {
@@ -49,178 +71,571 @@ impl Context {
                 warn!("Worker thread {} exited", i);
             });
         }
+        let events = self.server.subscribe();
+        thread::spawn(move || {
+            self.peer_event_loop(events);
+            warn!("Peer event thread exited");
+        });
+    }
+
+    fn peer_event_loop(&self, events: channel::Receiver<PeerEvent>) {
+        for event in events.iter() {
+            match event {
+                PeerEvent::Connected(peer, direction) => {
+                    info!("Peer {} connected ({:?})", peer.addr(), direction);
+                    self.handle_connected(&peer);
+                }
+                PeerEvent::Disconnected(addr) => {
+                    info!("Peer {} disconnected", addr);
+                }
+                PeerEvent::Banned(ip) => {
+                    info!("Address {} banned", ip);
+                }
+            }
+        }
+    }
+}
+
+impl<T: Transport> Context<T> {
+    /// Finish reconstructing a compact block, falling back to fetching the full block if the
+    /// reconstructed transactions do not match the header.
+    fn process_partial_block(
+        &self,
+        blck_hash: H256,
+        partial: PartialBlock,
+        compact: &CompactBlock,
+        peer: &T::Peer,
+        blockchain: &mut Blockchain,
+        mempool: &mut TransactionMempool,
+    ) {
+        match partial.into_block() {
+            Some(blck) => {
+                let saved = compact::bytes_saved(&blck, compact);
+                debug!("Reconstructed block {} from compact block, {} bytes saved", blck_hash, saved);
+                metrics::global().compact_block_bytes_saved(saved);
+                self.process_block(blck, peer, blockchain, mempool);
+            }
+            None => {
+                debug!("Failed to reconstruct block {}, fetching it in full", blck_hash);
+                peer.write(Message::GetBlocks(vec![blck_hash]));
+            }
+        }
+    }
+
+    /// Validate a block received from `peer`, insert it and relay it to the other peers.
+    fn process_block(
+        &self,
+        blck: Block,
+        peer: &T::Peer,
+        blockchain: &mut Blockchain,
+        mempool: &mut TransactionMempool,
+    ) {
+        // the coinbase is unsigned, and checked against the reward on insertion
+        for tx in blck.Content.content.iter().filter(|tx| !tx.is_coinbase()) {
+            if !txs_check::is_tx_valid(tx) {
+                debug!("Invalid tx in received block. Ignoring that block");
+                return;
+            }
+        }
+
+        let blck_hash = blck.hash();
+        peer.mark_blocks_known(&[blck_hash]);
+        // added difficulty check in insert method
+        let (outcome, connected_orphans) = blockchain.insert_from(&blck, Some(peer.addr()));
+        // the block arrived, so transactions requested to rebuild it are not needed anymore
+        self.pending_blocks.lock().unwrap().retain(|(_, hash), _| *hash != blck_hash);
+
+        //asking the peer that sent an orphan for its first missing ancestor
+        if blockchain.status(&blck_hash) == BlockStatus::Orphan {
+            let missing = blockchain.orphans.missing_ancestor(&blck_hash);
+            debug!("Block {} is an orphan, requesting ancestor {}", blck_hash, missing);
+            peer.write(Message::GetBlocks(vec![missing]));
+        }
+
+        //relaying the block to the peers that have not seen it, once it is connected
+        let relay = !matches!(
+            outcome,
+            InsertOutcome::AlreadyKnown | InsertOutcome::Invalid | InsertOutcome::Orphan
+        );
+        if relay {
+            let compact = CompactBlock::from_block(&blck, mempool);
+            self.server.relay_compact_block(compact, Some(peer.addr()));
+        }
+        // the orphans it connected were not relayed when they arrived
+        if !connected_orphans.is_empty() {
+            self.server.relay_blocks(connected_orphans, None);
+        }
+
+        //Updating mempool
+        mempool.update(&outcome, blockchain);
     }
 
     fn worker_loop(&self) {
         loop {
             let msg = self.msg_chan.recv().unwrap();
             let (msg, peer) = msg;
-            let msg: Message = bincode::deserialize(&msg).unwrap();
-            let mut locked_blockchain = self.blockchain.lock().unwrap();
-            let mut locked_mempool = self.tx_mempool.lock().unwrap();
-            match msg {
-                Message::Ping(nonce) => {
-                    debug!("Ping: {}", nonce);
-                    peer.write(Message::Pong(nonce.to_string()));
+            let size = msg.len();
+            let msg: Message = match bincode::deserialize(&msg) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    warn!("Malformed message from {}, disconnecting it: {}", peer.addr(), e);
+                    self.server.drop_peer(peer.addr());
+                    continue;
                 }
-                Message::Pong(nonce) => {
-                    debug!("Pong: {}", nonce);
+            };
+            metrics::global().bytes_received(msg.kind(), size);
+            self.handle_message(msg, &peer);
+        }
+    }
+
+    /// Announce our tip to a newly connected peer so that it can fetch the chain from it.
+    pub fn handle_connected(&self, peer: &T::Peer) {
+        let tip = self.blockchain.read().unwrap().tip();
+        peer.mark_blocks_known(&[tip]);
+        peer.write(Message::NewBlockHashes(vec![tip]));
+    }
+
+    /// Process a message received from `peer`, dispatching it to the handler of its kind.
+    pub fn handle_message(&self, msg: Message, peer: &T::Peer) {
+        match msg {
+            Message::Ping(nonce) => self.dispatch::<handlers::Ping>(nonce, peer),
+            Message::Pong(nonce) => self.dispatch::<handlers::Pong>(nonce, peer),
+            Message::NewBlockHashes(hashes) => self.dispatch::<handlers::NewBlockHashes>(hashes, peer),
+            Message::GetBlocks(hashes) => self.dispatch::<handlers::GetBlocks>(hashes, peer),
+            Message::Blocks(blocks) => self.dispatch::<handlers::Blocks>(blocks, peer),
+            Message::CompactBlock(compact) => self.dispatch::<handlers::CompactBlock>(compact, peer),
+            Message::GetBlockTxn(blck_hash, indexes) => {
+                self.dispatch::<handlers::GetBlockTxn>((blck_hash, indexes), peer)
+            }
+            Message::BlockTxn(blck_hash, txs) => self.dispatch::<handlers::BlockTxn>((blck_hash, txs), peer),
+            Message::NewTransactionHashes(hashes) => {
+                self.dispatch::<handlers::NewTransactionHashes>(hashes, peer)
+            }
+            Message::GetTransactions(hashes) => self.dispatch::<handlers::GetTransactions>(hashes, peer),
+            Message::Transactions(txs) => self.dispatch::<handlers::Transactions>(txs, peer),
+        }
+    }
+
+    /// Run the handler `H`, recording the time it took in the metrics of its message kind.
+    fn dispatch<H: Handler<T>>(&self, payload: H::Payload, peer: &T::Peer) {
+        let start = Instant::now();
+        H::handle(self, payload, peer);
+        metrics::global().message_handled(H::KIND, start.elapsed());
+    }
+}
+
+/// The handler of the messages of one kind, `KIND` being the name of their `Message` variant and
+/// `Payload` its content.
+trait Handler<T: Transport> {
+    const KIND: &'static str;
+    type Payload;
+
+    fn handle(ctx: &Context<T>, payload: Self::Payload, peer: &T::Peer);
+}
+
+/// The handlers of the messages, one per `Message` variant and named after it. They are
+/// called by `Context::handle_message` and take the locks they need themselves, so that reads
+/// such as `GetBlocks` run concurrently.
+mod handlers {
+    use super::*;
+
+    pub struct Ping;
+
+    impl<T: Transport> Handler<T> for Ping {
+        const KIND: &'static str = "Ping";
+        type Payload = String;
+
+        fn handle(_ctx: &Context<T>, nonce: Self::Payload, peer: &T::Peer) {
+            debug!("Ping: {}", nonce);
+            peer.write(Message::Pong(nonce));
+        }
+    }
+
+    pub struct Pong;
+
+    impl<T: Transport> Handler<T> for Pong {
+        const KIND: &'static str = "Pong";
+        type Payload = String;
+
+        fn handle(_ctx: &Context<T>, nonce: Self::Payload, peer: &T::Peer) {
+            debug!("Pong: {}", nonce);
+            peer.record_pong(&nonce);
+        }
+    }
+
+    pub struct NewBlockHashes;
+
+    impl<T: Transport> Handler<T> for NewBlockHashes {
+        const KIND: &'static str = "NewBlockHashes";
+        type Payload = Vec<H256>;
+
+        fn handle(ctx: &Context<T>, vec_hashes: Self::Payload, peer: &T::Peer) {
+            debug!("Received New Block Hashes");
+            peer.mark_blocks_known(&vec_hashes);
+            if let Some(hash) = vec_hashes.last() {
+                peer.record_announced_tip(*hash);
+            }
+            let locked_blockchain = ctx.blockchain.read().unwrap();
+            let mut required_blocks: Vec<H256> = vec![];
+            for recv_hash in vec_hashes {
+                if locked_blockchain.contains(&recv_hash) {
+                    debug!("Block that hashes to {} already present", recv_hash);
+                } else {
+                    required_blocks.push(recv_hash);
                 }
-                Message::NewBlockHashes(vec_hashes) => {
-                    let mut required_blocks:Vec<H256> = vec![];
-                    debug!("Received New Block Hashes");
-
-                    for recv_hash in vec_hashes {
-                        let mut flag: bool = false;
-                        for (bhash,_) in locked_blockchain.chain.iter(){
-                            if *bhash == recv_hash{
-                                debug!("Block that hashes to {} already present", bhash);
-                                flag = true;
-                            }
-                        }
-                        for (bhash,_) in locked_blockchain.buffer.iter(){
-                            if *bhash == recv_hash {
-                                debug!("Block that hashes to {} already present", bhash);
-                                flag = true;
-                            }
-                        }
-                        if !flag {
-                            required_blocks.push(recv_hash);
-                        }
-                    }
-                    if required_blocks.len()!= 0 {
-                        debug!("Sending getBlocks Message");
-                        peer.write(Message::GetBlocks(required_blocks));
-                    }
+            }
+            drop(locked_blockchain);
+            if !required_blocks.is_empty() {
+                debug!("Sending getBlocks Message");
+                peer.write(Message::GetBlocks(required_blocks));
+            }
+        }
+    }
+
+    pub struct GetBlocks;
+
+    impl<T: Transport> Handler<T> for GetBlocks {
+        const KIND: &'static str = "GetBlocks";
+        type Payload = Vec<H256>;
 
+        fn handle(ctx: &Context<T>, vec_hashes: Self::Payload, peer: &T::Peer) {
+            debug!("Received GetBlocks");
+            let locked_blockchain = ctx.blockchain.read().unwrap();
+            let mut give_blocks: Vec<Block> = vec![];
+            for getblock_hash in vec_hashes {
+                if let Some(blck) = locked_blockchain.get_block(&getblock_hash) {
+                    debug!("Adding block with hash {} to give_blocks", getblock_hash);
+                    give_blocks.push(blck.clone());
                 }
-                Message::GetBlocks(vec_hashes) => {
-                    let mut give_blocks:Vec<Block> = vec![];
-                    debug!("Received GetBlocks");
-                    for getblock_hash in vec_hashes {
-                        for (bhash,blck) in locked_blockchain.chain.iter(){
-                            if *bhash == getblock_hash{
-                                debug!("Adding block with hash {} to give_blocks", bhash);
-                                give_blocks.push(blck.clone());
-                            }
-                        }
-                        for (bhash,blck) in locked_blockchain.buffer.iter(){
-                            if *bhash == getblock_hash {
-                                debug!("Adding block with hash {} to give_blocks", bhash);
-                                give_blocks.push(blck.clone());
-                            }
-                        }
+            }
+            drop(locked_blockchain);
+            if !give_blocks.is_empty() {
+                debug!("Sending Blocks message");
+                let given: Vec<H256> = give_blocks.iter().map(|b| b.hash()).collect();
+                peer.mark_blocks_known(&given);
+                peer.write(Message::Blocks(give_blocks));
+            }
+        }
+    }
 
-                    }
-                    if give_blocks.len()!=0 {
-                        debug!("Sending Blocks message");
-                        peer.write(Message::Blocks(give_blocks));
-                    }
+    pub struct Blocks;
+
+    impl<T: Transport> Handler<T> for Blocks {
+        const KIND: &'static str = "Blocks";
+        type Payload = Vec<Block>;
+
+        fn handle(ctx: &Context<T>, vec_blocks: Self::Payload, peer: &T::Peer) {
+            debug!("Received Blocks message");
+            let mut locked_blockchain = ctx.blockchain.write().unwrap();
+            let mut locked_mempool = ctx.tx_mempool.write().unwrap();
+            for blck in vec_blocks {
+                ctx.process_block(blck, peer, &mut locked_blockchain, &mut locked_mempool);
+            }
+        }
+    }
+
+    pub struct CompactBlock;
+
+    impl<T: Transport> Handler<T> for CompactBlock {
+        const KIND: &'static str = "CompactBlock";
+        type Payload = compact::CompactBlock;
 
+        fn handle(ctx: &Context<T>, compact: Self::Payload, peer: &T::Peer) {
+            let blck_hash = compact.hash();
+            debug!("Received CompactBlock {}", blck_hash);
+            peer.mark_blocks_known(&[blck_hash]);
+            let partial = {
+                let locked_blockchain = ctx.blockchain.read().unwrap();
+                if locked_blockchain.contains(&blck_hash) {
+                    debug!("Block that hashes to {} already present", blck_hash);
+                    return;
                 }
-                Message::Blocks(vec_blocks) => {
-                    debug!("Received Blocks message");
-                    for blck in vec_blocks {
-                      let mut blck_is_valid: bool = true;
-                      for tx in &blck.Content.content{
-                          if !txs_check::is_tx_valid(tx){
-                             blck_is_valid = false;
-                             debug!("Invalid tx in received block. Ignoring that block");
-                             break;
-                          }
-                      }
-
-                      if blck_is_valid {
-                        // added difficulty check in insert method
-                        locked_blockchain.insert(&blck);
-                        
-                        //Sending getblocks message if block is orphan
-                        let mut get_block_hash : Vec<H256> = vec![];
-                        get_block_hash.push(blck.Header.parent);
-                        if !locked_blockchain.chain.contains_key(&blck.Header.parent){
-                            self.server.broadcast(Message::GetBlocks(get_block_hash));
-                        }
-
-                        //broadcasting NewBlockHashes
-                        let mut new_block_hash : Vec<H256> = vec![];
-                        new_block_hash.push(blck.hash());
-                        self.server.broadcast(Message::NewBlockHashes(new_block_hash));
-
-                        //Updating mempool
-                        for signed_tx in &blck.Content.content {
-                          let signed_tx_hash = signed_tx.hash();
-                          match locked_mempool.tx_to_process.get(&signed_tx_hash){
-                              Some(_tx_present) => {
-                                  locked_mempool.tx_to_process.insert(signed_tx_hash, false);
-                              },
-                              None => {
-                                  locked_mempool.tx_to_process.insert(signed_tx_hash, false);
-                                  locked_mempool.tx_map.insert(signed_tx_hash, signed_tx.clone());
-                              }
-                          }
-                        }
-
-                        //Updating State
-                      
-                      }
+                compact.reconstruct(&ctx.tx_mempool.read().unwrap())
+            };
+            let missing = partial.missing();
+            metrics::global().compact_block_reconstructed(missing.is_empty());
+            if missing.is_empty() {
+                let mut locked_blockchain = ctx.blockchain.write().unwrap();
+                let mut locked_mempool = ctx.tx_mempool.write().unwrap();
+                ctx.process_partial_block(blck_hash, partial, &compact, peer, &mut locked_blockchain, &mut locked_mempool);
+                return;
+            }
+            let locked_blockchain = ctx.blockchain.read().unwrap();
+            let mut pending = ctx.pending_blocks.lock().unwrap();
+            // drop the requests that went unanswered, and those of blocks connected since then by
+            // another peer, the miner or the API
+            pending.retain(|(_, hash), block| {
+                block.requested.elapsed() < PENDING_BLOCK_TIMEOUT && !locked_blockchain.contains(hash)
+            });
+            drop(locked_blockchain);
+            if pending.len() >= MAX_PENDING_BLOCKS {
+                debug!("Too many blocks pending reconstruction, fetching {} in full", blck_hash);
+                peer.write(Message::GetBlocks(vec![blck_hash]));
+            } else {
+                debug!("Requesting {} missing transactions of block {}", missing.len(), blck_hash);
+                let block = PendingBlock { compact, partial, requested: Instant::now() };
+                pending.insert((peer.addr(), blck_hash), block);
+                peer.write(Message::GetBlockTxn(blck_hash, missing));
+            }
+        }
+    }
+
+    pub struct GetBlockTxn;
+
+    impl<T: Transport> Handler<T> for GetBlockTxn {
+        const KIND: &'static str = "GetBlockTxn";
+        type Payload = (H256, Vec<u32>);
+
+        fn handle(ctx: &Context<T>, (blck_hash, indexes): Self::Payload, peer: &T::Peer) {
+            debug!("Received GetBlockTxn for {}", blck_hash);
+            let locked_blockchain = ctx.blockchain.read().unwrap();
+            match locked_blockchain.get_block(&blck_hash) {
+                Some(blck) => {
+                    let txs: Option<Vec<SignTransaction>> = indexes.iter()
+                        .map(|i| blck.Content.content.get(*i as usize).cloned())
+                        .collect();
+                    match txs {
+                        Some(txs) => peer.write(Message::BlockTxn(blck_hash, txs)),
+                        None => debug!("GetBlockTxn index out of range for block {}", blck_hash),
                     }
                 }
-                Message::NewTransactionHashes(vec_tx_hashes) => {
-                    let mut required_txs: Vec<H256> = vec![];
-                    debug!("Received NewTransactionHashes");
-                    
-                    for recv_tx_hash in vec_tx_hashes {
-                        match locked_mempool.tx_to_process.get(&recv_tx_hash){
-                            Some(_tx_present) => debug!("tx which hashes to {} already present in mempool", 
-                                                        recv_tx_hash),
-                            None => required_txs.push(recv_tx_hash.clone())
-                        }
-                    }
+                None => debug!("Block that hashes to {} not present", blck_hash),
+            }
+        }
+    }
 
-                    if required_txs.len()!= 0 {
-                        debug!("Sending GetTransactions Message");
-                        peer.write(Message::GetTransactions(required_txs));
-                    }
+    pub struct BlockTxn;
+
+    impl<T: Transport> Handler<T> for BlockTxn {
+        const KIND: &'static str = "BlockTxn";
+        type Payload = (H256, Vec<SignTransaction>);
+
+        fn handle(ctx: &Context<T>, (blck_hash, txs): Self::Payload, peer: &T::Peer) {
+            debug!("Received BlockTxn for {}", blck_hash);
+            // only the peer that was asked for the transactions may complete the block
+            let pending = ctx.pending_blocks.lock().unwrap().remove(&(peer.addr(), blck_hash));
+            match pending {
+                Some(PendingBlock { compact, mut partial, .. }) => {
+                    partial.fill(txs);
+                    let mut locked_blockchain = ctx.blockchain.write().unwrap();
+                    let mut locked_mempool = ctx.tx_mempool.write().unwrap();
+                    ctx.process_partial_block(blck_hash, partial, &compact, peer, &mut locked_blockchain, &mut locked_mempool);
                 }
-                Message::GetTransactions(vec_tx_hashes) => {
-                    let mut txs_to_send:Vec<SignTransaction> = vec![];
-                    debug!("Received GetTransactions");
-                    
-                    for tx_hash in vec_tx_hashes {
-                        match locked_mempool.tx_map.get(&tx_hash){
-                            Some(signed_tx) => txs_to_send.push(signed_tx.clone()), 
-                            None => debug!("tx which hashes to {} not present in mempool", tx_hash)
-                        }
-                    }
-                    
-                    if txs_to_send.len()!=0 {
-                        debug!("Sending Transactions message");
-                        peer.write(Message::Transactions(txs_to_send));
-                    }
+                None => debug!("No block pending reconstruction for {} from {}", blck_hash, peer.addr()),
+            }
+        }
+    }
+
+    pub struct NewTransactionHashes;
+
+    impl<T: Transport> Handler<T> for NewTransactionHashes {
+        const KIND: &'static str = "NewTransactionHashes";
+        type Payload = Vec<H256>;
+
+        fn handle(ctx: &Context<T>, vec_tx_hashes: Self::Payload, peer: &T::Peer) {
+            debug!("Received NewTransactionHashes");
+            peer.mark_transactions_known(&vec_tx_hashes);
+            let locked_mempool = ctx.tx_mempool.read().unwrap();
+            let mut required_txs: Vec<H256> = vec![];
+            for recv_tx_hash in vec_tx_hashes {
+                match locked_mempool.tx_to_process.get(&recv_tx_hash){
+                    Some(_tx_present) => debug!("tx which hashes to {} already present in mempool",
+                                                recv_tx_hash),
+                    None => required_txs.push(recv_tx_hash)
                 }
-                Message::Transactions(vec_signed_txs) => {
-                    debug!("Received Transactions");
-                    let mut tx_hashes_to_broadcast: Vec<H256> = vec![];
-                    for signed_tx in vec_signed_txs {
-                      if txs_check::is_tx_valid(&signed_tx){
-                          let signed_tx_hash = signed_tx.hash();
-                          match locked_mempool.tx_to_process.get(&signed_tx_hash){
-                              Some(_tx_present) => debug!("tx_hash {} already present. Not adding to mempool", 
-                                                         signed_tx_hash),
-                              None => {
-                                  locked_mempool.tx_to_process.insert(signed_tx_hash, true);
-                                  locked_mempool.tx_map.insert(signed_tx_hash, signed_tx);
-                                  locked_mempool.tx_hash_queue.push_back(signed_tx_hash);
-                                  tx_hashes_to_broadcast.push(signed_tx_hash);
-                              }
-                          }
-                      }
+            }
+            drop(locked_mempool);
+            if !required_txs.is_empty() {
+                debug!("Sending GetTransactions Message");
+                peer.write(Message::GetTransactions(required_txs));
+            }
+        }
+    }
+
+    pub struct GetTransactions;
+
+    impl<T: Transport> Handler<T> for GetTransactions {
+        const KIND: &'static str = "GetTransactions";
+        type Payload = Vec<H256>;
+
+        fn handle(ctx: &Context<T>, vec_tx_hashes: Self::Payload, peer: &T::Peer) {
+            debug!("Received GetTransactions");
+            let locked_mempool = ctx.tx_mempool.read().unwrap();
+            let mut txs_to_send: Vec<SignTransaction> = vec![];
+            for tx_hash in vec_tx_hashes {
+                match locked_mempool.tx_map.get(&tx_hash){
+                    Some(signed_tx) => txs_to_send.push(signed_tx.clone()),
+                    None => debug!("tx which hashes to {} not present in mempool", tx_hash)
+                }
+            }
+            drop(locked_mempool);
+            if !txs_to_send.is_empty() {
+                debug!("Sending Transactions message");
+                let sent: Vec<H256> = txs_to_send.iter().map(|tx| tx.hash()).collect();
+                peer.mark_transactions_known(&sent);
+                peer.write(Message::Transactions(txs_to_send));
+            }
+        }
+    }
+
+    pub struct Transactions;
+
+    impl<T: Transport> Handler<T> for Transactions {
+        const KIND: &'static str = "Transactions";
+        type Payload = Vec<SignTransaction>;
+
+        fn handle(ctx: &Context<T>, vec_signed_txs: Self::Payload, peer: &T::Peer) {
+            debug!("Received Transactions");
+            let locked_blockchain = ctx.blockchain.read().unwrap();
+            let mut locked_mempool = ctx.tx_mempool.write().unwrap();
+            let mut tx_hashes_to_broadcast: Vec<H256> = vec![];
+            for signed_tx in vec_signed_txs {
+                let signed_tx_hash = signed_tx.hash();
+                match locked_mempool.admit(signed_tx, locked_blockchain.tip_state()) {
+                    Ok(_) => {
+                        peer.mark_transactions_known(&[signed_tx_hash]);
+                        tx_hashes_to_broadcast.push(signed_tx_hash);
                     }
-                    if tx_hashes_to_broadcast.len() != 0{
-                      self.server.broadcast(Message::NewTransactionHashes(tx_hashes_to_broadcast));
+                    Err(Rejection::Coinbase) | Err(Rejection::InvalidSignature) => {}
+                    Err(rejection) => {
+                        peer.mark_transactions_known(&[signed_tx_hash]);
+                        debug!("tx_hash {} not added to mempool: {}", signed_tx_hash, rejection);
                     }
                 }
             }
+            if !tx_hashes_to_broadcast.is_empty() {
+                ctx.server.relay_transactions(tx_hashes_to_broadcast, Some(peer.addr()));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::network::loopback;
+
+    #[test]
+    fn records_handler_metrics() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new()));
+        let mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let (transport, _outbox) = loopback::new();
+        let (_, msg_rx) = channel::unbounded();
+        let worker = new(1, msg_rx, &transport, &blockchain, &mempool);
+        let (peer, replies) = loopback::peer("127.0.0.1:6001".parse().unwrap());
+
+        // read-only handlers do not wait for a writer of the other structure
+        let _locked_mempool = mempool.write().unwrap();
+        let pings = metrics::global().messages_handled("Ping");
+        let get_blocks = metrics::global().messages_handled("GetBlocks");
+        worker.handle_message(Message::Ping("1".to_string()), &peer);
+        worker.handle_message(Message::GetBlocks(vec![blockchain.read().unwrap().tip()]), &peer);
+        assert!(matches!(replies.try_recv(), Ok(Message::Pong(_))));
+        assert!(matches!(replies.try_recv(), Ok(Message::Blocks(_))));
+
+        // other tests handle messages concurrently, so the counts may have grown by more
+        assert!(metrics::global().messages_handled("Ping") > pings);
+        assert!(metrics::global().messages_handled("GetBlocks") > get_blocks);
+    }
+
+    #[test]
+    fn accepts_coinbase_blocks() {
+        // a trivial target, so that any nonce is a valid proof of work
+        let spec = crate::chain_spec::ChainSpec {
+            initial_target: [0xff; 32].into(),
+            ..crate::chain_spec::ChainSpec::default()
+        };
+        let blockchain = Arc::new(RwLock::new(Blockchain::from_spec(spec.clone())));
+        let mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let (transport, _outbox) = loopback::new();
+        let (_, msg_rx) = channel::unbounded();
+        let worker = new(1, msg_rx, &transport, &blockchain, &mempool);
+        let (peer, _replies) = loopback::peer("127.0.0.1:6001".parse().unwrap());
+
+        let block = {
+            let blockchain = Blockchain::from_spec(spec);
+            let payout = crate::crypto::address::generate_random_address();
+            let reward = crate::transaction::UtxoOutput {
+                recipient_address: payout,
+                value: blockchain.spec.reward(1),
+            };
+            let content = vec![crate::transaction::coinbase(blockchain.tip(), vec![reward])];
+            crate::miner::assemble_block(blockchain.tip(), blockchain.spec.initial_target, 0, 0, content)
+        };
+        worker.handle_message(Message::Blocks(vec![block.clone()]), &peer);
+        assert_eq!(blockchain.read().unwrap().tip(), block.hash());
+    }
+
+    #[test]
+    fn relays_orphans_once_connected() {
+        let spec = crate::chain_spec::ChainSpec::regtest();
+        let blockchain = Arc::new(RwLock::new(Blockchain::from_spec(spec.clone())));
+        let mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let (transport, outbox) = loopback::new();
+        let (_, msg_rx) = channel::unbounded();
+        let worker = new(1, msg_rx, &transport, &blockchain, &mempool);
+        let (sender, _sender_replies) = loopback::peer("127.0.0.1:6001".parse().unwrap());
+        let (other, other_replies) = loopback::peer("127.0.0.1:6002".parse().unwrap());
+
+        // two chains of two blocks, told apart by the address their coinbases pay
+        let chain = || {
+            let mut source = Blockchain::from_spec(spec.clone());
+            let payout = crate::crypto::address::generate_random_address();
+            (0..2)
+                .map(|_| {
+                    let content = crate::miner::block_template(&source, &TransactionMempool::new(), Some(payout));
+                    let block = crate::miner::assemble_block(source.tip(), source.spec.initial_target, 0, 0, content);
+                    source.insert(&block);
+                    block
+                })
+                .collect::<Vec<Block>>()
+        };
+
+        // the orphan extends the tip once connected, then it is on a side chain as long as the tip
+        for blocks in &[chain(), chain()] {
+            worker.handle_message(Message::Blocks(vec![blocks[1].clone()]), &sender);
+            outbox.deliver(std::iter::once(&other));
+            assert!(other_replies.try_recv().is_err());
+
+            worker.handle_message(Message::Blocks(vec![blocks[0].clone()]), &sender);
+            outbox.deliver(std::iter::once(&other));
+            assert!(matches!(other_replies.try_recv(), Ok(Message::CompactBlock(_))));
+            match other_replies.try_recv() {
+                Ok(Message::NewBlockHashes(hashes)) => assert_eq!(hashes, vec![blocks[1].hash()]),
+                _ => panic!("the connected orphan was not announced"),
+            }
         }
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn keys_pending_blocks_by_peer() {
+        let spec = crate::chain_spec::ChainSpec::regtest();
+        let blockchain = Arc::new(RwLock::new(Blockchain::from_spec(spec.clone())));
+        let mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+        let (transport, _outbox) = loopback::new();
+        let (_, msg_rx) = channel::unbounded();
+        let worker = new(1, msg_rx, &transport, &blockchain, &mempool);
+        let (sender, sender_replies) = loopback::peer("127.0.0.1:6001".parse().unwrap());
+        let (other, _other_replies) = loopback::peer("127.0.0.1:6002".parse().unwrap());
+
+        let block = {
+            let blockchain = Blockchain::from_spec(spec);
+            let payout = crate::crypto::address::generate_random_address();
+            let content = crate::miner::block_template(&blockchain, &TransactionMempool::new(), Some(payout));
+            crate::miner::assemble_block(blockchain.tip(), blockchain.spec.initial_target, 0, 0, content)
+        };
+        // the sender had the coinbase in its mempool, so the compact block only has its short id
+        let mut sender_mempool = TransactionMempool::new();
+        let coinbase = block.Content.content[0].clone();
+        sender_mempool.tx_map.insert(coinbase.hash(), coinbase.clone());
+        let compact = CompactBlock::from_block(&block, &sender_mempool);
+        worker.handle_message(Message::CompactBlock(compact), &sender);
+        assert!(matches!(sender_replies.try_recv(), Ok(Message::GetBlockTxn(_, _))));
+
+        worker.handle_message(Message::BlockTxn(block.hash(), vec![coinbase]), &other);
+        assert_ne!(blockchain.read().unwrap().tip(), block.hash());
+        worker.handle_message(Message::Blocks(vec![block.clone()]), &other);
+        assert_eq!(blockchain.read().unwrap().tip(), block.hash());
+        assert!(worker.pending_blocks.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::block::Block;
+use crate::crypto::hash::{H256, Hashable};
+use log::debug;
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// Maximum number of orphan blocks kept in total
+pub const MAX_ORPHANS: usize = 750;
+/// Maximum number of orphan blocks kept per peer that sent them
+pub const MAX_ORPHANS_PER_PEER: usize = 100;
+/// Orphan blocks whose parent did not arrive within this time are dropped
+pub const ORPHAN_EXPIRY: Duration = Duration::from_secs(20 * 60);
+
+struct Orphan {
+    block: Block,
+    source: Option<SocketAddr>,
+    received: Instant,
+}
+
+/// Blocks whose parent is not connected yet, indexed by parent hash.
+pub struct OrphanPool {
+    orphans: HashMap<H256, Orphan>,
+    by_parent: HashMap<H256, Vec<H256>>,
+    per_peer: HashMap<SocketAddr, usize>,
+    /// Hashes in arrival order, to evict the oldest first
+    arrival: VecDeque<H256>,
+}
+
+impl OrphanPool {
+    pub fn new() -> Self {
+        OrphanPool {
+            orphans: HashMap::new(),
+            by_parent: HashMap::new(),
+            per_peer: HashMap::new(),
+            arrival: VecDeque::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.orphans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty()
+    }
+
+    pub fn contains(&self, hash: &H256) -> bool {
+        self.orphans.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &H256) -> Option<&Block> {
+        self.orphans.get(hash).map(|orphan| &orphan.block)
+    }
+
+    /// Add an orphan received from `source`. Returns false if it is already present or the
+    /// peer reached its limit. The oldest orphan is evicted when the pool is full.
+    pub fn insert(&mut self, block: Block, source: Option<SocketAddr>, now: Instant) -> bool {
+        let hash = block.hash();
+        if self.orphans.contains_key(&hash) {
+            return false;
+        }
+        if let Some(addr) = source {
+            if self.per_peer.get(&addr).copied().unwrap_or(0) >= MAX_ORPHANS_PER_PEER {
+                debug!("Peer {} has too many orphan blocks, dropping {}", addr, hash);
+                return false;
+            }
+        }
+        while self.orphans.len() >= MAX_ORPHANS {
+            match self.arrival.front().copied() {
+                Some(oldest) => {
+                    debug!("Orphan pool full, evicting {}", oldest);
+                    self.remove(&oldest);
+                }
+                None => break,
+            }
+        }
+        self.by_parent.entry(block.Header.parent).or_default().push(hash);
+        if let Some(addr) = source {
+            *self.per_peer.entry(addr).or_insert(0) += 1;
+        }
+        self.arrival.push_back(hash);
+        self.orphans.insert(hash, Orphan { block, source, received: now });
+        true
+    }
+
+    /// Remove an orphan, returning it.
+    pub fn remove(&mut self, hash: &H256) -> Option<Block> {
+        let orphan = self.orphans.remove(hash)?;
+        let parent = orphan.block.Header.parent;
+        if let Some(siblings) = self.by_parent.get_mut(&parent) {
+            siblings.retain(|sibling| sibling != hash);
+            if siblings.is_empty() {
+                self.by_parent.remove(&parent);
+            }
+        }
+        if let Some(addr) = orphan.source {
+            if let Some(count) = self.per_peer.get_mut(&addr) {
+                *count -= 1;
+                if *count == 0 {
+                    self.per_peer.remove(&addr);
+                }
+            }
+        }
+        if let Some(index) = self.arrival.iter().position(|h| h == hash) {
+            self.arrival.remove(index);
+        }
+        Some(orphan.block)
+    }
+
+    /// Remove and return the orphans whose parent is `parent`.
+    pub fn take_children(&mut self, parent: &H256) -> Vec<Block> {
+        let children = self.by_parent.get(parent).cloned().unwrap_or_default();
+        children.iter().filter_map(|hash| self.remove(hash)).collect()
+    }
+
+    /// The hash of the first missing ancestor of an orphan, found by following parent links
+    /// through the pool.
+    pub fn missing_ancestor(&self, hash: &H256) -> H256 {
+        let mut missing = *hash;
+        while let Some(orphan) = self.orphans.get(&missing) {
+            missing = orphan.block.Header.parent;
+        }
+        missing
+    }
+
+    /// Drop the orphans received before `now - ORPHAN_EXPIRY`.
+    pub fn expire(&mut self, now: Instant) {
+        while let Some(oldest) = self.arrival.front().copied() {
+            if now.duration_since(self.orphans[&oldest].received) < ORPHAN_EXPIRY {
+                break;
+            }
+            debug!("Orphan block {} expired", oldest);
+            self.remove(&oldest);
+        }
+    }
+}
+
+impl Default for OrphanPool {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::block::generate_random_block_;
+    use crate::crypto::hash::generate_random_hash;
+
+    #[test]
+    fn limits_and_expiry() {
+        let mut pool = OrphanPool::new();
+        let peer: SocketAddr = "127.0.0.1:6001".parse().unwrap();
+        let start = Instant::now();
+        for _ in 0..MAX_ORPHANS_PER_PEER {
+            assert!(pool.insert(generate_random_block_(&generate_random_hash()), Some(peer), start));
+        }
+        assert!(!pool.insert(generate_random_block_(&generate_random_hash()), Some(peer), start));
+        assert_eq!(pool.len(), MAX_ORPHANS_PER_PEER);
+
+        let parent = generate_random_hash();
+        let child = generate_random_block_(&parent);
+        let grandchild = generate_random_block_(&child.hash());
+        let later = start + Duration::from_secs(60);
+        assert!(pool.insert(grandchild.clone(), None, later));
+        assert!(pool.insert(child.clone(), None, later));
+        assert_eq!(pool.missing_ancestor(&grandchild.hash()), parent);
+
+        pool.expire(start + ORPHAN_EXPIRY);
+        assert_eq!(pool.len(), 2);
+        let children = pool.take_children(&parent);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].hash(), child.hash());
+        assert!(pool.insert(generate_random_block_(&parent), Some(peer), later));
+    }
+}
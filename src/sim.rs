@@ -0,0 +1,383 @@
+//! Deterministic in-process network simulator.
+//!
+//! Runs several nodes (blockchain, mempool, miner and worker) in a single thread over an
+//! in-memory network. Time is virtual: message delivery and block mining are events ordered by
+//! their virtual timestamp, and all randomness comes from a seeded generator, so the same seed
+//! always yields the same run.
+
+use crate::blockchain::Blockchain;
+use crate::chain_spec::ChainSpec;
+use crate::crypto::hash::{H256, Hashable};
+use crate::memory_pool::TransactionMempool;
+use crate::miner;
+use crate::network::compact::CompactBlock;
+use crate::network::message::Message;
+use crate::network::loopback::{self, Loopback, LoopbackPeer};
+use crate::network::transport::Transport;
+use crate::network::worker;
+use crate::transaction::SignTransaction;
+
+use crossbeam::channel;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock, RwLockReadGuard};
+
+enum Event {
+    Deliver { from: usize, to: usize, msg: Message },
+    Mine(usize),
+}
+
+struct Link {
+    handle: LoopbackPeer,
+    outbox: channel::Receiver<Message>,
+}
+
+struct Node {
+    blockchain: Arc<RwLock<Blockchain>>,
+    mempool: Arc<RwLock<TransactionMempool>>,
+    worker: worker::Context<Loopback>,
+    transport: Loopback,
+    outbox: loopback::Outbox,
+    /// Links to the other nodes, keyed by node index
+    peers: BTreeMap<usize, Link>,
+    /// The peer used to submit transactions from outside the network
+    client: Link,
+    /// Mean interval between mined blocks in milliseconds, if mining
+    mining: Option<u64>,
+}
+
+pub struct Simulation {
+    nodes: Vec<Node>,
+    /// Pending events ordered by (virtual time, sequence number)
+    events: BTreeMap<(u128, u64), Event>,
+    next_seq: u64,
+    /// Virtual time in milliseconds
+    clock: u128,
+    rng: StdRng,
+    /// Range of the one-way latency of every link in milliseconds
+    latency_ms: (u64, u64),
+    /// Time of the last scheduled delivery on each link, to keep links in order like TCP
+    last_delivery: HashMap<(usize, usize), u128>,
+}
+
+fn node_addr(index: usize) -> SocketAddr {
+    SocketAddr::from(([10, 0, (index >> 8) as u8, index as u8], 6000))
+}
+
+impl Simulation {
+    /// Create `num_nodes` unconnected nodes of the chain described by `spec`.
+    pub fn new(num_nodes: usize, seed: u64, spec: &ChainSpec) -> Self {
+        let nodes = (0..num_nodes)
+            .map(|_| {
+                let blockchain = Arc::new(RwLock::new(Blockchain::from_spec(spec.clone())));
+                let mempool = Arc::new(RwLock::new(TransactionMempool::new()));
+                let (transport, outbox) = loopback::new();
+                let (_, msg_rx) = channel::unbounded();
+                let worker = worker::new(1, msg_rx, &transport, &blockchain, &mempool);
+                let (client, client_outbox) = loopback::peer(SocketAddr::from(([0, 0, 0, 0], 0)));
+                Node {
+                    blockchain,
+                    mempool,
+                    worker,
+                    transport,
+                    outbox,
+                    peers: BTreeMap::new(),
+                    client: Link {
+                        handle: client,
+                        outbox: client_outbox,
+                    },
+                    mining: None,
+                }
+            })
+            .collect();
+        Simulation {
+            nodes,
+            events: BTreeMap::new(),
+            next_seq: 0,
+            clock: 0,
+            rng: StdRng::seed_from_u64(seed),
+            latency_ms: (50, 150),
+            last_delivery: HashMap::new(),
+        }
+    }
+
+    /// Set the range the one-way latency of messages is drawn from.
+    pub fn set_latency(&mut self, min_ms: u64, max_ms: u64) {
+        self.latency_ms = (min_ms, max_ms);
+    }
+
+    pub fn now(&self) -> u128 {
+        self.clock
+    }
+
+    pub fn blockchain(&self, node: usize) -> RwLockReadGuard<'_, Blockchain> {
+        self.nodes[node].blockchain.read().unwrap()
+    }
+
+    pub fn mempool(&self, node: usize) -> RwLockReadGuard<'_, TransactionMempool> {
+        self.nodes[node].mempool.read().unwrap()
+    }
+
+    pub fn tip(&self, node: usize) -> H256 {
+        self.blockchain(node).tip()
+    }
+
+    /// Connect two nodes. Like a new TCP connection, each side announces its tip to the other.
+    pub fn connect(&mut self, a: usize, b: usize) {
+        for (local, remote) in [(a, b), (b, a)].iter() {
+            let (handle, outbox) = loopback::peer(node_addr(*remote));
+            self.nodes[*local]
+                .peers
+                .insert(*remote, Link { handle, outbox });
+        }
+        for (local, remote) in [(a, b), (b, a)].iter() {
+            let node = &self.nodes[*local];
+            node.worker.handle_connected(&node.peers[remote].handle);
+            self.flush(*local);
+        }
+    }
+
+    /// Disconnect two nodes. Messages in flight between them are lost.
+    pub fn disconnect(&mut self, a: usize, b: usize) {
+        self.nodes[a].peers.remove(&b);
+        self.nodes[b].peers.remove(&a);
+    }
+
+    /// Submit a transaction to a node as if it came from a client outside the network.
+    pub fn submit_transaction(&mut self, node: usize, tx: SignTransaction) {
+        let n = &self.nodes[node];
+        n.worker
+            .handle_message(Message::Transactions(vec![tx]), &n.client.handle);
+        self.flush(node);
+    }
+
+    /// Let a node mine blocks at exponentially distributed intervals with the given mean.
+    pub fn start_mining(&mut self, node: usize, mean_interval_ms: u64) {
+        let already_mining = self.nodes[node].mining.is_some();
+        self.nodes[node].mining = Some(mean_interval_ms);
+        if !already_mining {
+            self.schedule_mining(node);
+        }
+    }
+
+    pub fn stop_mining(&mut self, node: usize) {
+        self.nodes[node].mining = None;
+    }
+
+    /// Mine a block on the tip of a node right now and announce it.
+    pub fn mine(&mut self, node: usize) -> H256 {
+        let block = {
+            let n = &self.nodes[node];
+            let mut blockchain = n.blockchain.write().unwrap();
+            let mut mempool = n.mempool.write().unwrap();
+            let parent = blockchain.tip();
+            let difficulty = blockchain.chain[&parent].Header.difficulty;
+            let txs = miner::block_template(&blockchain, &mempool, None);
+            let block = (0..)
+                .map(|nonce| {
+                    miner::assemble_block(parent, difficulty, self.clock, nonce, txs.clone())
+                })
+                .find(|block| block.hash() < difficulty)
+                .unwrap();
+            let outcome = blockchain.insert(&block);
+            mempool.update(&outcome, &blockchain);
+            let compact = CompactBlock::from_block(&block, &mempool);
+            n.transport.relay_compact_block(compact, None);
+            block
+        };
+        self.flush(node);
+        block.hash()
+    }
+
+    /// Process events until the virtual clock reaches `duration_ms` from now.
+    pub fn run_for(&mut self, duration_ms: u128) {
+        let deadline = self.clock + duration_ms;
+        while let Some(&(time, seq)) = self.events.keys().next() {
+            if time > deadline {
+                break;
+            }
+            let event = self.events.remove(&(time, seq)).unwrap();
+            self.clock = time;
+            self.process(event);
+        }
+        self.clock = deadline;
+    }
+
+    /// Process events until none is left. Mining must be stopped on every node first.
+    pub fn run_until_idle(&mut self) {
+        while let Some(&(time, seq)) = self.events.keys().next() {
+            let event = self.events.remove(&(time, seq)).unwrap();
+            self.clock = time;
+            self.process(event);
+        }
+    }
+
+    fn process(&mut self, event: Event) {
+        match event {
+            Event::Deliver { from, to, msg } => {
+                let node = &self.nodes[to];
+                let link = match node.peers.get(&from) {
+                    Some(link) => link,
+                    None => return,
+                };
+                node.worker.handle_message(msg, &link.handle);
+                self.flush(to);
+            }
+            Event::Mine(node) => {
+                if self.nodes[node].mining.is_some() {
+                    self.mine(node);
+                    self.schedule_mining(node);
+                }
+            }
+        }
+    }
+
+    fn schedule(&mut self, time: u128, event: Event) {
+        self.events.insert((time, self.next_seq), event);
+        self.next_seq += 1;
+    }
+
+    fn schedule_mining(&mut self, node: usize) {
+        if let Some(mean) = self.nodes[node].mining {
+            let u: f64 = self.rng.gen();
+            let interval = -(1.0 - u).ln() * mean as f64;
+            self.schedule(self.clock + interval as u128 + 1, Event::Mine(node));
+        }
+    }
+
+    /// Hand the messages a node sent to the network.
+    fn flush(&mut self, node: usize) {
+        let n = &self.nodes[node];
+        n.outbox.deliver(n.peers.values().map(|link| &link.handle));
+        let mut outgoing = vec![];
+        for (remote, link) in &n.peers {
+            while let Ok(msg) = link.outbox.try_recv() {
+                outgoing.push((*remote, msg));
+            }
+        }
+        while n.client.outbox.try_recv().is_ok() {}
+        for (remote, msg) in outgoing {
+            let latency = self.rng.gen_range(self.latency_ms.0, self.latency_ms.1 + 1);
+            let last = self.last_delivery.entry((node, remote)).or_insert(0);
+            let time = std::cmp::max(self.clock + latency as u128, *last);
+            *last = time;
+            self.schedule(time, Event::Deliver { from: node, to: remote, msg });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::chain_spec::Allocation;
+    use crate::crypto::address::{self, H160};
+    use crate::fixtures::{self, key};
+    use crate::transaction::{UtxoInput, UtxoOutput};
+    use ring::signature::{Ed25519KeyPair, KeyPair};
+
+    /// A chain whose genesis block only gives `value` to the owner of `key`
+    fn funded_spec(key: &Ed25519KeyPair, value: u32) -> ChainSpec {
+        ChainSpec {
+            allocations: vec![Allocation {
+                address: address::address_from_public_key_ref(key.public_key()),
+                value,
+            }],
+            ..ChainSpec::default()
+        }
+    }
+
+    fn pay(key: &Ed25519KeyPair, input: &UtxoInput, recipient: H160, value: u32) -> SignTransaction {
+        let output = UtxoOutput {
+            recipient_address: recipient,
+            value,
+        };
+        fixtures::sign_spend(key, vec![input.clone()], vec![output])
+    }
+
+    fn run_line(seed: u64) -> (Simulation, Vec<H256>) {
+        let mut sim = Simulation::new(4, seed, &ChainSpec::default());
+        for i in 0..3 {
+            sim.connect(i, i + 1);
+        }
+        for i in 0..4 {
+            sim.start_mining(i, 4000);
+        }
+        sim.run_for(60_000);
+        for i in 0..4 {
+            sim.stop_mining(i);
+        }
+        sim.run_until_idle();
+        let tips = (0..4).map(|i| sim.tip(i)).collect();
+        (sim, tips)
+    }
+
+    #[test]
+    fn converges() {
+        let (sim, tips) = run_line(1);
+        assert!(tips.iter().all(|tip| *tip == tips[0]));
+        assert!(sim.blockchain(0).heights[&tips[0]] > 5);
+    }
+
+    #[test]
+    fn same_seed_same_run() {
+        let (_, first) = run_line(2);
+        let (_, second) = run_line(2);
+        assert_eq!(first, second);
+    }
+
+    #[test]
+    fn reorg_to_longer_chain() {
+        let mut sim = Simulation::new(2, 3, &ChainSpec::default());
+        let short_tip = (0..2).map(|_| sim.mine(0)).last().unwrap();
+        let long_tip = (0..3).map(|_| sim.mine(1)).last().unwrap();
+        assert_eq!(sim.tip(0), short_tip);
+
+        sim.connect(0, 1);
+        sim.run_until_idle();
+        assert_eq!(sim.tip(0), long_tip);
+        assert_eq!(sim.tip(1), long_tip);
+        assert!(sim.blockchain(0).chain.contains_key(&short_tip));
+    }
+
+    #[test]
+    fn rejects_double_spend() {
+        let owner = key(1);
+        let spec = funded_spec(&owner, 100);
+        let mut sim = Simulation::new(2, 4, &spec);
+        let owner_address = address::address_from_public_key_ref(owner.public_key());
+        let (input, _) = sim.blockchain(0).tip_state().utxos_of(&owner_address).remove(0);
+        let alice = address::generate_random_address();
+        let bob = address::generate_random_address();
+        let to_alice = pay(&owner, &input, alice, 100);
+        let to_bob = pay(&owner, &input, bob, 100);
+
+        sim.connect(0, 1);
+        sim.submit_transaction(0, to_alice.clone());
+        sim.submit_transaction(1, to_bob.clone());
+        sim.run_until_idle();
+        // each node keeps the first spend it saw and rejects the conflicting one
+        assert!(sim.mempool(0).tx_map.contains_key(&to_alice.hash()));
+        assert!(!sim.mempool(0).tx_map.contains_key(&to_bob.hash()));
+        assert!(!sim.mempool(1).tx_map.contains_key(&to_alice.hash()));
+
+        let tip = sim.mine(0);
+        sim.run_until_idle();
+        assert_eq!(sim.tip(1), tip);
+        for node in 0..2 {
+            let blockchain = sim.blockchain(node);
+            let outputs: Vec<_> = blockchain.tip_state().iter().map(|(_, output)| output).collect();
+            assert_eq!(outputs.len(), 1);
+            assert_eq!(outputs[0].recipient_address, alice);
+        }
+
+        // once the first spend is confirmed, the second one is not even admitted
+        sim.submit_transaction(1, to_bob.clone());
+        assert!(sim.mempool(1).pending().all(|(tx_hash, _)| *tx_hash != to_bob.hash()));
+        let tip = sim.mine(1);
+        sim.run_until_idle();
+        assert_eq!(sim.tip(0), tip);
+        assert_eq!(sim.blockchain(0).chain[&tip].Content.content.len(), 0);
+    }
+}
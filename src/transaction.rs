@@ -40,6 +40,27 @@ pub struct SignTransaction{
     pub signature: Vec<u8>
 }
 
+impl SignTransaction {
+    /// Whether this is a coinbase, which is unsigned
+    pub fn is_coinbase(&self) -> bool {
+        self.signature.is_empty() && self.public_key.is_empty()
+    }
+
+    /// The addresses a transaction takes coins from or gives coins to: the signer, who must own
+    /// every input, and the recipients of the outputs
+    pub fn addresses(&self) -> Vec<H160> {
+        let mut addresses: Vec<H160> = self.transaction.tx_output.iter()
+            .map(|output| output.recipient_address)
+            .collect();
+        if !self.is_coinbase() {
+            addresses.push(address::address_from_public_key_vec_ref(&self.public_key));
+        }
+        addresses.sort_by(|a, b| a.as_ref().cmp(b.as_ref()));
+        addresses.dedup();
+        addresses
+    }
+}
+
 // we inplement the hashable function for Transaction structure, and it should be still working in the following project.
 impl Hashable for Transaction{
     fn hash(&self) -> H256 {
@@ -82,11 +103,11 @@ pub fn generate_random_transaction() -> Transaction {
     Transaction{tx_input: input, tx_output: output}
 }
 
-pub fn generate_genesis_transaction() -> Transaction {
-    let input = vec![UtxoInput{prev_hash: H256::from([0;32]), index: 0}];
-    let output = vec![UtxoOutput{recipient_address: H160::from([0;20]), value: 0}];
-    
-    Transaction{tx_input: input, tx_output: output}
+/// Create a coinbase transaction, which creates the coins of its outputs. It is not signed, and
+/// its only input is `marker`, the parent block for a block reward, to make its hash unique.
+pub fn coinbase(marker: H256, outputs: Vec<UtxoOutput>) -> SignTransaction {
+    let t = Transaction{tx_input: vec![UtxoInput{prev_hash: marker, index: 0}], tx_output: outputs};
+    SignTransaction{transaction: t, public_key: vec![], signature: vec![]}
 }
 
 pub fn generate_random_signed_transaction() -> SignTransaction {
@@ -99,14 +120,6 @@ pub fn generate_random_signed_transaction() -> SignTransaction {
     signed_tx
 }
 
-pub fn generate_genesis_signed_transaction() -> SignTransaction {
-
-    let t = generate_genesis_transaction();
-    let key = Ed25519KeyPair::from_pkcs8([48, 83, 2, 1, 1, 48, 5, 6, 3, 43, 101, 112, 4, 34, 4, 32, 187, 131, 74, 161, 134, 11, 240, 6, 188, 109, 18, 108, 124, 219, 167, 164, 215, 125, 168, 79, 204, 194, 232, 91, 58, 186, 181, 230, 212, 78, 163, 28, 161, 35, 3, 33, 0, 233, 72, 146, 218, 220, 235, 17, 123, 202, 112, 119, 63, 134, 105, 134, 71, 34, 185, 71, 193, 59, 66, 43, 137, 50, 194, 120, 234, 97, 132, 235, 159].as_ref().into()).unwrap();
-    let sig = sign(&t, &key);
-    let signed_tx = SignTransaction{transaction:t,signature:sig.as_ref().to_vec(),public_key:key.public_key().as_ref().to_vec()};
-    signed_tx
-}
 
 #[cfg(any(test, test_utilities))]
 mod tests {
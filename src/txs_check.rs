@@ -1,18 +1,84 @@
-use crate::transaction::{self, SignTransaction};
+use crate::transaction::{self, SignTransaction, UtxoInput};
 use crate::block::Block;
 use crate::crypto::address;
-use crate::ledger::State;
+use crate::ledger::{self, State};
 
 use log::debug;
+use std::collections::HashSet;
 
 pub fn is_tx_valid(signed_tx: &SignTransaction) -> bool {
    //verify whether the tx is signed properly
    return transaction::verify(&signed_tx.transaction, &signed_tx.signature, &signed_tx.public_key);
 }
 
-pub fn is_blck_valid(block: &Block, parent_state: &State) -> bool {
-    for signed_tx in &block.Content.content {
+/// Check a transaction against a ledger state: its inputs must be unspent and owned by the
+/// signer, and they must cover the value of its outputs.
+pub fn is_tx_spendable(signed_tx: &SignTransaction, state: &State) -> bool {
+    let owner_address = address::address_from_public_key_vec_ref(&signed_tx.public_key);
+    let mut seen_inputs = HashSet::new();
+    let mut total_input_value: u64 = 0;
+    for input in &signed_tx.transaction.tx_input {
+        debug!("current tx_input {:?}", input);
+        if !seen_inputs.insert(input) {
+            debug!("tx spends the same input twice!");
+            return false;
+        }
+        let output = match state.get(input) {
+            Some(output) => output,
+            None => {
+                debug!("tx is double spend as input is not there in State!");
+                return false;
+            }
+        };
+        if output.recipient_address != owner_address {
+            debug!("owner of tx input doesn't match to previous tx output");
+            debug!("input addreess {:?}", owner_address);
+            debug!("output address {:?}", output.recipient_address);
+            return false;
+        }
+        total_input_value += output.value as u64;
+    }
+
+    let total_output_value: u64 = signed_tx.transaction.tx_output.iter()
+        .map(|output| output.value as u64)
+        .sum();
+    if total_input_value < total_output_value {
+        debug!("Input sum is less than output sum for tx");
+        return false;
+    }
+    true
+}
+
+/// The fee paid by a transaction whose inputs are all in `state`: the value of its inputs that
+/// its outputs do not claim.
+pub fn tx_fee(signed_tx: &SignTransaction, state: &State) -> u64 {
+    let total_input_value: u64 = signed_tx.transaction.tx_input.iter()
+        .filter_map(|input| state.get(input))
+        .map(|output| output.value as u64)
+        .sum();
+    let total_output_value: u64 = signed_tx.transaction.tx_output.iter()
+        .map(|output| output.value as u64)
+        .sum();
+    total_input_value.saturating_sub(total_output_value)
+}
+
+/// Check the transactions of a block against the state of its parent. Only the first
+/// transaction may be a coinbase, and it may create at most `reward` plus the fees of the
+/// other transactions.
+pub fn is_blck_valid(block: &Block, parent_state: &State, reward: u32) -> bool {
+    //Transactions may spend outputs created earlier in the same block, but not an output
+    //already spent in it, so check each one against the state left by the previous ones
+    let mut state = parent_state.clone();
+    let mut fees: u64 = 0;
+    for (i, signed_tx) in block.Content.content.iter().enumerate() {
       debug!("current signed_tx {:?}", signed_tx);
+       if signed_tx.is_coinbase() {
+          if i != 0 {
+             debug!("coinbase is not the first tx of the block!");
+             return false;
+          }
+          continue;
+       }
        if !is_tx_valid(signed_tx){
           debug!("tx didn't pass signature check!");
           return false;
@@ -22,34 +88,27 @@ pub fn is_blck_valid(block: &Block, parent_state: &State) -> bool {
        //1. Owner match
        //2. Input/Output total match
        //3. Double Spend
-       let owner_address = address::address_from_public_key_vec_ref(&signed_tx.public_key);
-       let mut total_input_value = 0;
-       for input in &signed_tx.transaction.tx_input {
-           debug!("current tx_input {:?}", input);
-           if !parent_state.state_map.contains_key(&input){
-              debug!("tx is double spend as input is not there in State!");
-              return false;  
-           }
-           let output = &parent_state.state_map[&input];
-           if output.recipient_address != owner_address {
-              debug!("owner of tx input doesn't match to previous tx output");
-              debug!("input addreess {:?}", owner_address);
-              debug!("output address {:?}", output.recipient_address);
-              return false;
-           }
-           total_input_value = output.value;
-       }
-       
-       let mut total_output_value = 0;
-       for output in &signed_tx.transaction.tx_output {
-            total_output_value += output.value;
+       if !is_tx_spendable(signed_tx, &state) {
+          return false;
        }
+       fees += tx_fee(signed_tx, &state);
+       ledger::apply_transaction(signed_tx, &mut state);
+    }
 
-       if total_input_value != total_output_value {
-          debug!("Input sum didn't match to output sum for tx");
+    if let Some(coinbase) = block.Content.content.first().filter(|tx| tx.is_coinbase()) {
+       let marker = UtxoInput{prev_hash: block.Header.parent, index: 0};
+       if coinbase.transaction.tx_input != vec![marker] {
+          debug!("coinbase input is not the parent block!");
+          return false;
+       }
+       let created: u64 = coinbase.transaction.tx_output.iter()
+          .map(|output| output.value as u64)
+          .sum();
+       if created > reward as u64 + fees {
+          debug!("coinbase creates {} but the reward and fees are {}", created, reward as u64 + fees);
           return false;
        }
     }
 
     true
-}
\ No newline at end of file
+}
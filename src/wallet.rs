@@ -0,0 +1,527 @@
+//! A wallet of Ed25519 keys, stored in the data directory as one hex PKCS#8 document per line of
+//! `wallet.keys`. The keys are not encrypted, so on Unix the directory is only accessible to the
+//! current user and the file only readable by it.
+//!
+//! The wallet keeps the unspent outputs paying its keys on the longest chain, refreshed from the
+//! ledger whenever the tip changes, so that outputs come back or go away with reorgs. Outputs
+//! spent by pending transactions are not spendable again until those transactions leave the
+//! mempool.
+//!
+//! A transaction is signed by a single key and may only spend the outputs of that key, so coins
+//! are selected among the outputs of one key at a time, and the change goes back to that key.
+
+use crate::blockchain::{Blockchain, InsertOutcome};
+use crate::crypto::address::{self, H160};
+use crate::crypto::hash::H256;
+use crate::crypto::key_pair;
+use crate::ledger::State;
+use crate::memory_pool::{Rejection, TransactionMempool};
+use crate::transaction::{self, SignTransaction, Transaction, UtxoInput, UtxoOutput};
+use log::{info, warn};
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use serde::Serialize;
+use std::cmp::Reverse;
+use std::collections::{HashMap, HashSet};
+use std::convert::TryFrom;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+/// Name of the file holding the keys in the data directory
+pub const KEYS_FILE: &str = "wallet.keys";
+/// Selections branch and bound tries before giving up on avoiding change
+const BNB_MAX_TRIES: usize = 100_000;
+
+/// How to pick the outputs funding a payment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CoinSelection {
+    /// Spend the largest outputs first, which keeps the number of inputs low
+    LargestFirst,
+    /// Look for outputs adding up to the amount and the fee closely enough to need no change
+    /// output, and fall back to the largest outputs first
+    BranchAndBound,
+}
+
+impl std::str::FromStr for CoinSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "largest-first" => Ok(CoinSelection::LargestFirst),
+            "branch-and-bound" => Ok(CoinSelection::BranchAndBound),
+            _ => Err(format!(
+                "unknown coin selection {}, expected largest-first or branch-and-bound",
+                s
+            )),
+        }
+    }
+}
+
+pub struct Payment {
+    pub to: H160,
+    pub amount: u32,
+    pub fee: u32,
+    pub strategy: CoinSelection,
+    /// Value above the amount and the fee that branch and bound may leave to the miner rather
+    /// than create a change output
+    pub max_excess: u64,
+}
+
+/// Why a payment failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SendError {
+    /// No key has spendable outputs covering the amount and the fee
+    InsufficientFunds,
+    /// The change is more than an output can hold
+    ChangeTooLarge,
+    /// The mempool refused the transaction
+    Rejected(Rejection),
+}
+
+impl SendError {
+    /// A short identifier of the reason, for API clients
+    pub fn code(&self) -> &'static str {
+        match self {
+            SendError::InsufficientFunds => "insufficient_funds",
+            SendError::ChangeTooLarge => "change_too_large",
+            SendError::Rejected(rejection) => rejection.code(),
+        }
+    }
+}
+
+impl std::fmt::Display for SendError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            SendError::InsufficientFunds => write!(f, "no key has enough spendable coins"),
+            SendError::ChangeTooLarge => write!(f, "change does not fit in an output"),
+            SendError::Rejected(rejection) => write!(f, "{}", rejection),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct Balance {
+    /// Value of the outputs paying the keys on the longest chain
+    pub confirmed: u64,
+    /// Confirmed value that no pending transaction spends
+    pub spendable: u64,
+    /// Value pending transactions pay to the keys, change included
+    pub incoming: u64,
+}
+
+struct Key {
+    address: H160,
+    pair: Ed25519KeyPair,
+}
+
+pub struct Wallet {
+    /// File new keys are appended to, none for a wallet that only lives in memory
+    path: Option<PathBuf>,
+    keys: Vec<Key>,
+    /// Unspent outputs paying the keys on the longest chain
+    utxos: HashMap<UtxoInput, UtxoOutput>,
+}
+
+impl Wallet {
+    /// A wallet without keys that is not stored, so its keys are lost when the node stops.
+    pub fn new() -> Self {
+        Wallet {
+            path: None,
+            keys: vec![],
+            utxos: HashMap::new(),
+        }
+    }
+
+    /// Load the wallet of a data directory, creating the directory and a first key if needed.
+    /// A keys file readable by other users is made private first.
+    pub fn open(dir: &Path) -> Result<Self, String> {
+        let mut builder = std::fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(dir)
+            .map_err(|e| format!("error creating {}: {}", dir.display(), e))?;
+        let path = dir.join(KEYS_FILE);
+        let mut wallet = Wallet {
+            path: Some(path.clone()),
+            ..Wallet::new()
+        };
+        if path.exists() {
+            #[cfg(unix)]
+            make_private(&path)?;
+            let file = std::fs::File::open(&path)
+                .map_err(|e| format!("error opening {}: {}", path.display(), e))?;
+            for (i, line) in std::io::BufReader::new(file).lines().enumerate() {
+                let line = line.map_err(|e| format!("error reading {}: {}", path.display(), e))?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let pair = hex::decode(line.trim())
+                    .map_err(|e| e.to_string())
+                    .and_then(|pkcs8| Ed25519KeyPair::from_pkcs8(&pkcs8).map_err(|e| e.to_string()))
+                    .map_err(|e| {
+                        format!("invalid key on line {} of {}: {}", i + 1, path.display(), e)
+                    })?;
+                wallet.push(pair);
+            }
+        }
+        if wallet.keys.is_empty() {
+            wallet.new_address()?;
+        }
+        info!("Loaded {} wallet keys from {}", wallet.keys.len(), path.display());
+        Ok(wallet)
+    }
+
+    /// Generate a key, store it, and return its address.
+    pub fn new_address(&mut self) -> Result<H160, String> {
+        let pkcs8 = key_pair::random_pkcs8();
+        if let Some(path) = &self.path {
+            let mut options = std::fs::OpenOptions::new();
+            options.append(true).create(true);
+            #[cfg(unix)]
+            std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+            options
+                .open(path)
+                .and_then(|mut file| writeln!(file, "{}", hex::encode(&pkcs8)))
+                .map_err(|e| format!("error writing {}: {}", path.display(), e))?;
+        }
+        Ok(self.push(Ed25519KeyPair::from_pkcs8(&pkcs8).unwrap()))
+    }
+
+    fn push(&mut self, pair: Ed25519KeyPair) -> H160 {
+        let address = address::address_from_public_key_ref(pair.public_key());
+        self.keys.push(Key { address, pair });
+        address
+    }
+
+    /// The addresses of the keys, oldest first
+    pub fn addresses(&self) -> Vec<H160> {
+        self.keys.iter().map(|key| key.address).collect()
+    }
+
+    /// Replace the outputs of the wallet by those paying its keys in `state`.
+    pub fn sync(&mut self, state: &State) {
+        self.utxos = self.keys.iter().flat_map(|key| state.utxos_of(&key.address)).collect();
+    }
+
+    /// The confirmed outputs that no pending transaction spends
+    pub fn spendable(&self, mempool: &TransactionMempool) -> Vec<(UtxoInput, UtxoOutput)> {
+        let spent: HashSet<&UtxoInput> = mempool
+            .pending()
+            .flat_map(|(_, signed_tx)| signed_tx.transaction.tx_input.iter())
+            .collect();
+        self.utxos
+            .iter()
+            .filter(|(input, _)| !spent.contains(input))
+            .map(|(input, output)| (input.clone(), *output))
+            .collect()
+    }
+
+    pub fn balance(&self, mempool: &TransactionMempool) -> Balance {
+        let addresses: HashSet<H160> = self.addresses().into_iter().collect();
+        let incoming = mempool
+            .pending()
+            .flat_map(|(_, signed_tx)| signed_tx.transaction.tx_output.iter())
+            .filter(|output| addresses.contains(&output.recipient_address))
+            .map(|output| output.value as u64)
+            .sum();
+        Balance {
+            confirmed: self.utxos.values().map(|output| output.value as u64).sum(),
+            spendable: self.spendable(mempool).iter().map(|(_, output)| output.value as u64).sum(),
+            incoming,
+        }
+    }
+
+    /// Build and sign a transaction making `payment` from the spendable outputs of one key,
+    /// with a change output back to that key if the selected outputs are worth more than the
+    /// amount and the fee.
+    pub fn create_transaction(
+        &self,
+        mempool: &TransactionMempool,
+        payment: &Payment,
+    ) -> Result<SignTransaction, SendError> {
+        let target = payment.amount as u64 + payment.fee as u64;
+        let spendable = self.spendable(mempool);
+        let coins_of = |key: &Key| -> Vec<(UtxoInput, UtxoOutput)> {
+            spendable
+                .iter()
+                .filter(|(_, output)| output.recipient_address == key.address)
+                .cloned()
+                .collect()
+        };
+        // a selection without change from any key beats one with change
+        let strategies: &[CoinSelection] = match payment.strategy {
+            CoinSelection::LargestFirst => &[CoinSelection::LargestFirst],
+            CoinSelection::BranchAndBound => {
+                &[CoinSelection::BranchAndBound, CoinSelection::LargestFirst]
+            }
+        };
+        let (key, selected, change) = strategies
+            .iter()
+            .flat_map(|strategy| self.keys.iter().map(move |key| (*strategy, key)))
+            .find_map(|(strategy, key)| {
+                let selected = select_coins(&coins_of(key), target, strategy, payment.max_excess)?;
+                let total: u64 = selected.iter().map(|(_, output)| output.value as u64).sum();
+                let change = match strategy {
+                    CoinSelection::BranchAndBound => 0,
+                    CoinSelection::LargestFirst => total - target,
+                };
+                Some((key, selected, change))
+            })
+            .ok_or(SendError::InsufficientFunds)?;
+
+        let mut tx_output = vec![UtxoOutput {
+            recipient_address: payment.to,
+            value: payment.amount,
+        }];
+        if change > 0 {
+            let value = u32::try_from(change).map_err(|_| SendError::ChangeTooLarge)?;
+            tx_output.push(UtxoOutput {
+                recipient_address: key.address,
+                value,
+            });
+        }
+        let t = Transaction {
+            tx_input: selected.into_iter().map(|(input, _)| input).collect(),
+            tx_output,
+        };
+        let signature = transaction::sign(&t, &key.pair);
+        Ok(SignTransaction {
+            transaction: t,
+            public_key: key.pair.public_key().as_ref().to_vec(),
+            signature: signature.as_ref().to_vec(),
+        })
+    }
+
+    /// Make `payment` and add its transaction to the mempool, returning the hash of the signed
+    /// transaction. The caller relays it.
+    pub fn send(
+        &self,
+        blockchain: &Blockchain,
+        mempool: &mut TransactionMempool,
+        payment: &Payment,
+    ) -> Result<H256, SendError> {
+        let signed_tx = self.create_transaction(mempool, payment)?;
+        let hash = mempool
+            .admit(signed_tx, blockchain.tip_state())
+            .map_err(SendError::Rejected)?;
+        info!("Wallet sent {} to {} in transaction {}", payment.amount, payment.to, hash);
+        Ok(hash)
+    }
+}
+
+impl Default for Wallet {
+    fn default() -> Self {
+        Wallet::new()
+    }
+}
+
+/// Keep the wallet in sync with the longest chain, now and whenever the tip changes.
+pub fn follow(wallet: &Arc<RwLock<Wallet>>, blockchain: &Arc<RwLock<Blockchain>>) {
+    let events = {
+        let mut chain = blockchain.write().unwrap();
+        wallet.write().unwrap().sync(chain.tip_state());
+        chain.subscribe()
+    };
+    let wallet = Arc::clone(wallet);
+    let blockchain = Arc::clone(blockchain);
+    thread::Builder::new()
+        .name("wallet".to_string())
+        .spawn(move || {
+            for event in events {
+                let tip_changed = matches!(
+                    event.outcome,
+                    InsertOutcome::ExtendedTip { .. } | InsertOutcome::Reorg { .. }
+                );
+                if tip_changed {
+                    let chain = blockchain.read().unwrap();
+                    wallet.write().unwrap().sync(chain.tip_state());
+                }
+            }
+        })
+        .unwrap();
+}
+
+/// Pick outputs among `coins` worth at least `target`, none if they are not enough.
+///
+/// Largest first takes the largest outputs until they cover the target. Branch and bound
+/// searches for outputs worth between `target` and `target + max_excess`, and gives up after
+/// `BNB_MAX_TRIES` selections.
+pub fn select_coins(
+    coins: &[(UtxoInput, UtxoOutput)],
+    target: u64,
+    strategy: CoinSelection,
+    max_excess: u64,
+) -> Option<Vec<(UtxoInput, UtxoOutput)>> {
+    let mut sorted = coins.to_vec();
+    sorted.sort_by_key(|(_, output)| Reverse(output.value));
+    let values: Vec<u64> = sorted.iter().map(|(_, output)| output.value as u64).collect();
+    if values.iter().sum::<u64>() < target {
+        return None;
+    }
+    let chosen = match strategy {
+        CoinSelection::LargestFirst => {
+            let mut total = 0;
+            let count = values
+                .iter()
+                .take_while(|value| {
+                    let needed = total < target;
+                    total += **value;
+                    needed
+                })
+                .count();
+            (0..count).collect()
+        }
+        CoinSelection::BranchAndBound => {
+            let mut remaining = vec![0; values.len()];
+            let mut sum = 0;
+            for i in (0..values.len()).rev() {
+                sum += values[i];
+                remaining[i] = sum;
+            }
+            let mut search = BranchAndBound {
+                values: &values,
+                remaining,
+                target,
+                max: target.saturating_add(max_excess),
+                tries: 0,
+                chosen: vec![],
+            };
+            if !search.search(0, 0) {
+                return None;
+            }
+            search.chosen
+        }
+    };
+    Some(chosen.into_iter().map(|i| sorted[i].clone()).collect())
+}
+
+/// A depth-first search over including or leaving out each output, largest first.
+struct BranchAndBound<'a> {
+    values: &'a [u64],
+    /// Value of the outputs from each index on
+    remaining: Vec<u64>,
+    target: u64,
+    max: u64,
+    tries: usize,
+    chosen: Vec<usize>,
+}
+
+impl<'a> BranchAndBound<'a> {
+    fn search(&mut self, i: usize, total: u64) -> bool {
+        self.tries += 1;
+        if self.tries > BNB_MAX_TRIES || total > self.max {
+            return false;
+        }
+        if total >= self.target {
+            return true;
+        }
+        if i == self.values.len() || total + self.remaining[i] < self.target {
+            return false;
+        }
+        self.chosen.push(i);
+        if self.search(i + 1, total + self.values[i]) {
+            return true;
+        }
+        self.chosen.pop();
+        self.search(i + 1, total)
+    }
+}
+
+/// Remove the access of other users to a file, since it holds keys in plain text.
+#[cfg(unix)]
+fn make_private(path: &Path) -> Result<(), String> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mode = std::fs::metadata(path)
+        .map_err(|e| format!("error reading {}: {}", path.display(), e))?
+        .permissions()
+        .mode();
+    if mode & 0o077 != 0 {
+        warn!("{} was accessible to other users, restricting it to the owner", path.display());
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode & 0o700))
+            .map_err(|e| format!("error restricting {}: {}", path.display(), e))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::crypto::hash::Hashable;
+
+    fn coin(index: u8, value: u32) -> (UtxoInput, UtxoOutput) {
+        let input = UtxoInput { prev_hash: H256::default(), index };
+        (input, UtxoOutput { recipient_address: H160::default(), value })
+    }
+
+    #[test]
+    fn stores_keys_selects_coins_and_pays() {
+        let coins = vec![coin(0, 10), coin(1, 7), coin(2, 5), coin(3, 3)];
+        let values = |selected: Option<Vec<(UtxoInput, UtxoOutput)>>| -> Vec<u32> {
+            selected.unwrap().iter().map(|(_, output)| output.value).collect()
+        };
+        assert_eq!(values(select_coins(&coins, 12, CoinSelection::LargestFirst, 0)), vec![10, 7]);
+        assert_eq!(values(select_coins(&coins, 12, CoinSelection::BranchAndBound, 0)), vec![7, 5]);
+        assert_eq!(values(select_coins(&coins, 14, CoinSelection::BranchAndBound, 1)), vec![10, 5]);
+        assert!(select_coins(&coins, 24, CoinSelection::BranchAndBound, 0).is_none());
+        assert!(select_coins(&coins, 26, CoinSelection::LargestFirst, 0).is_none());
+
+        let dir = std::env::temp_dir().join(format!("wallet-test-{}", std::process::id()));
+        let mut wallet = Wallet::open(&dir).unwrap();
+        let second = wallet.new_address().unwrap();
+        assert_eq!(Wallet::open(&dir).unwrap().addresses(), wallet.addresses());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let owner = wallet.addresses()[0];
+        let (blockchain, coin, reward) = crate::fixtures::chain_with_coinbase_to(owner);
+        let mut mempool = TransactionMempool::new();
+        wallet.sync(blockchain.tip_state());
+        let balance = wallet.balance(&mempool);
+        assert_eq!((balance.confirmed, balance.spendable), (reward as u64, reward as u64));
+
+        let payment = Payment {
+            to: second,
+            amount: 10,
+            fee: 1,
+            strategy: CoinSelection::BranchAndBound,
+            max_excess: 0,
+        };
+        let signed_tx = wallet.create_transaction(&mempool, &payment).unwrap();
+        let outputs: Vec<(H160, u32)> = signed_tx
+            .transaction
+            .tx_output
+            .iter()
+            .map(|output| (output.recipient_address, output.value))
+            .collect();
+        assert_eq!(outputs, vec![(second, 10), (owner, reward - 11)]);
+        assert_eq!(signed_tx.transaction.tx_input[0], coin);
+        let hash = wallet.send(&blockchain, &mut mempool, &payment).unwrap();
+        assert_eq!(hash, signed_tx.hash());
+
+        let balance = wallet.balance(&mempool);
+        assert_eq!((balance.spendable, balance.incoming), (0, reward as u64 - 1));
+        let again = wallet.send(&blockchain, &mut mempool, &payment);
+        assert_eq!(again, Err(SendError::InsufficientFunds));
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn keys_are_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("wallet-mode-test-{}", std::process::id()));
+        let mode = |path: &Path| std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        Wallet::open(&dir).unwrap();
+        let keys = dir.join(KEYS_FILE);
+        let created = (mode(&dir), mode(&keys));
+        std::fs::set_permissions(&keys, std::fs::Permissions::from_mode(0o644)).unwrap();
+        Wallet::open(&dir).unwrap();
+        let reopened = mode(&keys);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(created, (0o700, 0o600));
+        assert_eq!(reopened, 0o600);
+    }
+}